@@ -41,7 +41,7 @@ fn main() {
                 // The repl is ready for evaluating
 
                 // evaluate using a unit value for data
-                let EvalResult { repl, signal } = eval.eval(&mut ());
+                let EvalResult { repl, signal, .. } = eval.eval(&mut ());
 
                 // handle the signal, other values are elided but would be
                 // handled in a more complete implementation
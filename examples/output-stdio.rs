@@ -50,7 +50,7 @@ fn main() {
                 let output_thread_jh = write_output_to_stdout(rx);
 
                 // evaluate using a unit value for data
-                let EvalResult { repl, signal } = eval.eval(&mut ());
+                let EvalResult { repl, signal, .. } = eval.eval(&mut ());
 
                 // handle the signal, other values are elided but would be
                 // handled in a more complete implementation
@@ -59,6 +59,57 @@
 //! used. To recursively add files `**/*.rs` can be used. This applies to removing static files using
 //! the `rm` command.
 //!
+//! ## Exporting
+//! The `export` command writes the REPL code as a standalone cargo binary project, such that a
+//! prototype can be graduated into a real project. `:export my-proto` writes a `Cargo.toml`, a
+//! `src/main.rs`, and copies of the static files into `my-proto/`. Items are placed at module level and
+//! `main` replays each statement group, printing the `out#` values with `Debug`. Crate versions are
+//! taken from the versions resolved when compiling the REPL. Linked libraries and `app_data` are not
+//! exported.
+//!
+//! ## Warnings
+//! Compiler warnings are suppressed by default. The `warnings` command toggles them on or off
+//! (`:warnings on` and `:warnings off` set them explicitly), which sets
+//! `ReplData::compile_options.warnings`. Only warnings for the latest input are shown, such that
+//! an unused variable is warned about once rather than for every following input.
+//!
+//! ## Persisting bindings
+//! By default each input evaluates all the previous statements of the module again, repeating
+//! their side effects. The `persist` command toggles persisting the bindings between inputs instead
+//! (`:persist on` and `:persist off` set it explicitly), which sets `ReplData::persist_bindings`.
+//! When on, the bindings are encoded after each evaluation and restored for the next input, so
+//! only the new statements are evaluated. Bindings which cannot be stored fall back to evaluating
+//! the previous statements again.
+//!
+//! A restored binding is a copy made by a `serde` round trip, not the value the statements
+//! produced. Fields marked `#[serde(skip)]` are reset to their default, and values which depend on
+//! their address or on process state (such as file handles or an `Rc` shared between bindings)
+//! are not the same after restoring.
+//!
+//! ## Variables
+//! The `vars` command lists the variables bound by the statements of the current module, including
+//! the `out#` values, along with their types. Shadowed variables are only listed once. The types are
+//! inferred by the compiler, so listing the variables compiles the REPL code (but does not evaluate
+//! it), see `ReplData::variables`.
+//!
+//! The `type` command shows the type of an expression without evaluating it, for example
+//! `:type v.iter().map(|x| x * 2)`. The expression is compiled in the context of the current module
+//! and is not added to the REPL input, see `ReplData::type_of`.
+//!
+//! ## Results
+//! The results of evaluations are kept for each module, up to `ReplData::output_history_size`. The
+//! `out` command prints a previous result again, `:out 2` prints the result of `out2`. The
+//! `save-out` command writes a result to a file, `:save-out 2 data.json` writes `out2` as JSON. The
+//! format is given as the last argument (`json`, `toml`, or `kserd`), otherwise it is inferred from
+//! the file extension, defaulting to the `kserd` text format.
+//!
+//! ## Sessions
+//! The `session` command saves and loads the REPL session to a portable JSON file. A session
+//! captures the previous input of every module, the current module, the imported static file paths,
+//! and the persistent module code. `:session save session.json` writes the file, and
+//! `:session load session.json` replaces the current session with the one in the file. Static files
+//! are re-imported from the working directory when loading.
+//!
 //! # Extending Commands
 //! ## Setup
 //!
@@ -207,7 +258,8 @@
 //! custom-cmds-app [out2]: "hello, world!"
 //! ```
 use super::*;
-use crate::repl::{Editing, EditingIndex, ReplData};
+use crate::code::SourceCode;
+use crate::repl::{fmt_output, Editing, EditingIndex, OutputError, OutputFormat, ReplData};
 use cmdtree::{BuildError, Builder, BuilderChain, Commander};
 use std::{
     fs,
@@ -240,6 +292,8 @@ pub enum CommandResult<D> {
     EditAlter(EditingIndex),
     /// Replace a previous statement, item, or crate with value.
     EditReplace(EditingIndex, String),
+    /// Remove a previous item or crate.
+    EditRemove(EditingIndex),
     /// Switch to a module.
     SwitchModule(PathBuf),
     /// Take an action on the `ReplData`.
@@ -304,7 +358,42 @@ fn papyrus_cmdr<D>(
             |wtr, args| edit_replace_priv(args, wtr, Editing::Stmt),
         )
         .end_class()
+        .begin_class("item", "Edit previous items")
+        .add_action(
+            "alter",
+            "Alter item contents. args: item-number",
+            |wtr, args| edit_alter_priv(args, wtr, Editing::Item),
+        )
+        .add_action(
+            "replace",
+            "Replace item contents. args: item-number value",
+            |wtr, args| edit_replace_priv(args, wtr, Editing::Item),
+        )
+        .add_action("rm", "Remove an item. args: item-number", |wtr, args| {
+            edit_remove_priv(args, wtr, Editing::Item)
+        })
+        .end_class()
+        .begin_class("crate", "Edit previous crates")
+        .add_action(
+            "alter",
+            "Alter crate contents. args: crate-number",
+            |wtr, args| edit_alter_priv(args, wtr, Editing::Crate),
+        )
+        .add_action(
+            "replace",
+            "Replace crate contents. args: crate-number value",
+            |wtr, args| edit_replace_priv(args, wtr, Editing::Crate),
+        )
+        .add_action("rm", "Remove a crate. args: crate-number", |wtr, args| {
+            edit_remove_priv(args, wtr, Editing::Crate)
+        })
+        .end_class()
         .end_class()
+        .add_action(
+            "list",
+            "List the statements, items, and crates of the current module with their indices",
+            |_, _| list(),
+        )
         .begin_class("mod", "Handle modules")
         .add_action(
             "switch",
@@ -330,6 +419,54 @@ fn papyrus_cmdr<D>(
         )
         .add_action("ls", "List imported static files", |_, _| ls_static_files())
         .end_class()
+        .add_action(
+            "export",
+            "Export the session as a cargo binary project. args: dir-path",
+            |wtr, args| export(wtr, args),
+        )
+        .add_action(
+            "warnings",
+            "Show compiler warnings for the latest input. args: on, off, or none to toggle",
+            |wtr, args| warnings(wtr, args),
+        )
+        .add_action(
+            "persist",
+            "Persist bindings between inputs rather than evaluating previous statements again. \
+             args: on, off, or none to toggle",
+            |wtr, args| persist(wtr, args),
+        )
+        .add_action(
+            "vars",
+            "List the variables in scope and their types",
+            |_, _| vars(),
+        )
+        .add_action(
+            "type",
+            "Show the type of an expression without evaluating it. args: expression",
+            |wtr, args| type_of(wtr, args),
+        )
+        .add_action(
+            "out",
+            "Print a previous result of the current module. args: out-number",
+            |wtr, args| out(wtr, args),
+        )
+        .add_action(
+            "save-out",
+            "Save a previous result to a file. args: out-number file-path [json|toml|kserd]",
+            |wtr, args| save_out(wtr, args),
+        )
+        .begin_class("session", "Save and load sessions")
+        .add_action(
+            "save",
+            "Save the session to a file. args: file-path",
+            |wtr, args| save_session(wtr, args),
+        )
+        .add_action(
+            "load",
+            "Load a session from a file, replacing the current session. args: file-path",
+            |wtr, args| load_session(wtr, args),
+        )
+        .end_class()
         .into_commander()
 }
 
@@ -393,7 +530,7 @@ fn edit_alter_priv<D, W: Write>(args: &[&str], mut wtr: W, t: Editing) -> Comman
 fn edit_replace_priv<D, W: Write>(args: &[&str], mut wtr: W, t: Editing) -> CommandResult<D> {
     if let Some(idx) = args.get(0) {
         match parse_idx(idx, t) {
-            Ok(ei) => CommandResult::EditReplace(ei, args[1..].iter().copied().collect::<String>()),
+            Ok(ei) => CommandResult::EditReplace(ei, args[1..].join(" ")),
             Err(e) => {
                 writeln!(wtr, "failed parsing {} as number: {}", idx, e).ok();
                 CommandResult::Empty
@@ -405,6 +542,21 @@ fn edit_replace_priv<D, W: Write>(args: &[&str], mut wtr: W, t: Editing) -> Comm
     }
 }
 
+fn edit_remove_priv<D, W: Write>(args: &[&str], mut wtr: W, t: Editing) -> CommandResult<D> {
+    if let Some(idx) = args.first() {
+        match parse_idx(idx, t) {
+            Ok(ei) => CommandResult::EditRemove(ei),
+            Err(e) => {
+                writeln!(wtr, "failed parsing {} as number: {}", idx, e).ok();
+                CommandResult::Empty
+            }
+        }
+    } else {
+        writeln!(wtr, "rm expects an index number").ok();
+        CommandResult::Empty
+    }
+}
+
 fn parse_idx(s: &str, editing: Editing) -> Result<EditingIndex, String> {
     s.parse()
         .map_err(|e| format!("{}", e))
@@ -428,6 +580,22 @@ pub(crate) fn edit_alter<D>(data: &mut ReplData<D>, ei: EditingIndex) -> &'stati
     }
 }
 
+pub(crate) fn edit_remove<D>(data: &mut ReplData<D>, ei: EditingIndex) -> String {
+    let src = match data.mods_map.get_mut(&data.current_mod) {
+        Some(src) => src,
+        None => return String::from("index is outside of range"),
+    };
+
+    let removed = match ei.editing {
+        Editing::Stmt if ei.index < src.stmts.len() => src.stmts.remove(ei.index).src_line(),
+        Editing::Item if ei.index < src.items.len() => src.items.remove(ei.index).0,
+        Editing::Crate if ei.index < src.crates.len() => src.crates.remove(ei.index).src_line,
+        _ => return String::from("index is outside of range"),
+    };
+
+    format!("removed `{}`", removed.trim())
+}
+
 pub(crate) fn switch_module<D>(data: &mut ReplData<D>, path: &Path) -> &'static str {
     let mut all = make_all_parents(path);
     all.push(path.to_path_buf());
@@ -451,6 +619,7 @@ fn clear_modules<D, W: Write>(args: &[&str], mut wtr: W) -> CommandResult<D> {
                         writeln!(wtr, "cleared inputs in `{}`", path.display()).ok();
                     }
                 }
+                data.clear_outputs(|path| pattern.matches_path(path));
 
                 String::from("cleared all previous inputs")
             }),
@@ -465,11 +634,52 @@ fn clear_modules<D, W: Write>(args: &[&str], mut wtr: W) -> CommandResult<D> {
             if let Some(src) = data.mods_map.get_mut(&p) {
                 src.clear()
             }
+            data.clear_outputs(|path| path == p);
             format!("cleared previous input in `{}`", p.display())
         })
     }
 }
 
+// ------ LIST -----------------------------------------------------------------
+fn list<D>() -> CommandResult<D> {
+    CommandResult::repl_data_fn(|data, _| list_src(data.current_mod(), data.current_src()))
+}
+
+/// Lists the statement groups, items, and crates with the indices used when editing.
+fn list_src(path: &Path, src: &SourceCode) -> String {
+    let sections = [
+        (
+            "statements",
+            src.stmts.iter().map(|x| x.src_line()).collect(),
+        ),
+        ("items", src.items.iter().map(|x| x.0.clone()).collect()),
+        (
+            "crates",
+            src.crates
+                .iter()
+                .map(|x| x.src_line.clone())
+                .collect::<Vec<_>>(),
+        ),
+    ];
+
+    let mut buf = String::new();
+    for (name, lines) in sections.iter().filter(|x| !x.1.is_empty()) {
+        buf.push_str(name);
+        buf.push_str(":\n");
+        for (idx, line) in lines.iter().enumerate() {
+            let line = line.trim().replace('\n', "\n     ");
+            buf.push_str(&format!("{:>3}: {}\n", idx, line));
+        }
+    }
+
+    if buf.is_empty() {
+        format!("no input in `{}`", path.display())
+    } else {
+        buf.pop(); // the trailing new line is printed
+        buf
+    }
+}
+
 // ------ STATIC FILES ---------------------------------------------------------
 fn add_static_file<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
     if let Some(&path) = args.get(0) {
@@ -530,6 +740,172 @@ fn ls_static_files<D>() -> CommandResult<D> {
     })
 }
 
+// ------ EXPORT ---------------------------------------------------------------
+fn export<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&dir) = args.first() {
+        let dir = PathBuf::from(dir);
+        CommandResult::repl_data_fn(move |data, _| match data.export(&dir) {
+            Ok(_) => format!("exported session to `{}`", dir.display()),
+            Err(e) => format!("failed to export session: {}", e),
+        })
+    } else {
+        writeln!(wtr, "export expects a directory path").ok();
+        CommandResult::Empty
+    }
+}
+
+// ------ WARNINGS -------------------------------------------------------------
+fn warnings<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    let set = match args.first() {
+        Some(&"on") => Some(true),
+        Some(&"off") => Some(false),
+        None => None,
+        Some(arg) => {
+            writeln!(wtr, "warnings expects `on` or `off`, found `{}`", arg).ok();
+            return CommandResult::Empty;
+        }
+    };
+
+    CommandResult::repl_data_fn(move |data, _| {
+        let on = set.unwrap_or(!data.compile_options.warnings);
+        data.compile_options.warnings = on;
+        format!("compiler warnings {}", if on { "on" } else { "off" })
+    })
+}
+
+// ------ PERSIST --------------------------------------------------------------
+fn persist<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    let set = match args.first() {
+        Some(&"on") => Some(true),
+        Some(&"off") => Some(false),
+        None => None,
+        Some(arg) => {
+            writeln!(wtr, "persist expects `on` or `off`, found `{}`", arg).ok();
+            return CommandResult::Empty;
+        }
+    };
+
+    CommandResult::repl_data_fn(move |data, _| {
+        let on = set.unwrap_or(!data.persist_bindings);
+        data.persist_bindings = on;
+        if on {
+            "persisting bindings on, restored bindings are serde round-trip copies".to_string()
+        } else {
+            "persisting bindings off".to_string()
+        }
+    })
+}
+
+// ------ VARIABLES ------------------------------------------------------------
+fn vars<D>() -> CommandResult<D> {
+    CommandResult::repl_data_fn(|data, _| match data.variables() {
+        Ok(vars) if vars.is_empty() => String::from("no variables in scope"),
+        Ok(vars) => vars
+            .iter()
+            .map(|(name, ty)| format!("{}: {}", name, ty))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(e) => format!("failed to infer variable types: {}", e),
+    })
+}
+
+fn type_of<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if args.is_empty() {
+        writeln!(wtr, "type expects an expression").ok();
+        return CommandResult::Empty;
+    }
+
+    let expr = args.join(" ");
+    CommandResult::repl_data_fn(move |data, _| match data.type_of(&expr) {
+        Ok(ty) => ty,
+        Err(e) => format!("failed to infer type: {}", e),
+    })
+}
+
+// ------ OUTPUTS --------------------------------------------------------------
+fn out<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    match args.first().map(|x| (x, x.parse::<usize>())) {
+        Some((_, Ok(n))) => CommandResult::repl_data_fn(move |data, _| match data.output(n) {
+            Some(kserd) => format!("[out{}]: {}", n, fmt_output(kserd, Default::default())),
+            None => OutputError::Missing(n).to_string(),
+        }),
+        Some((x, Err(e))) => {
+            writeln!(wtr, "failed parsing {} as number: {}", x, e).ok();
+            CommandResult::Empty
+        }
+        None => {
+            writeln!(wtr, "out expects an out number").ok();
+            CommandResult::Empty
+        }
+    }
+}
+
+fn save_out<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    match parse_save_out(args) {
+        Ok((n, path, format)) => {
+            CommandResult::repl_data_fn(move |data, _| match data.save_output(n, &path, format) {
+                Ok(_) => format!("saved out{} to `{}`", n, path.display()),
+                Err(e) => format!("failed to save out{}: {}", n, e),
+            })
+        }
+        Err(e) => {
+            writeln!(wtr, "{}", e).ok();
+            CommandResult::Empty
+        }
+    }
+}
+
+/// Parses `out-number file-path [format]`, inferring the format from the file extension if it is
+/// not specified.
+fn parse_save_out(args: &[&str]) -> Result<(usize, PathBuf, OutputFormat), String> {
+    let (n, path) = match args {
+        [n, path, ..] => (n, PathBuf::from(path)),
+        _ => {
+            return Err(String::from(
+                "save-out expects an out number and a file path",
+            ))
+        }
+    };
+
+    let n = n
+        .parse()
+        .map_err(|e| format!("failed parsing {} as number: {}", n, e))?;
+
+    let format = match args.get(2) {
+        Some(format) => format.parse()?,
+        None => OutputFormat::from_path(&path),
+    };
+
+    Ok((n, path, format))
+}
+
+// ------ SESSIONS -------------------------------------------------------------
+fn save_session<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&path) = args.first() {
+        let path = PathBuf::from(path);
+        CommandResult::repl_data_fn(move |data, _| match data.save_session(&path) {
+            Ok(_) => format!("saved session to `{}`", path.display()),
+            Err(e) => format!("failed to save session: {}", e),
+        })
+    } else {
+        writeln!(wtr, "save expects a file path").ok();
+        CommandResult::Empty
+    }
+}
+
+fn load_session<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&path) = args.first() {
+        let path = PathBuf::from(path);
+        CommandResult::repl_data_fn(move |data, _| match data.load_session(&path) {
+            Ok(_) => format!("loaded session from `{}`", path.display()),
+            Err(e) => format!("failed to load session: {}", e),
+        })
+    } else {
+        writeln!(wtr, "load expects a file path").ok();
+        CommandResult::Empty
+    }
+}
+
 fn foreach_glob_path<F>(glob: &str, wtr: &mut dyn Write, mut f: F)
 where
     F: FnMut(PathBuf, &mut dyn Write),
@@ -604,6 +980,109 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_edit_interface() {
+        let mut buf = Vec::new();
+        edit_remove_priv::<(), _>(&[], &mut buf, Editing::Item);
+        assert_eq!(buf.as_slice(), &b"rm expects an index number\n"[..]);
+
+        buf.clear();
+        edit_remove_priv::<(), _>(&["a"], &mut buf, Editing::Crate);
+        assert_eq!(
+            buf.as_slice(),
+            &b"failed parsing a as number: invalid digit found in string\n"[..]
+        );
+
+        buf.clear();
+        match edit_replace_priv::<(), _>(&["1", "fn", "a()", "{}"], &mut buf, Editing::Item) {
+            CommandResult::EditReplace(ei, val) => {
+                assert_eq!(ei.index, 1);
+                assert_eq!(val, "fn a() {}");
+            }
+            _ => panic!("expecting EditReplace"),
+        }
+    }
+
+    #[test]
+    fn edit_remove_test() {
+        let mut data = ReplData::<()>::default();
+        let src = data.mods_map.get_mut(Path::new("lib")).unwrap();
+        src.items.push(("fn a() {}".to_string(), false));
+        src.items.push(("fn b() {}".to_string(), false));
+        let ei = |editing, index| EditingIndex { editing, index };
+
+        assert_eq!(
+            edit_remove(&mut data, ei(Editing::Item, 0)),
+            "removed `fn a() {}`"
+        );
+        assert_eq!(
+            edit_remove(&mut data, ei(Editing::Item, 1)),
+            "index is outside of range"
+        );
+        assert_eq!(
+            edit_remove(&mut data, ei(Editing::Crate, 0)),
+            "index is outside of range"
+        );
+        assert_eq!(
+            data.current_src().items,
+            vec![("fn b() {}".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn list_src_test() {
+        use crate::code::{CrateType, Statement, StmtGrp};
+
+        let path = Path::new("lib");
+        let mut src = SourceCode::default();
+        assert_eq!(list_src(path, &src), "no input in `lib`");
+
+        src.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let a = 1".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a".to_string(),
+                semi: false,
+            },
+        ]));
+        src.items.push(("fn a() {\n    ()\n}".to_string(), false));
+        src.crates
+            .push(CrateType::parse_str("extern crate rand;").unwrap());
+
+        assert_eq!(
+            list_src(path, &src),
+            "statements:
+  0: let a = 1; a
+items:
+  0: fn a() {
+         ()
+     }
+crates:
+  0: extern crate rand;"
+        );
+    }
+
+    #[test]
+    fn parse_save_out_test() {
+        assert_eq!(
+            parse_save_out(&["2", "a.json"]),
+            Ok((2, PathBuf::from("a.json"), OutputFormat::Json))
+        );
+        assert_eq!(
+            parse_save_out(&["2", "a.json", "toml"]),
+            Ok((2, PathBuf::from("a.json"), OutputFormat::Toml))
+        );
+        assert_eq!(
+            parse_save_out(&["2", "a"]),
+            Ok((2, PathBuf::from("a"), OutputFormat::Kserd))
+        );
+        assert!(parse_save_out(&["2"]).is_err());
+        assert!(parse_save_out(&["a", "a.json"]).is_err());
+        assert!(parse_save_out(&["2", "a.json", "yaml"]).is_err());
+    }
+
     #[test]
     fn test_static_file_interface() {
         let mut buf = Vec::new();
@@ -38,13 +38,26 @@
 //! assert_eq!(&cr.cargo_name, "a-crate");
 //! ```
 //!
+//! The `Cargo.toml` dependency can be specified with a `papyrus` attribute, see [`DependencySpec`].
+//! ```rust
+//! # extern crate papyrus;
+//! use papyrus::code::*;
+//!
+//! let input = r#"#[papyrus(version = "0.7", features("small_rng"))] extern crate rand;"#;
+//! let cr = CrateType::parse_str(input).unwrap();
+//!
+//! assert_eq!(&cr.spec.toml_value(), r#"{ version = "0.7", features = ["small_rng"] }"#);
+//! ```
+//!
 //! [`CrateType`]: CrateType
+//! [`DependencySpec`]: DependencySpec
 //! [`Input`]: Input
 //! [`Item`]: Item
 //! [`SourceCode`]: SourceCode
 //! [`Statement`]: Statement
 use super::*;
 use crate::linking::LinkingConfiguration;
+use serde::{Deserialize, Serialize};
 use std::{
     borrow::Borrow,
     cmp::Ordering,
@@ -55,6 +68,269 @@ use std::{
 };
 
 type ReturnRange = std::ops::Range<usize>;
+
+/// The identity of the `Kserd` an evaluation function returns if the evaluation panicked, a tuple
+/// of the panic message, location, and backtrace (empty if not captured).
+pub(crate) const PANIC_ID: &str = "__papyrus_panic";
+
+/// Appended to the source code, each evaluation function body is wrapped in this to catch panics,
+/// which would otherwise abort at the `extern "C"` boundary. A hook records the panic location
+/// and backtrace.
+const CATCH_PANIC: &str = r#"
+fn __papyrus_catch<F: FnOnce() -> kserd::Kserd<'static>>(f: F) -> kserd::Kserd<'static> {
+    use std::cell::RefCell;
+    use std::panic;
+
+    thread_local!(static PANIC: RefCell<(String, String)> = RefCell::new(Default::default()));
+
+    // RUST_LIB_BACKTRACE takes precedence over RUST_BACKTRACE
+    let capture = std::env::var("RUST_LIB_BACKTRACE")
+        .or_else(|_| std::env::var("RUST_BACKTRACE"))
+        .map(|x| x != "0")
+        .unwrap_or(false);
+
+    panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|x| x.to_string()).unwrap_or_default();
+        let bt = if capture {
+            format!("{:?}", papyrus_backtrace::Backtrace::new())
+        } else {
+            String::new()
+        };
+        PANIC.with(|x| *x.borrow_mut() = (location, bt));
+    }));
+
+    let payload = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(kserd) => return kserd,
+        Err(payload) => payload,
+    };
+    let msg = match payload.downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(s) => s.clone(),
+            None => "Box<dyn Any>".to_string(),
+        },
+    };
+    let (location, bt) = PANIC.with(|x| x.replace(Default::default()));
+    let value = kserd::Value::Tuple(vec![
+        kserd::Kserd::new_string(msg),
+        kserd::Kserd::new_string(location),
+        kserd::Kserd::new_string(bt),
+    ]);
+    kserd::Kserd::with_id("__papyrus_panic", value).unwrap()
+}
+"#;
+
+/// The identity of the `Kserd` a persisting evaluation function returns, a tuple of the output and
+/// a container of the bindings in scope. The output is returned as is if a binding could not be
+/// encoded.
+pub(crate) const PERSIST_ID: &str = "__papyrus_persist";
+
+/// The function exported by the library which takes the container of bindings to restore, before
+/// calling a persisting evaluation function.
+pub(crate) const RESTORE_FN: &str = "__papyrus_restore";
+
+/// Appended to the source code, the helpers which restore and snapshot bindings for persisting
+/// evaluation functions (see [`construct_source_code_persisting`]).
+///
+/// Whether a binding's type can be encoded or decoded is resolved with autoref specialisation,
+/// such that types without `serde` implementations still compile and are replayed instead.
+/// Decoding borrowed types (such as `&str`) fails, and also falls back to replaying.
+const PERSIST: &str = r#"
+mod __papyrus_persist {
+    use kserd::encode::{Deserialize, Serialize};
+    use kserd::{Kserd, Value};
+    use std::cell::RefCell;
+    use std::marker::PhantomData;
+
+    thread_local!(static VARS: RefCell<Option<Kserd<'static>>> = RefCell::new(None));
+
+    #[no_mangle]
+    pub extern "C" fn __papyrus_restore(vars: *const Kserd<'static>) {
+        let vars = unsafe { &*vars }.clone();
+        VARS.with(|x| *x.borrow_mut() = Some(vars));
+    }
+
+    pub fn vars() -> Option<Kserd<'static>> {
+        VARS.with(|x| x.borrow_mut().take())
+    }
+
+    pub fn ty<T, U, F: FnOnce() -> T>(_: &F, _: fn(T) -> U) -> PhantomData<U> {
+        PhantomData
+    }
+
+    pub struct Var<T>(pub T);
+
+    pub trait Decode<T> {
+        fn decode(&self, vars: &Kserd<'static>, name: &str) -> Option<T>;
+    }
+
+    impl<T: Deserialize<'static>> Decode<T> for Var<PhantomData<T>> {
+        fn decode(&self, vars: &Kserd<'static>, name: &str) -> Option<T> {
+            match &vars.val {
+                Value::Cntr(map) => map.iter().find(|x| x.0.as_str() == name)?.1.clone().decode().ok(),
+                _ => None,
+            }
+        }
+    }
+
+    pub trait NoDecode<T> {
+        fn decode(&self, _: &Kserd<'static>, _: &str) -> Option<T> {
+            None
+        }
+    }
+
+    impl<T> NoDecode<T> for &Var<PhantomData<T>> {}
+
+    pub trait Encode {
+        fn encode(&self) -> Option<Kserd<'static>>;
+    }
+
+    impl<T: Serialize> Encode for Var<&T> {
+        fn encode(&self) -> Option<Kserd<'static>> {
+            Kserd::enc(self.0).ok()
+        }
+    }
+
+    pub trait NoEncode {
+        fn encode(&self) -> Option<Kserd<'static>> {
+            None
+        }
+    }
+
+    impl<T> NoEncode for &Var<&T> {}
+
+    pub fn persist(vars: Vec<(&'static str, Option<Kserd<'static>>)>, out: Kserd<'static>) -> Kserd<'static> {
+        let vars = vars
+            .into_iter()
+            .map(|(name, x)| x.map(|x| (name, x)))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|x| Kserd::new_cntr(x).ok());
+        match vars {
+            // the names are borrowed from this library, which is unloaded after evaluation
+            Some(vars) => Kserd::with_id("__papyrus_persist", Value::Tuple(vec![out, vars]))
+                .unwrap()
+                .into_owned(),
+            None => out,
+        }
+    }
+}
+"#;
+
+// the code written by a persisting evaluation function, see `append_persist_*`
+const PERSIST_USE: &str =
+    "use crate::__papyrus_persist::{Decode as _, Encode as _, NoDecode as _, NoEncode as _};\n";
+const REPLAY_OPEN: &str = "let __papyrus_replay = move || {\n";
+const REPLAY_CLOSE: &str = ",)\n};\n";
+const RESTORE_OPEN: &str =
+    ",) = match crate::__papyrus_persist::vars().as_ref().and_then(|vars| Some((\n";
+const RESTORE_VAR: &str =
+    "(&crate::__papyrus_persist::Var(crate::__papyrus_persist::ty(&__papyrus_replay, |t| t.";
+const RESTORE_CLOSE: &str = "))) { Some(x) => x, None => __papyrus_replay() };\n";
+const RESTORE_DECODE: &str = "))).decode(vars, \"";
+const RESTORE_VAR_CLOSE: &str = "\")?,\n";
+const SNAPSHOT_OPEN: &str = "crate::__papyrus_persist::persist(vec![";
+const SNAPSHOT_VAR: &str = "\", (&crate::__papyrus_persist::Var(&";
+const SNAPSHOT_VAR_CLOSE: &str = ")).encode()), ";
+const SNAPSHOT_CLOSE: &str = "], ";
+
+/// The identity of the output of an evaluation function which does not implement `ToKserd`, a
+/// string of its `Debug` formatting.
+pub const DEBUG_ID: &str = "__papyrus_debug";
+
+/// The identity of the output of an evaluation function which implements neither `ToKserd` nor
+/// `Debug`, a string of its type name.
+pub const TYPE_NAME_ID: &str = "__papyrus_type_name";
+
+/// The identity of the output of an evaluation function which implements the `Mime` trait, a
+/// sequence of `(mime type, data)` tuples.
+pub const MIME_ID: &str = "__papyrus_mime";
+
+/// Appended to the source code, converts the output of an evaluation function into a `Kserd`.
+///
+/// The conversion is resolved with autoref specialisation, preferring `Mime`, then `ToKserd`,
+/// then `Debug`, then only the type name, such that any output can be returned.
+const DISPLAY: &str = r#"
+mod __papyrus_display {
+    use kserd::{Kserd, Kstr, ToKserd, Value};
+    use std::cell::Cell;
+    use std::fmt::Debug;
+
+    /// Rich display of a type in the REPL.
+    pub trait Mime {
+        /// Renderings of the value as pairs of MIME type (such as `text/html`) and data.
+        /// The `text/plain` rendering is written to the terminal.
+        fn mime(&self) -> Vec<(String, String)>;
+    }
+
+    pub struct Out<T>(Cell<Option<T>>);
+
+    impl<T> Out<T> {
+        pub fn new(x: T) -> Self {
+            Out(Cell::new(Some(x)))
+        }
+
+        fn take(&self) -> T {
+            self.0.take().expect("output is displayed once")
+        }
+    }
+
+    fn with_id(id: &'static str, s: String) -> Kserd<'static> {
+        Kserd::with_id(id, Value::Str(Kstr::owned(s))).unwrap().into_owned()
+    }
+
+    pub trait ViaMime {
+        fn display(&self) -> Kserd<'static>;
+    }
+
+    impl<T: Mime> ViaMime for &&&Out<T> {
+        fn display(&self) -> Kserd<'static> {
+            let str = |s: String| Kserd::new(Value::Str(Kstr::owned(s)));
+            let renderings = self
+                .take()
+                .mime()
+                .into_iter()
+                .map(|(mime, data)| Kserd::new(Value::Tuple(vec![str(mime), str(data)])))
+                .collect();
+            Kserd::with_id("__papyrus_mime", Value::Seq(renderings)).unwrap().into_owned()
+        }
+    }
+
+    pub trait ViaKserd {
+        fn display(&self) -> Kserd<'static>;
+    }
+
+    impl<'a, T: ToKserd<'a>> ViaKserd for &&Out<T> {
+        fn display(&self) -> Kserd<'static> {
+            self.take().into_kserd().unwrap().into_owned()
+        }
+    }
+
+    pub trait ViaDebug {
+        fn display(&self) -> Kserd<'static>;
+    }
+
+    impl<T: Debug> ViaDebug for &Out<T> {
+        fn display(&self) -> Kserd<'static> {
+            with_id("__papyrus_debug", format!("{:?}", self.take()))
+        }
+    }
+
+    pub trait ViaTypeName {
+        fn display(&self) -> Kserd<'static>;
+    }
+
+    impl<T> ViaTypeName for Out<T> {
+        fn display(&self) -> Kserd<'static> {
+            with_id("__papyrus_type_name", std::any::type_name::<T>().to_string())
+        }
+    }
+}
+"#;
+
+// the return expression of an evaluation function, see `DISPLAY`
+const DISPLAY_OPEN: &str = "{ use crate::__papyrus_display::{ViaDebug as _, ViaKserd as _, ViaMime as _, ViaTypeName as _}; (&&&&crate::__papyrus_display::Out::new(out";
+const DISPLAY_CLOSE: &str = ")).display() }";
+
 type ReturnRangeMap<'a> = fxhash::FxHashMap<&'a Path, ReturnRange>;
 
 /// Mapping of modules to source code.
@@ -75,7 +351,7 @@ pub struct Input {
 
 /// The flattened representation of source code.
 /// Statements are grouped based on the the 'out' number.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SourceCode {
     /// Module-level items (`fn`, `enum`, `type`, `struct`, etc.)
     pub items: Vec<Item>,
@@ -102,6 +378,56 @@ impl SourceCode {
         self.stmts.clear();
         self.crates.clear();
     }
+
+    /// The names bound by statements which are in scope after the last statement, in the order
+    /// they were bound. This includes the `out#` bindings of each statement group. A name which
+    /// is shadowed is only included at its last binding, and a name which is the output of a
+    /// statement group is left out, as it is moved into the `out#` binding.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use papyrus::code::*;
+    /// let mut src = SourceCode::default();
+    /// src.stmts.push(StmtGrp(vec![
+    ///     Statement { expr: "let (a, b) = (1, 2)".to_string(), semi: true },
+    ///     Statement { expr: "a".to_string(), semi: false },
+    /// ]));
+    /// src.stmts.push(StmtGrp(vec![
+    ///     Statement { expr: "let a = b".to_string(), semi: true },
+    ///     Statement { expr: "a * 2".to_string(), semi: false },
+    /// ]));
+    /// assert_eq!(src.bindings(), vec!["b", "out0", "a", "out1"]);
+    /// ```
+    pub fn bindings(&self) -> Vec<String> {
+        bindings(&self.stmts, true)
+            .into_iter()
+            .map(|x| x.0)
+            .collect()
+    }
+}
+
+/// The names, and whether they are bound mutably, in scope after the statement groups.
+///
+/// If `moved` is set, a binding which is the output of a statement group (such as `a` in
+/// `let a = 1; a`) is left out, as it is moved into the `out#` binding.
+fn bindings(stmts: &[StmtGrp], moved: bool) -> Vec<(String, bool)> {
+    let mut names: Vec<(String, bool)> = Vec::new();
+    for (i, grp) in stmts.iter().enumerate() {
+        let n = grp.0.len().saturating_sub(1);
+        for name in grp.0[..n].iter().flat_map(|x| x.bindings_mut()) {
+            names.retain(|x| x.0 != name.0);
+            names.push(name);
+        }
+        if moved {
+            if let Some(output) = grp.0.last() {
+                names.retain(|x| x.0 != output.expr.trim());
+            }
+        }
+        let out = format!("out{}", i);
+        names.retain(|x| x.0 != out);
+        names.push((out, false));
+    }
+    names
 }
 
 /// Group of statements that result in an expression to evaulate.
@@ -114,7 +440,7 @@ impl SourceCode {
 /// let grp = StmtGrp(vec![stmt1, stmt2]);
 /// assert_eq!(&grp.src_line(), "let a = 1; a");
 /// ```
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StmtGrp(pub Vec<Statement>);
 
 impl StmtGrp {
@@ -138,11 +464,27 @@ impl StmtGrp {
     }
 
     /// Stringfy's the statements and assigns trailing expressions with `let out# = expr;`.
-    fn assign_let_binding(&self, input_num: usize, buf: &mut String) {
+    /// Records the statement spans into `spans` if given.
+    fn assign_let_binding<'a>(
+        &'a self,
+        input_num: usize,
+        buf: &mut String,
+        mut spans: Option<&mut SpanSink<'a, '_>>,
+    ) {
         let stmts = &self.0;
+        let mut record = |stmt: usize, buf: &String| {
+            if let Some(spans) = spans.as_mut() {
+                let kind = SpanKind::Stmt {
+                    grp: input_num,
+                    stmt,
+                };
+                spans.push(kind, &stmts[stmt].expr, buf);
+            }
+        };
 
-        for stmt in &stmts[0..stmts.len().saturating_sub(1)] {
+        for (i, stmt) in stmts[0..stmts.len().saturating_sub(1)].iter().enumerate() {
             buf.push_str(&stmt.expr);
+            record(i, buf);
             if stmt.semi {
                 buf.push(';');
             }
@@ -154,6 +496,7 @@ impl StmtGrp {
             buf.push_str(&input_num.to_string());
             buf.push_str(" = ");
             buf.push_str(&stmts[stmts.len() - 1].expr);
+            record(stmts.len() - 1, buf);
             buf.push(';');
         }
     }
@@ -184,10 +527,44 @@ pub fn construct_source_code<'a>(
     mods_map: &'a ModsMap,
     linking_config: &LinkingConfiguration,
     static_files: &StaticFiles,
+) -> (String, ReturnRangeMap<'a>) {
+    construct_source_code_persisting(mods_map, linking_config, static_files, None)
+}
+
+/// Same as [`construct_source_code`], persisting the bindings of the module at `persist` rather
+/// than evaluating all its statements.
+///
+/// The module's evaluation function restores the bindings in scope before the last statement group
+/// from values passed to the library (if all the bindings can be decoded), otherwise the previous
+/// statements are evaluated as usual. The bindings in scope after the last statement group are
+/// returned alongside the output. Bindings are encoded and decoded using their `serde`
+/// implementations.
+pub(crate) fn construct_source_code_persisting<'a>(
+    mods_map: &'a ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+    persist: Option<&Path>,
+) -> (String, ReturnRangeMap<'a>) {
+    construct_source_code_spanned(
+        mods_map,
+        linking_config,
+        static_files,
+        persist,
+        &mut Vec::new(),
+    )
+}
+
+/// Constructs the source code, populating `spans` with the origin of statements and items.
+fn construct_source_code_spanned<'a>(
+    mods_map: &'a ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+    persist: Option<&Path>,
+    spans: &mut Vec<SourceSpan<'a>>,
 ) -> (String, ReturnRangeMap<'a>) {
     // assumed to be sorted, FileMap is BTreeMap
 
-    let (cap, map) = calc_capacity(mods_map, linking_config, static_files);
+    let (cap, map) = calc_capacity(mods_map, linking_config, static_files, persist);
 
     let mut contents = String::with_capacity(cap);
 
@@ -197,7 +574,7 @@ pub fn construct_source_code<'a>(
     }
 
     // do the lib first
-    if let Some(lib) = mods_map.get(Path::new("lib")) {
+    if let Some((path, lib)) = mods_map.get_key_value(Path::new("lib")) {
         // add static file links
         for n in static_files
             .iter()
@@ -212,10 +589,12 @@ pub fn construct_source_code<'a>(
         // append source code
         append_buffer(
             lib,
-            &into_mod_path_vec(Path::new("lib")),
+            &into_mod_path_vec(path),
             linking_config,
             &StaticFiles::new(), // don't pass through as handled as mods above
+            persist == Some(path),
             &mut contents,
+            Some(&mut SpanSink { path, spans }),
         );
     }
 
@@ -245,7 +624,9 @@ pub fn construct_source_code<'a>(
             &into_mod_path_vec(file),
             linking_config,
             static_files,
+            persist == Some(file),
             &mut contents,
+            Some(&mut SpanSink { path: file, spans }),
         );
     }
 
@@ -258,6 +639,10 @@ pub fn construct_source_code<'a>(
         contents.push('}');
     }
 
+    contents.push_str(CATCH_PANIC);
+    contents.push_str(PERSIST);
+    contents.push_str(DISPLAY);
+
     debug_assert_eq!(
         cap,
         contents.len(),
@@ -267,6 +652,116 @@ pub fn construct_source_code<'a>(
     (contents, map)
 }
 
+/// Construct the [`SourceMap`](SourceMap) of the source code that
+/// [`construct_source_code`](construct_source_code) produces.
+pub fn construct_source_map<'a>(
+    mods_map: &'a ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+) -> SourceMap<'a> {
+    construct_source_map_persisting(mods_map, linking_config, static_files, None)
+}
+
+/// Construct the [`SourceMap`](SourceMap) of the source code that
+/// [`construct_source_code_persisting`](construct_source_code_persisting) produces.
+pub(crate) fn construct_source_map_persisting<'a>(
+    mods_map: &'a ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+    persist: Option<&Path>,
+) -> SourceMap<'a> {
+    let mut spans = Vec::new();
+    construct_source_code_spanned(mods_map, linking_config, static_files, persist, &mut spans);
+    SourceMap(spans)
+}
+
+/// Maps regions of the constructed source code back to the REPL input.
+///
+/// Only statements and items are mapped, other code such as the evaluation function wrapper,
+/// persistent module code, and static files do not originate from REPL input.
+///
+/// # Example
+/// ```rust
+/// # use papyrus::code::*;
+/// # use papyrus::linking::LinkingConfiguration;
+/// # use std::path::{Path, PathBuf};
+/// let mut mods_map = ModsMap::new();
+/// let mut src = SourceCode::default();
+/// src.stmts.push(StmtGrp(vec![Statement { expr: "2 + 2".to_string(), semi: false }]));
+/// mods_map.insert(PathBuf::from("lib"), src);
+///
+/// let linking = LinkingConfiguration::default();
+/// let (code, _) = construct_source_code(&mods_map, &linking, &StaticFiles::new());
+/// let map = construct_source_map(&mods_map, &linking, &StaticFiles::new());
+///
+/// let offset = code.find("2 + 2").unwrap() + 4;
+/// let span = map.locate(offset).unwrap();
+/// assert_eq!(span.path, Path::new("lib"));
+/// assert_eq!(span.kind, SpanKind::Stmt { grp: 0, stmt: 0 });
+/// assert_eq!(span.text, "2 + 2");
+/// assert_eq!(&code[span.range.clone()], "2 + 2");
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceMap<'a>(Vec<SourceSpan<'a>>);
+
+/// A region of the constructed source code which originates from a statement or item.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceSpan<'a> {
+    /// The module path.
+    pub path: &'a Path,
+    /// The statement or item.
+    pub kind: SpanKind,
+    /// The original text.
+    pub text: &'a str,
+    /// The byte range in the constructed source code.
+    pub range: std::ops::Range<usize>,
+}
+
+/// The kind of code a [`SourceSpan`](SourceSpan) originates from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    /// A statement, indexed by the statement group (the `out#` number) and the index of the
+    /// statement within the group.
+    Stmt {
+        /// The statement group index.
+        grp: usize,
+        /// The statement index in the group.
+        stmt: usize,
+    },
+    /// An item, indexed in the module's items.
+    Item(usize),
+}
+
+impl<'a> SourceMap<'a> {
+    /// The spans, ordered by their position in the constructed source code.
+    pub fn spans(&self) -> &[SourceSpan<'a>] {
+        &self.0
+    }
+
+    /// Find the span which contains the byte `offset` of the constructed source code.
+    ///
+    /// The end of a span is inclusive, as compiler errors regularly point to the character _after_
+    /// some code.
+    pub fn locate(&self, offset: usize) -> Option<&SourceSpan<'a>> {
+        let idx = match self.0.binary_search_by_key(&offset, |x| x.range.start) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        self.0.get(idx).filter(|x| offset <= x.range.end)
+    }
+}
+
+impl fmt::Display for SpanKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpanKind::Stmt { grp, .. } => write!(f, "out{}", grp),
+            SpanKind::Item(i) => write!(f, "item{}", i),
+        }
+    }
+}
+
 /// Constructs the evaluation function name given the mod sequence path.
 /// Appends to the buffer.
 pub fn eval_fn_name<S: AsRef<str>>(mod_path: &[S], buf: &mut String) {
@@ -309,6 +804,7 @@ fn calc_capacity<'a>(
     mods_map: &'a ModsMap,
     linking_config: &LinkingConfiguration,
     static_files: &StaticFiles,
+    persist: Option<&Path>,
 ) -> (usize, ReturnRangeMap<'a>) {
     fn mv_rng(mut rng: ReturnRange, by: usize) -> ReturnRange {
         rng.start += by;
@@ -340,6 +836,7 @@ fn calc_capacity<'a>(
             &into_mod_path_vec(Path::new("lib")),
             linking_config,
             &StaticFiles::new(),
+            persist == Some(Path::new("lib")),
         );
 
         map.insert(Path::new("lib"), mv_rng(src_code_return, cap));
@@ -369,6 +866,7 @@ fn calc_capacity<'a>(
             &into_mod_path_vec(file),
             linking_config,
             static_files,
+            persist == Some(file),
         );
 
         map.insert(file, mv_rng(src_code_return, cap));
@@ -383,20 +881,28 @@ fn calc_capacity<'a>(
         .unwrap_or(0);
     cap += lvl;
 
+    cap += CATCH_PANIC.len() + PERSIST.len() + DISPLAY.len();
+
     (cap, map)
 }
 
-/// Build the buffer with the stringified contents of SourceCode
-fn append_buffer<S: AsRef<str>>(
-    src_code: &SourceCode,
+/// Build the buffer with the stringified contents of SourceCode, persisting the bindings if
+/// `persist`, and recording the spans of statements and items into `spans` if given.
+fn append_buffer<'a, S: AsRef<str>>(
+    src_code: &'a SourceCode,
     mod_path: &[S],
     linking_config: &linking::LinkingConfiguration,
     static_files: &StaticFiles,
+    persist: bool,
     buf: &mut String,
+    mut spans: Option<&mut SpanSink<'a, '_>>,
 ) {
     // do up top items first.
-    for item in src_code.items.iter().filter(|x| x.1) {
+    for (idx, item) in src_code.items.iter().enumerate().filter(|x| (x.1).1) {
         buf.push_str(item.0.as_str());
+        if let Some(spans) = spans.as_mut() {
+            spans.push(SpanKind::Item(idx), &item.0, buf);
+        }
         buf.push('\n');
     }
 
@@ -422,36 +928,76 @@ fn append_buffer<S: AsRef<str>>(
     eval_fn_name(mod_path, buf);
     buf.push('(');
     linking_config.construct_fn_args(buf);
-    buf.push_str(") -> kserd::Kserd<'static> {\n"); // 29 len
+    buf.push_str(") -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {\n"); // 57 len
 
     // add stmts
     let c = src_code.stmts.len();
     if c >= 1 {
         // only add statements if more than zero!
+        let prev = persisted_bindings(src_code, persist);
+        if persist {
+            buf.push_str(PERSIST_USE);
+        }
+        if !prev.is_empty() {
+            buf.push_str(REPLAY_OPEN);
+        }
         src_code.stmts.iter().enumerate().for_each(|(i, x)| {
-            x.assign_let_binding(i, buf);
+            if i + 1 == c && !prev.is_empty() {
+                append_restore(&prev, buf);
+            }
+            x.assign_let_binding(i, buf, spans.as_deref_mut());
             buf.push('\n');
         });
-        buf.push_str("kserd::ToKserd::into_kserd(out");
+        if persist {
+            append_snapshot(&bindings(&src_code.stmts, true), buf);
+        }
+        buf.push_str(DISPLAY_OPEN);
         buf.push_str(&c.saturating_sub(1).to_string());
-        buf.push_str(").unwrap().into_owned()\n");
+        buf.push_str(DISPLAY_CLOSE);
+        if persist {
+            buf.push(')');
+        }
+        buf.push('\n');
     } else {
         buf.push_str("kserd::Kserd::new_str(\"no statements\")\n");
     }
-    buf.push_str("}\n");
+    buf.push_str("})}\n");
 
     // add items
-    for item in src_code.items.iter().filter(|x| !x.1) {
+    for (idx, item) in src_code.items.iter().enumerate().filter(|x| !(x.1).1) {
         buf.push_str(item.0.as_str());
+        if let Some(spans) = spans.as_mut() {
+            spans.push(SpanKind::Item(idx), &item.0, buf);
+        }
         buf.push('\n');
     }
 }
 
+/// Records the [`SourceSpan`]s of a module's statements and items as they are written.
+struct SpanSink<'a, 'b> {
+    path: &'a Path,
+    spans: &'b mut Vec<SourceSpan<'a>>,
+}
+
+impl<'a, 'b> SpanSink<'a, 'b> {
+    /// Records `text`, which was just written to the end of `buf`.
+    fn push(&mut self, kind: SpanKind, text: &'a str, buf: &str) {
+        let end = buf.len();
+        self.spans.push(SourceSpan {
+            path: self.path,
+            kind,
+            text,
+            range: end - text.len()..end,
+        });
+    }
+}
+
 fn append_buffer_length<S: AsRef<str>>(
     src_code: &SourceCode,
     mod_path: &[S],
     linking_config: &linking::LinkingConfiguration,
     static_files: &StaticFiles,
+    persist: bool,
 ) -> (usize, ReturnRange) {
     let mut cap: usize = src_code
         .items
@@ -460,21 +1006,7 @@ fn append_buffer_length<S: AsRef<str>>(
         .map(|x| x.0.len() + 1)
         .sum();
 
-    // persistent module code
-    if !linking_config.persistent_module_code.is_empty() {
-        cap += linking_config.persistent_module_code.len() + 1;
-    }
-
-    // static files -- use crate::#;\n
-    cap += static_files
-        .iter()
-        .map(|x| x.path.as_path())
-        .filter_map(static_file_mod_name)
-        .map(|x| x.len() + 13)
-        .sum::<usize>();
-
-    // wrap stmts
-    cap += 31 + eval_fn_name_length(mod_path) + 1 + linking_config.construct_fn_args_length() + 29;
+    cap += eval_fn_header_length(mod_path, linking_config, static_files);
 
     // add stmts
     let c = src_code.stmts.len();
@@ -484,10 +1016,9 @@ fn append_buffer_length<S: AsRef<str>>(
             .iter()
             .enumerate()
             .map(|(i, x)| x.assign_let_binding_length(i) + 1)
-            .sum::<usize>();
-        let return_str = 30 // kserd::ToKserd::into_kserd(out
-            + c.saturating_sub(1).to_string().len()
-            + 24; // ).unwrap().into_owned()\n
+            .sum::<usize>()
+            + persist_prefix_length(src_code, persist);
+        let return_str = return_length(src_code, persist);
 
         (
             stmts + return_str,
@@ -497,7 +1028,7 @@ fn append_buffer_length<S: AsRef<str>>(
         // kserd::Kserd::new_str("no statements")\n
         (39, cap..cap + 38)
     };
-    cap += add + 2; // }\n
+    cap += add + 4; // })}\n
 
     // add items
     cap += src_code
@@ -510,6 +1041,163 @@ fn append_buffer_length<S: AsRef<str>>(
     (cap, rng)
 }
 
+/// The length of the return statement, including the snapshot of bindings if persisting.
+fn return_length(src_code: &SourceCode, persist: bool) -> usize {
+    let mut cap = DISPLAY_OPEN.len()
+        + src_code.stmts.len().saturating_sub(1).to_string().len()
+        + DISPLAY_CLOSE.len()
+        + 1;
+
+    if persist {
+        cap += snapshot_length(&bindings(&src_code.stmts, true)) + 1;
+    }
+
+    cap
+}
+
+/// The bindings which are restored by a persisting evaluation function, those in scope before the
+/// last statement group. Empty if not persisting.
+fn persisted_bindings(src_code: &SourceCode, persist: bool) -> Vec<(String, bool)> {
+    match src_code.stmts.split_last() {
+        Some((_, prev)) if persist => bindings(prev, true),
+        _ => Vec::new(),
+    }
+}
+
+/// The length of the code written before the last statement group when persisting.
+fn persist_prefix_length(src_code: &SourceCode, persist: bool) -> usize {
+    if !persist {
+        return 0;
+    }
+
+    let prev = persisted_bindings(src_code, persist);
+    if prev.is_empty() {
+        PERSIST_USE.len()
+    } else {
+        PERSIST_USE.len() + REPLAY_OPEN.len() + restore_length(&prev)
+    }
+}
+
+/// Closes the replay closure returning the `bindings`, and restores the bindings from the values
+/// passed to the library, replaying if they cannot be restored.
+///
+/// ```text
+/// (a, out0,)
+/// };
+/// let (mut a, out0,) = match ... Some((
+/// (&Var(ty(&__papyrus_replay, |t| t.0))).decode(vars, "a")?,
+/// (&Var(ty(&__papyrus_replay, |t| t.1))).decode(vars, "out0")?,
+/// ))) { Some(x) => x, None => __papyrus_replay() };
+/// ```
+fn append_restore(bindings: &[(String, bool)], buf: &mut String) {
+    buf.push('(');
+    for (i, (name, _)) in bindings.iter().enumerate() {
+        if i > 0 {
+            buf.push_str(", ");
+        }
+        buf.push_str(name);
+    }
+    buf.push_str(REPLAY_CLOSE);
+
+    buf.push_str("let (");
+    for (i, (name, mutable)) in bindings.iter().enumerate() {
+        if i > 0 {
+            buf.push_str(", ");
+        }
+        if *mutable {
+            buf.push_str("mut ");
+        }
+        buf.push_str(name);
+    }
+    buf.push_str(RESTORE_OPEN);
+
+    for (i, (name, _)) in bindings.iter().enumerate() {
+        buf.push_str(RESTORE_VAR);
+        buf.push_str(&i.to_string());
+        buf.push_str(RESTORE_DECODE);
+        buf.push_str(name);
+        buf.push_str(RESTORE_VAR_CLOSE);
+    }
+    buf.push_str(RESTORE_CLOSE);
+}
+
+fn restore_length(bindings: &[(String, bool)]) -> usize {
+    let names =
+        bindings.iter().map(|x| x.0.len()).sum::<usize>() + bindings.len().saturating_sub(1) * 2;
+    let muts = bindings.iter().filter(|x| x.1).count() * 4;
+    let vars = bindings
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| {
+            RESTORE_VAR.len()
+                + i.to_string().len()
+                + RESTORE_DECODE.len()
+                + name.len()
+                + RESTORE_VAR_CLOSE.len()
+        })
+        .sum::<usize>();
+
+    1 + names
+        + REPLAY_CLOSE.len()
+        + 5 // let (
+        + names
+        + muts
+        + RESTORE_OPEN.len()
+        + vars
+        + RESTORE_CLOSE.len()
+}
+
+/// Opens the return statement of a persisting evaluation function, which returns the encoded
+/// `bindings` alongside the output.
+fn append_snapshot(bindings: &[(String, bool)], buf: &mut String) {
+    buf.push_str(SNAPSHOT_OPEN);
+    for (name, _) in bindings {
+        buf.push_str("(\"");
+        buf.push_str(name);
+        buf.push_str(SNAPSHOT_VAR);
+        buf.push_str(name);
+        buf.push_str(SNAPSHOT_VAR_CLOSE);
+    }
+    buf.push_str(SNAPSHOT_CLOSE);
+}
+
+fn snapshot_length(bindings: &[(String, bool)]) -> usize {
+    SNAPSHOT_OPEN.len()
+        + bindings
+            .iter()
+            .map(|x| 2 + x.0.len() * 2 + SNAPSHOT_VAR.len() + SNAPSHOT_VAR_CLOSE.len())
+            .sum::<usize>()
+        + SNAPSHOT_CLOSE.len()
+}
+
+/// The length of the persistent module code, static file imports, and the evaluation function
+/// signature, as written by `append_buffer`.
+fn eval_fn_header_length<S: AsRef<str>>(
+    mod_path: &[S],
+    linking_config: &linking::LinkingConfiguration,
+    static_files: &StaticFiles,
+) -> usize {
+    let mut cap = 0;
+
+    // persistent module code
+    if !linking_config.persistent_module_code.is_empty() {
+        cap += linking_config.persistent_module_code.len() + 1;
+    }
+
+    // static files -- use crate::#;\n
+    cap += static_files
+        .iter()
+        .map(|x| x.path.as_path())
+        .filter_map(static_file_mod_name)
+        .map(|x| x.len() + 13)
+        .sum::<usize>();
+
+    // wrap stmts
+    cap += 31 + eval_fn_name_length(mod_path) + 1 + linking_config.construct_fn_args_length() + 57;
+
+    cap
+}
+
 /// A single item.
 ///
 /// Wraps as `(content, top_placement)`.
@@ -519,7 +1207,7 @@ fn append_buffer_length<S: AsRef<str>>(
 pub type Item = (String, bool);
 
 /// Represents an inner statement.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Statement {
     /// The code, not including the trailing semi if there is one.
     pub expr: String,
@@ -527,10 +1215,63 @@ pub struct Statement {
     pub semi: bool,
 }
 
+impl Statement {
+    /// The names bound if the statement is a `let` statement.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use papyrus::code::Statement;
+    /// let stmt = Statement { expr: "let (a, mut b, _) = (1, 2, 3)".to_string(), semi: true };
+    /// assert_eq!(stmt.bindings(), vec!["a", "b"]);
+    /// ```
+    pub fn bindings(&self) -> Vec<String> {
+        self.bindings_mut().into_iter().map(|x| x.0).collect()
+    }
+
+    /// The names bound, and whether they are bound mutably.
+    fn bindings_mut(&self) -> Vec<(String, bool)> {
+        fn pat_idents(pat: &syn::Pat, buf: &mut Vec<(String, bool)>) {
+            use syn::Pat;
+
+            match pat {
+                Pat::Ident(x) => {
+                    buf.push((x.ident.to_string(), x.mutability.is_some()));
+                    if let Some((_, pat)) = &x.subpat {
+                        pat_idents(pat, buf);
+                    }
+                }
+                Pat::Box(x) => pat_idents(&x.pat, buf),
+                Pat::Or(x) => {
+                    // each case binds the same names
+                    if let Some(pat) = x.cases.first() {
+                        pat_idents(pat, buf);
+                    }
+                }
+                Pat::Reference(x) => pat_idents(&x.pat, buf),
+                Pat::Slice(x) => x.elems.iter().for_each(|x| pat_idents(x, buf)),
+                Pat::Struct(x) => x.fields.iter().for_each(|x| pat_idents(&x.pat, buf)),
+                Pat::Tuple(x) => x.elems.iter().for_each(|x| pat_idents(x, buf)),
+                Pat::TupleStruct(x) => x.pat.elems.iter().for_each(|x| pat_idents(x, buf)),
+                Pat::Type(x) => pat_idents(&x.pat, buf),
+                _ => (),
+            }
+        }
+
+        let mut buf = Vec::new();
+        if let Ok(syn::Stmt::Local(local)) = syn::parse_str(&format!("{};", self.expr)) {
+            pat_idents(&local.pat, &mut buf);
+        }
+        buf
+    }
+}
+
 /// Some definition around crate names.
 ///
 /// Crates are parsed and made suitable for `Cargo.toml`. The input line is kept verbatim.
 ///
+/// The dependency can be specified with a `#[papyrus(...)]` attribute on the `extern crate` item,
+/// see [`DependencySpec`](DependencySpec). Without an attribute the latest version is used.
+///
 /// # Examples
 /// ```rust
 /// # use papyrus::code::CrateType;
@@ -538,8 +1279,14 @@ pub struct Statement {
 /// let cr = CrateType::parse_str(input).unwrap();
 /// assert_eq!(&cr.src_line, input);
 /// assert_eq!(&cr.cargo_name, "a-crate");
+///
+/// let input = r#"#[papyrus(version = "0.7", features("small_rng"))] extern crate rand;"#;
+/// let cr = CrateType::parse_str(input).unwrap();
+/// assert_eq!(&cr.cargo_name, "rand");
+/// assert_eq!(cr.spec.version.as_deref(), Some("0.7"));
+/// assert_eq!(&cr.spec.features, &["small_rng"]);
 /// ```
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CrateType {
     /// The source line which adds the crates.
     /// This is usually `extern crate crate_name;` or could be `extern crate crate_name as alias;`
@@ -547,12 +1294,18 @@ pub struct CrateType {
     /// The name to use in cargo.
     /// Usually `crate_name` will turn into `crate-name`. The default behaviour is to replace `_` with a dash (`-`).
     pub cargo_name: String,
+    /// The dependency specification, from a `#[papyrus(...)]` attribute.
+    #[serde(default)]
+    pub spec: DependencySpec,
 }
 
 impl CrateType {
     /// Parses a string to return the `CrateType`.
     pub fn parse_str(string: &str) -> Result<Self, &'static str> {
-        let line = string
+        let (attrs, rest) = split_outer_attrs(string);
+        let spec = DependencySpec::parse_attrs(attrs)?;
+
+        let line = rest
             .replace(';', "")
             .replace('_', "-")
             .trim()
@@ -568,6 +1321,7 @@ impl CrateType {
                     .nth(2)
                     .expect("should always have trailing item")
                     .to_string(),
+                spec,
             })
         } else {
             Err("line needs `extern crate NAME;`")
@@ -575,6 +1329,212 @@ impl CrateType {
     }
 }
 
+/// The `Cargo.toml` dependency specification of a crate.
+///
+/// This is specified using a `papyrus` attribute on the `extern crate` item:
+///
+/// ```rust,ignore
+/// #[papyrus(version = "0.7", features("small_rng"), default_features = false)]
+/// extern crate rand;
+/// #[papyrus(git = "https://github.com/rust-random/rand", branch = "master")]
+/// extern crate rand_core;
+/// #[papyrus(path = "../my-lib")]
+/// extern crate my_lib;
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DependencySpec {
+    /// The version requirement, `None` will use the latest version (`"*"`).
+    pub version: Option<String>,
+    /// Features to enable.
+    pub features: Vec<String>,
+    /// Use the default features. Defaults to `true`.
+    pub default_features: bool,
+    /// A git repository source.
+    pub git: Option<String>,
+    /// The git branch.
+    pub branch: Option<String>,
+    /// The git revision.
+    pub rev: Option<String>,
+    /// A local path source.
+    pub path: Option<String>,
+}
+
+impl Default for DependencySpec {
+    fn default() -> Self {
+        Self {
+            version: None,
+            features: Vec::new(),
+            default_features: true,
+            git: None,
+            branch: None,
+            rev: None,
+            path: None,
+        }
+    }
+}
+
+const INVALID_PAPYRUS_ATTR: &str = "invalid papyrus attribute, expecting key-values such as \
+`#[papyrus(version = \"1\", features(\"a\"), default_features = false, git = \"url\", \
+branch = \"b\", rev = \"r\", path = \"p\")]`";
+
+impl DependencySpec {
+    /// Parse the `#[papyrus(...)]` attributes in `attrs`. Other attributes are ignored.
+    fn parse_attrs(attrs: &str) -> Result<Self, &'static str> {
+        use syn::{parse::Parser, Attribute, Lit, Meta, NestedMeta};
+
+        let mut spec = Self::default();
+
+        if attrs.trim().is_empty() {
+            return Ok(spec);
+        }
+
+        let attrs = Attribute::parse_outer
+            .parse_str(attrs)
+            .map_err(|_| INVALID_PAPYRUS_ATTR)?;
+
+        for attr in attrs.iter().filter(|x| x.path.is_ident("papyrus")) {
+            let list = match attr.parse_meta() {
+                Ok(Meta::List(list)) => list,
+                _ => return Err(INVALID_PAPYRUS_ATTR),
+            };
+
+            for nested in list.nested {
+                match nested {
+                    NestedMeta::Meta(Meta::NameValue(nv)) => {
+                        let key = nv.path.get_ident().map(|x| x.to_string());
+                        match (key.as_deref(), nv.lit) {
+                            (Some("version"), Lit::Str(s)) => spec.version = Some(s.value()),
+                            (Some("git"), Lit::Str(s)) => spec.git = Some(s.value()),
+                            (Some("branch"), Lit::Str(s)) => spec.branch = Some(s.value()),
+                            (Some("rev"), Lit::Str(s)) => spec.rev = Some(s.value()),
+                            (Some("path"), Lit::Str(s)) => spec.path = Some(s.value()),
+                            (Some("default_features"), Lit::Bool(b)) => {
+                                spec.default_features = b.value
+                            }
+                            _ => return Err(INVALID_PAPYRUS_ATTR),
+                        }
+                    }
+                    NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("features") => {
+                        for f in list.nested {
+                            match f {
+                                NestedMeta::Lit(Lit::Str(s)) => spec.features.push(s.value()),
+                                _ => return Err(INVALID_PAPYRUS_ATTR),
+                            }
+                        }
+                    }
+                    _ => return Err(INVALID_PAPYRUS_ATTR),
+                }
+            }
+        }
+
+        Ok(spec)
+    }
+
+    /// The `Cargo.toml` value of the dependency.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use papyrus::code::DependencySpec;
+    /// let mut spec = DependencySpec::default();
+    /// assert_eq!(&spec.toml_value(), r#""*""#);
+    /// spec.version = Some("0.7".to_string());
+    /// assert_eq!(&spec.toml_value(), r#""0.7""#);
+    /// spec.default_features = false;
+    /// assert_eq!(
+    ///     &spec.toml_value(),
+    ///     r#"{ version = "0.7", default-features = false }"#
+    /// );
+    /// ```
+    pub fn toml_value(&self) -> String {
+        let mut kvs = Vec::new();
+
+        if let Some(v) = &self.version {
+            kvs.push(format!("version = {}", toml_str(v)));
+        }
+        if let Some(git) = &self.git {
+            kvs.push(format!("git = {}", toml_str(git)));
+        }
+        if let Some(branch) = &self.branch {
+            kvs.push(format!("branch = {}", toml_str(branch)));
+        }
+        if let Some(rev) = &self.rev {
+            kvs.push(format!("rev = {}", toml_str(rev)));
+        }
+        if let Some(path) = &self.path {
+            kvs.push(format!("path = {}", toml_str(path)));
+        }
+        if !self.features.is_empty() {
+            kvs.push(format!(
+                "features = {}",
+                toml::Value::from(self.features.clone())
+            ));
+        }
+        if !self.default_features {
+            kvs.push("default-features = false".to_string());
+        }
+
+        match (kvs.len(), &self.version) {
+            (0, _) => r#""*""#.to_string(),
+            (1, Some(v)) => toml_str(v),
+            _ => format!("{{ {} }}", kvs.join(", ")),
+        }
+    }
+
+    /// The specification has not been altered from the default.
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Formats as a TOML string, escaped as needed.
+pub(crate) fn toml_str(s: &str) -> String {
+    toml::Value::from(s).to_string()
+}
+
+/// Splits leading outer attributes (`#[...]`) from the rest of the string.
+fn split_outer_attrs(s: &str) -> (&str, &str) {
+    let mut end = 0;
+
+    loop {
+        let rest = &s[end..];
+        let trimmed = rest.trim_start();
+        // token streams are spaced, `# [attr]`
+        if !trimmed.starts_with('#') || !trimmed[1..].trim_start().starts_with('[') {
+            break;
+        }
+
+        let start = end + (rest.len() - trimmed.len());
+        let mut depth = 0;
+        let mut in_str = false;
+        let mut escaped = false;
+        let mut close = None;
+
+        for (idx, ch) in trimmed.char_indices() {
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' if in_str => escaped = true,
+                '"' => in_str = !in_str,
+                '[' if !in_str => depth += 1,
+                ']' if !in_str => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(idx);
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        match close {
+            Some(idx) => end = start + idx + 1,
+            None => break, // unclosed, leave as is
+        }
+    }
+
+    (&s[..end], &s[end..])
+}
+
 // ###### STATIC FILES ###################################################################
 /// A static file pointer.
 pub struct StaticFile {
@@ -808,6 +1768,76 @@ mod tests {
         assert_eq!(i.next(), None);
     }
 
+    #[test]
+    fn test_parse_crate_spec() {
+        let c = CrateType::parse_str(
+            r#"#[papyrus(version = "0.7", features("a", "b"), default_features = false)]
+extern crate rand;"#,
+        )
+        .unwrap();
+        assert_eq!(&c.cargo_name, "rand");
+        assert_eq!(
+            c.spec.toml_value(),
+            r#"{ version = "0.7", features = ["a", "b"], default-features = false }"#
+        );
+
+        let c = CrateType::parse_str(
+            r#"#[macro_use] #[papyrus(git = "https://github.com/a/b", branch = "]")] extern crate some_lib as s;"#,
+        )
+        .unwrap();
+        assert_eq!(&c.cargo_name, "some-lib");
+        assert_eq!(
+            c.spec.toml_value(),
+            r#"{ git = "https://github.com/a/b", branch = "]" }"#
+        );
+
+        let c = CrateType::parse_str(r#"#[papyrus(path = "../lib")] extern crate lib;"#).unwrap();
+        assert_eq!(c.spec.toml_value(), r#"{ path = "../lib" }"#);
+
+        let c = CrateType::parse_str("#[macro_use] extern crate rand;").unwrap();
+        assert_eq!(&c.cargo_name, "rand");
+        assert!(c.spec.is_default());
+
+        // escaped as toml, not rust
+        let spec = DependencySpec {
+            path: Some("C:\\crates\\caf\u{e9}\n\"x\"".to_string()),
+            ..Default::default()
+        };
+        let toml: toml::Value = format!("a = {}", spec.toml_value()).parse().unwrap();
+        assert_eq!(toml["a"]["path"].as_str(), spec.path.as_deref());
+
+        let err = Err(INVALID_PAPYRUS_ATTR);
+        assert_eq!(
+            CrateType::parse_str(r#"#[papyrus(version = 1)] extern crate rand;"#),
+            err
+        );
+        assert_eq!(
+            CrateType::parse_str(r#"#[papyrus(wrong = "1")] extern crate rand;"#),
+            err
+        );
+        assert_eq!(
+            CrateType::parse_str(r#"#[papyrus] extern crate rand;"#),
+            err
+        );
+    }
+
+    #[test]
+    fn split_outer_attrs_test() {
+        assert_eq!(
+            split_outer_attrs("extern crate a;"),
+            ("", "extern crate a;")
+        );
+        assert_eq!(
+            split_outer_attrs("#[a] #[b(\"]\")]\nextern crate a;"),
+            ("#[a] #[b(\"]\")]", "\nextern crate a;")
+        );
+        assert_eq!(split_outer_attrs("#[a"), ("", "#[a"));
+        assert_eq!(
+            split_outer_attrs("# [papyrus (version = \"1\")] extern crate a ;"),
+            ("# [papyrus (version = \"1\")]", " extern crate a ;")
+        );
+    }
+
     #[test]
     fn test_parse_crate() {
         let err = Err("line needs `extern crate NAME;`");
@@ -827,6 +1857,7 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("somelib"),
+                spec: DependencySpec::default(),
             })
         );
 
@@ -837,6 +1868,7 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some-lib"),
+                spec: DependencySpec::default(),
             })
         );
 
@@ -847,6 +1879,7 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some"),
+                spec: DependencySpec::default(),
             })
         );
 
@@ -857,6 +1890,7 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some-lib"),
+                spec: DependencySpec::default(),
             })
         );
     }
@@ -866,7 +1900,7 @@ mod tests {
         let mut grp = StmtGrp(vec![]);
 
         let mut s = String::new();
-        grp.assign_let_binding(0, &mut s);
+        grp.assign_let_binding(0, &mut s, None);
 
         let ans = "";
         assert_eq!(&s, ans);
@@ -878,7 +1912,7 @@ mod tests {
         });
 
         let mut s = String::new();
-        grp.assign_let_binding(0, &mut s);
+        grp.assign_let_binding(0, &mut s, None);
 
         let ans = "let out0 = a;";
         assert_eq!(&s, ans);
@@ -890,14 +1924,14 @@ mod tests {
         });
 
         let mut s = String::new();
-        grp.assign_let_binding(0, &mut s);
+        grp.assign_let_binding(0, &mut s, None);
 
         let ans = "a\nlet out0 = b;";
         assert_eq!(&s, ans);
         assert_eq!(grp.assign_let_binding_length(0), ans.len());
 
         let mut s = String::new();
-        grp.assign_let_binding(100, &mut s);
+        grp.assign_let_binding(100, &mut s, None);
 
         let ans = "a\nlet out100 = b;";
         assert_eq!(&s, ans);
@@ -918,19 +1952,26 @@ mod tests {
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            false,
             &mut s,
+            None,
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            false,
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 73..111);
+        assert_eq!(rng, 101..139);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // alter mod path
@@ -942,19 +1983,26 @@ kserd::Kserd::new_str("no statements")
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            false,
             &mut s,
+            None,
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            false,
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 83..121);
+        assert_eq!(rng, 111..149);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // alter the linking config
@@ -969,19 +2017,26 @@ kserd::Kserd::new_str("no statements")
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            false,
             &mut s,
+            None,
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            false,
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 100..138);
+        assert_eq!(rng, 128..166);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // add an item and new input
@@ -994,21 +2049,28 @@ kserd::Kserd::new_str("no statements")
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            false,
             &mut s,
+            None,
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            false,
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 fn a() {}
 fn b() {}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 100..138);
+        assert_eq!(rng, 128..166);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // add stmts
@@ -1049,30 +2111,37 @@ fn b() {}
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            false,
             &mut s,
+            None,
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            false,
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#![feature(UP_TOP)]
 some-injected-persistent-code
 #[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 let a = 1;
 let out0 = b;
 let c = 2;
 let out1 = d;
-kserd::ToKserd::into_kserd(out1).unwrap().into_owned()
-}
+{ use crate::__papyrus_display::{ViaDebug as _, ViaKserd as _, ViaMime as _, ViaTypeName as _}; (&&&&crate::__papyrus_display::Out::new(out1)).display() }
+})}
 fn a() {}
 fn b() {}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 200..254);
+        assert_eq!(rng, 228..382);
         assert_eq!(
             &ans[rng],
-            "kserd::ToKserd::into_kserd(out1).unwrap().into_owned()"
+            "{ use crate::__papyrus_display::{ViaDebug as _, ViaKserd as _, ViaMime as _, ViaTypeName as _}; (&&&&crate::__papyrus_display::Out::new(out1)).display() }"
         );
     }
 
@@ -1096,40 +2165,40 @@ fn b() {}
         let (s, map) = construct_source_code(&map, &linking, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 mod foo {
 #[no_mangle]
-pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 mod bar {
 #[no_mangle]
-pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 }}
 mod test {
 #[no_mangle]
-pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 mod inner {
 #[no_mangle]
-pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 }
 mod inner2 {
 #[no_mangle]
-pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 }}"##;
 
         let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
-        assert_eq!(&s, ans);
+        assert_eq!(s, [ans, CATCH_PANIC, PERSIST, DISPLAY].concat());
         assert_eq!(
             &ans[map.get(Path::new("lib")).unwrap().clone()],
             return_stmt
@@ -1156,6 +2225,138 @@ kserd::Kserd::new_str("no statements")
         );
     }
 
+    #[test]
+    fn construct_persist_test() {
+        let mut src_code = SourceCode::default();
+        src_code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let mut a = vec![1]".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a.len()".to_string(),
+                semi: false,
+            },
+        ]));
+        src_code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "a.push(2)".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a".to_string(),
+                semi: false,
+            },
+        ]));
+
+        let map = vec![("lib".into(), src_code)].into_iter().collect();
+        let linking = LinkingConfiguration::default();
+        let lib = Some(Path::new("lib"));
+        let (s, rng) = construct_source_code_persisting(&map, &linking, &StaticFiles::new(), lib);
+
+        let ans = r##"#[no_mangle]
+pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
+use crate::__papyrus_persist::{Decode as _, Encode as _, NoDecode as _, NoEncode as _};
+let __papyrus_replay = move || {
+let mut a = vec![1];
+let out0 = a.len();
+(a, out0,)
+};
+let (mut a, out0,) = match crate::__papyrus_persist::vars().as_ref().and_then(|vars| Some((
+(&crate::__papyrus_persist::Var(crate::__papyrus_persist::ty(&__papyrus_replay, |t| t.0))).decode(vars, "a")?,
+(&crate::__papyrus_persist::Var(crate::__papyrus_persist::ty(&__papyrus_replay, |t| t.1))).decode(vars, "out0")?,
+))) { Some(x) => x, None => __papyrus_replay() };
+a.push(2);
+let out1 = a;
+crate::__papyrus_persist::persist(vec![("out0", (&crate::__papyrus_persist::Var(&out0)).encode()), ("out1", (&crate::__papyrus_persist::Var(&out1)).encode()), ], { use crate::__papyrus_display::{ViaDebug as _, ViaKserd as _, ViaMime as _, ViaTypeName as _}; (&&&&crate::__papyrus_display::Out::new(out1)).display() })
+})}
+"##;
+
+        assert_eq!(s, [ans, CATCH_PANIC, PERSIST, DISPLAY].concat());
+        assert_eq!(
+            &s[rng.get(Path::new("lib")).unwrap().clone()],
+            ans.lines().nth(14).unwrap()
+        );
+
+        let source_map = construct_source_map_persisting(&map, &linking, &StaticFiles::new(), lib);
+        let texts = source_map
+            .spans()
+            .iter()
+            .map(|x| &s[x.range.clone()])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec!["let mut a = vec![1]", "a.len()", "a.push(2)", "a"]
+        );
+    }
+
+    #[test]
+    fn construct_source_map_test() {
+        fn stmt(expr: &str, semi: bool) -> Statement {
+            Statement {
+                expr: expr.to_string(),
+                semi,
+            }
+        }
+
+        let src = SourceCode {
+            items: vec![
+                ("#![allow(unused)]".to_string(), true),
+                ("fn a() -> i32 { 1 }".to_string(), false),
+                ("struct B;".to_string(), false),
+            ],
+            stmts: vec![
+                StmtGrp(vec![stmt("let x = a()", true), stmt("x + 1", false)]),
+                StmtGrp(vec![stmt("println!(\"{}\", x)", true)]),
+            ],
+            crates: vec![],
+        };
+
+        let map: ModsMap = vec![
+            ("lib".into(), src.clone()),
+            ("foo".into(), SourceCode::default()),
+            ("foo/bar".into(), src.clone()),
+            ("test".into(), src),
+        ]
+        .into_iter()
+        .collect();
+
+        let linking = LinkingConfiguration {
+            persistent_module_code: "use std::io;".to_string(),
+            ..Default::default()
+        };
+        let static_files = vec![StaticFile {
+            path: "sf.rs".into(),
+            codehash: Box::new([0; 32]),
+            crates: vec![],
+        }]
+        .into_iter()
+        .collect();
+
+        let persist = Some(Path::new("foo/bar"));
+        let (code, _) = construct_source_code_persisting(&map, &linking, &static_files, persist);
+        let source_map = construct_source_map_persisting(&map, &linking, &static_files, persist);
+
+        assert_eq!(source_map.spans().len(), 18);
+        for span in source_map.spans() {
+            assert_eq!(&code[span.range.clone()], span.text);
+        }
+
+        let offset = code.find("struct B;").unwrap();
+        let span = source_map.locate(offset).unwrap();
+        assert_eq!(span.path, Path::new("lib"));
+        assert_eq!(span.kind, SpanKind::Item(2));
+
+        let offset = code.rfind("x + 1").unwrap() + 5; // end inclusive
+        let span = source_map.locate(offset).unwrap();
+        assert_eq!(span.path, Path::new("test"));
+        assert_eq!(span.kind, SpanKind::Stmt { grp: 0, stmt: 1 });
+        assert_eq!(span.kind.to_string(), "out0");
+
+        assert_eq!(source_map.locate(0), None);
+        assert_eq!(source_map.locate(code.find("#[no_mangle]").unwrap()), None);
+    }
+
     #[test]
     fn eval_fn_name_test() {
         let path: Vec<String> = ["some", "lib", "module", "path"]
@@ -1212,12 +2413,12 @@ kserd::Kserd::new_str("no statements")
 
         let ans = r##"Up Top
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 Test1
 "##;
-        assert_eq!(&s, ans);
+        assert_eq!(s, [ans, CATCH_PANIC, PERSIST, DISPLAY].concat());
     }
 
     #[test]
@@ -1335,51 +2536,51 @@ Test1
         let ans = r##"mod bar2;
 mod foo2;
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 mod foo {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 mod bar {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 }}
 mod test {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 mod inner {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 }
 mod inner2 {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 }}"##;
 
         let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
         println!("{}", s);
-        assert_eq!(&s, ans);
+        assert_eq!(s, [ans, CATCH_PANIC, PERSIST, DISPLAY].concat());
         assert_eq!(
             &ans[map.get(Path::new("lib")).unwrap().clone()],
             return_stmt
@@ -1481,14 +2682,14 @@ kserd::Kserd::new_str("no statements")
         let ans = r##"mod bar2;
 mod foo2;
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> { crate::__papyrus_catch(|| {
 kserd::Kserd::new_str("no statements")
-}
+})}
 "##;
 
         let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
         println!("{}", s);
-        assert_eq!(&s, ans);
+        assert_eq!(s, [ans, CATCH_PANIC, PERSIST, DISPLAY].concat());
         assert_eq!(
             &ans[map.get(Path::new("lib")).unwrap().clone()],
             return_stmt
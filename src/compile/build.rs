@@ -1,21 +1,218 @@
+use super::cache;
+use super::cancel::{CancelToken, Watchdog};
+use super::diagnostics::{parse_cargo_message, CargoMessage, Diagnostic};
 use super::LIBRARY_NAME;
+use crate::code::{toml_str, SourceMap};
 use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::{error, fmt};
+use std::{error, fmt, fs};
+
+/// Options passed to `cargo` when compiling.
+///
+/// The default is to resolve crates from `crates.io`, updating the index as required.
+///
+/// # Caching
+/// Compiled libraries are cached, keyed on a hash of the generated source code, `Cargo.toml`,
+/// `Cargo.lock`, `rustc` version, linking configuration, and these options. Compiling identical
+/// code (such as re-evaluating or editing back to a previous state) reuses the cached library
+/// rather than invoking `cargo`. The cache is stored in the compilation directory, limited to the
+/// [`cache_limit`] most recently used libraries.
+///
+/// # Offline compilation
+/// Build hosts without network access can compile offline by setting [`offline`] and either
+/// relying on the local cargo cache, or pointing [`source`] at a vendored directory or local
+/// registry. If a crate is not available the compilation fails with
+/// [`CompilationError::CrateUnavailable`](CompilationError::CrateUnavailable).
+///
+/// ```rust
+/// # use papyrus::compile::{CompileOptions, CrateSource};
+/// let opts = CompileOptions {
+///     offline: true,
+///     source: Some(CrateSource::Vendored("/opt/vendor".into())),
+///     ..Default::default()
+/// };
+/// ```
+///
+/// # Warnings
+/// Compiler warnings are suppressed unless [`warnings`] is set, in which case they are returned by
+/// [`compile_with_diagnostics`](compile_with_diagnostics).
+///
+/// # Build configuration
+/// Snippets are compiled using the `dev` profile by default. Benchmark-style code can be compiled
+/// optimised by setting [`release`] or [`opt_level`]. Compilation directories can share a warm
+/// target directory through [`target_dir`].
+///
+/// ```rust
+/// # use papyrus::compile::CompileOptions;
+/// let opts = CompileOptions {
+///     release: true,
+///     rustflags: vec!["-Ctarget-cpu=native".to_string()],
+///     toolchain: Some("nightly".to_string()),
+///     target_dir: Some("/var/cache/papyrus-target".into()),
+///     ..Default::default()
+/// };
+/// ```
+///
+/// [`cache_limit`]: CompileOptions::cache_limit
+/// [`offline`]: CompileOptions::offline
+/// [`opt_level`]: CompileOptions::opt_level
+/// [`release`]: CompileOptions::release
+/// [`target_dir`]: CompileOptions::target_dir
+/// [`source`]: CompileOptions::source
+/// [`warnings`]: CompileOptions::warnings
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileOptions {
+    /// Pass `--offline`, cargo will not access the network.
+    pub offline: bool,
+    /// Pass `--frozen`, cargo will not access the network _and_ requires `Cargo.lock` to be up to
+    /// date.
+    pub frozen: bool,
+    /// Replace `crates.io` as the source of crates. `None` uses `crates.io`.
+    pub source: Option<CrateSource>,
+    /// The maximum number of compiled libraries to cache. Zero disables caching. Defaults to 20.
+    pub cache_limit: usize,
+    /// Compile using the `release` profile.
+    pub release: bool,
+    /// Override the optimisation level of the REPL code, such as `"3"` or `"s"`.
+    /// This is passed as `-C opt-level` and does not affect dependencies.
+    pub opt_level: Option<String>,
+    /// Flags set as the `RUSTFLAGS` environment variable, these apply to dependencies as well.
+    pub rustflags: Vec<String>,
+    /// The Rust edition of the REPL code. Defaults to `2018`.
+    pub edition: String,
+    /// The toolchain to use, such as `nightly`. This is passed as `cargo +toolchain` and
+    /// requires `rustup`.
+    pub toolchain: Option<String>,
+    /// The target directory, relative paths are relative to the compilation directory.
+    /// Defaults to `target` in the compilation directory.
+    ///
+    /// Sharing a target directory between compilation directories avoids recompiling
+    /// dependencies, but compiling from the same target directory concurrently will block.
+    pub target_dir: Option<PathBuf>,
+    /// Report compiler warnings rather than passing `-Awarnings`.
+    pub warnings: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            offline: false,
+            frozen: false,
+            source: None,
+            cache_limit: 20,
+            release: false,
+            opt_level: None,
+            rustflags: Vec::new(),
+            edition: "2018".to_string(),
+            toolchain: None,
+            target_dir: None,
+            warnings: false,
+        }
+    }
+}
+
+/// A local source of crates, replacing `crates.io`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CrateSource {
+    /// Replace `crates.io` with a vendored directory, such as one created by `cargo vendor`.
+    Vendored(PathBuf),
+    /// Replace `crates.io` with a local registry, such as one created by `cargo local-registry`.
+    LocalRegistry(PathBuf),
+}
+
+impl CompileOptions {
+    /// Crates are expected to be available locally.
+    fn is_local(&self) -> bool {
+        self.offline || self.frozen || self.source.is_some()
+    }
+
+    /// The directory the library is compiled into, such as `target/debug`.
+    fn target_dir(&self, compile_dir: &Path) -> PathBuf {
+        let dir = self
+            .target_dir
+            .as_ref()
+            .map(|x| compile_dir.join(x))
+            .unwrap_or_else(|| compile_dir.join("target"));
+        dir.join(if self.release { "release" } else { "debug" })
+    }
+}
 
 /// Run `rustc` in the given compilation directory.
 pub fn compile<P, F>(
     compile_dir: P,
     linking_config: &crate::linking::LinkingConfiguration,
-    mut stderr_line_cb: F,
+    stderr_line_cb: F,
+) -> Result<PathBuf, CompilationError>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str),
+{
+    compile_with_options(
+        compile_dir,
+        linking_config,
+        &CompileOptions::default(),
+        stderr_line_cb,
+    )
+}
+
+/// Run `rustc` in the given compilation directory, configured with `options`.
+pub fn compile_with_options<P, F>(
+    compile_dir: P,
+    linking_config: &crate::linking::LinkingConfiguration,
+    options: &CompileOptions,
+    stderr_line_cb: F,
 ) -> Result<PathBuf, CompilationError>
 where
     P: AsRef<Path>,
     F: FnMut(&str),
 {
+    compile_with_diagnostics(compile_dir, linking_config, options, stderr_line_cb).map(|x| x.0)
+}
+
+/// Run `rustc` in the given compilation directory, returning the library path along with any
+/// warnings.
+///
+/// Warnings are only reported if [`CompileOptions::warnings`] is set. A library fetched from the
+/// cache returns the warnings of the original compilation.
+pub fn compile_with_diagnostics<P, F>(
+    compile_dir: P,
+    linking_config: &crate::linking::LinkingConfiguration,
+    options: &CompileOptions,
+    stderr_line_cb: F,
+) -> Result<(PathBuf, Vec<Diagnostic>), CompilationError>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str),
+{
+    compile_cancellable(
+        compile_dir,
+        linking_config,
+        options,
+        &CancelToken::default(),
+        stderr_line_cb,
+    )
+}
+
+/// Same as [`compile_with_diagnostics`] but `cargo` is killed if `cancel` is triggered. Nothing is
+/// compiled if `cancel` is already triggered.
+pub(crate) fn compile_cancellable<P, F>(
+    compile_dir: P,
+    linking_config: &crate::linking::LinkingConfiguration,
+    options: &CompileOptions,
+    cancel: &CancelToken,
+    mut stderr_line_cb: F,
+) -> Result<(PathBuf, Vec<Diagnostic>), CompilationError>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str),
+{
+    if cancel.is_cancelled() {
+        return Err(CompilationError::Cancelled);
+    }
+
     let compile_dir = compile_dir.as_ref();
-    let lib_file = compile_dir.join("target/debug/");
+    let lib_file = options.target_dir(compile_dir);
     let lib_file = if cfg!(windows) {
         lib_file.join(format!("{}.dll", LIBRARY_NAME))
     } else if cfg!(target_os = "macos") {
@@ -24,7 +221,49 @@ where
         lib_file.join(format!("lib{}.so", LIBRARY_NAME))
     };
 
-    let mut args = vec!["rustc".to_owned(), "--".to_owned(), "-Awarnings".to_owned()];
+    write_cargo_config(compile_dir, options.source.as_ref()).map_err(CompilationError::IOError)?;
+
+    let cache_key = if options.cache_limit > 0 {
+        let key = cache::cache_key(compile_dir, linking_config, options)
+            .map_err(CompilationError::IOError)?;
+        if let Some(parent) = lib_file.parent() {
+            fs::create_dir_all(parent).map_err(CompilationError::IOError)?;
+        }
+        if let Some(warnings) = cache::fetch(compile_dir, &key, &lib_file) {
+            return Ok((lib_file, warnings));
+        }
+        Some(key)
+    } else {
+        None
+    };
+
+    let mut args = Vec::new();
+    if let Some(toolchain) = &options.toolchain {
+        args.push(format!("+{}", toolchain));
+    }
+    args.push("rustc".to_owned());
+    if options.release {
+        args.push("--release".to_owned());
+    }
+    if let Some(dir) = &options.target_dir {
+        args.push("--target-dir".to_owned());
+        args.push(dir.display().to_string());
+    }
+    if options.offline {
+        args.push("--offline".to_owned());
+    }
+    if options.frozen {
+        args.push("--frozen".to_owned());
+    }
+    args.push("--message-format=json".to_owned());
+    args.push("--".to_owned());
+    if !options.warnings {
+        args.push("-Awarnings".to_owned());
+    }
+    if let Some(lvl) = &options.opt_level {
+        args.push("-C".to_owned());
+        args.push(format!("opt-level={}", lvl));
+    }
 
     for external in linking_config.external_libs.iter() {
         args.push("-L".to_owned());
@@ -37,7 +276,13 @@ where
         ));
     }
 
-    let mut child = Command::new("cargo")
+    let mut cmd = Command::new("cargo");
+    if !options.rustflags.is_empty() {
+        cmd.env("RUSTFLAGS", options.rustflags.join(" "));
+    }
+    own_process_group(&mut cmd);
+
+    let mut child = cmd
         .current_dir(compile_dir)
         .args(&args)
         .stdout(Stdio::piped())
@@ -45,6 +290,28 @@ where
         .spawn()
         .map_err(|_| CompilationError::NoBuildCommand)?;
 
+    let pid = child.id();
+    let watchdog = Watchdog::start(cancel, None, move || kill_process_tree(pid));
+
+    // compiler messages are written to stdout, read on another thread to avoid blocking
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    let messages = std::thread::spawn(move || {
+        let mut diagnostics = Vec::new();
+        let mut others = String::new();
+        for line in BufReader::new(stdout).lines() {
+            let line = match line {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            match parse_cargo_message(&line, LIBRARY_NAME) {
+                Some(CargoMessage::Target(x)) => diagnostics.push(x),
+                Some(CargoMessage::Other(x)) => others.push_str(&x),
+                None => (),
+            }
+        }
+        (diagnostics, others)
+    });
+
     let stderr = {
         let rdr = BufReader::new(child.stderr.as_mut().expect("stderr should be piped"));
         let mut s = String::new();
@@ -57,18 +324,116 @@ where
         s
     };
 
+    let cancelled = watchdog.finish().is_some();
+
+    let (diagnostics, others) = messages.join().unwrap_or_default();
+    let (warnings, errors): (Vec<_>, Vec<_>) =
+        diagnostics.into_iter().partition(|x| x.level == "warning");
+
     match child.wait() {
+        Ok(_) if cancelled => Err(CompilationError::Cancelled),
         Ok(ex) => {
             if ex.success() {
-                Ok(lib_file)
+                if let Some(key) = cache_key {
+                    // caching is an optimisation, failures are ignored
+                    cache::store(compile_dir, &key, &lib_file, &warnings, options.cache_limit).ok();
+                }
+                Ok((lib_file, warnings))
+            } else if !errors.is_empty() {
+                Err(CompilationError::Diagnostics(errors))
+            } else if let Some(krate) = unavailable_crate(&stderr).filter(|_| options.is_local()) {
+                Err(CompilationError::CrateUnavailable(krate, stderr))
             } else {
-                Err(CompilationError::CompileError(stderr))
+                // errors in other crates (such as dependencies) are only written to stdout
+                Err(CompilationError::CompileError(others + &stderr))
             }
         }
         Err(e) => Err(CompilationError::IOError(e)),
     }
 }
 
+/// `cargo` is started in its own process group, such that the `rustc` processes are killed with
+/// it, and a terminal interrupt is left to the REPL to handle.
+#[cfg(unix)]
+fn own_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setpgid(0, 0);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_: &mut Command) {}
+
+#[cfg(unix)]
+fn kill_process_tree(pid: u32) {
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+}
+
+#[cfg(windows)]
+fn kill_process_tree(pid: u32) {
+    Command::new("taskkill")
+        .args(&["/F", "/T", "/PID", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .ok();
+}
+
+/// Writes (or removes) the `.cargo/config.toml` in the compilation directory which replaces the
+/// `crates.io` source.
+fn write_cargo_config(compile_dir: &Path, source: Option<&CrateSource>) -> io::Result<()> {
+    let file = compile_dir.join(".cargo/config.toml");
+
+    let (kind, path) = match source {
+        None => {
+            if file.exists() {
+                fs::remove_file(file)?;
+            }
+            return Ok(());
+        }
+        Some(CrateSource::Vendored(path)) => ("directory", path),
+        Some(CrateSource::LocalRegistry(path)) => ("local-registry", path),
+    };
+
+    // relative paths are relative to the working directory, not the compilation directory
+    let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+
+    fs::create_dir_all(compile_dir.join(".cargo"))?;
+    fs::write(
+        file,
+        format!(
+            r#"[source.crates-io]
+replace-with = "papyrus-local"
+
+[source.papyrus-local]
+{} = {}
+"#,
+            kind,
+            toml_str(&path.display().to_string())
+        ),
+    )
+}
+
+/// Finds the name of a crate which cargo could not resolve.
+fn unavailable_crate(stderr: &str) -> Option<String> {
+    let patterns = [
+        "no matching package named `",
+        "failed to select a version for the requirement `",
+    ];
+
+    stderr.lines().find_map(|line| {
+        patterns.iter().find_map(|pat| {
+            let start = line.find(pat)? + pat.len();
+            let name = line[start..].split(&['`', ' '][..]).next()?;
+            Some(name.to_string())
+        })
+    })
+}
+
 /// Function to rename the output library file and remove the associated dependency.
 ///
 /// In relation to [#44](https://github.com/kurtlawrence/papyrus/issues/44), loading a library will
@@ -112,12 +477,36 @@ fn rename_lib_file<P: AsRef<Path>>(compiled_lib: P) -> io::Result<PathBuf> {
 pub enum CompilationError {
     /// Failed to initialise `cargo build`. Usually because `cargo` is not in your `PATH` or Rust is not installed.
     NoBuildCommand,
-    /// A compiling error occured, with the contents of the stderr.
+    /// A compiling error occured, with the contents of the stderr, preceded by the rendered errors
+    /// of other crates.
     CompileError(String),
+    /// The compiler produced error diagnostics.
+    ///
+    /// Use [`locate`](CompilationError::locate) to map the diagnostics back to the REPL input.
+    Diagnostics(Vec<Diagnostic>),
+    /// A crate is not available locally when compiling offline or from a local source.
+    /// Contains the crate name and the contents of the stderr.
+    CrateUnavailable(String, String),
+    /// The compilation was cancelled.
+    Cancelled,
     /// Generic IO errors.
     IOError(io::Error),
 }
 
+impl CompilationError {
+    /// Map the spans of any diagnostics back to the REPL input using `source_map`.
+    ///
+    /// The source map should be constructed with the same source code that was compiled, see
+    /// [`construct_source_map`](crate::code::construct_source_map).
+    pub fn locate(&mut self, source_map: &SourceMap) {
+        if let CompilationError::Diagnostics(diagnostics) = self {
+            for d in diagnostics {
+                d.locate(source_map);
+            }
+        }
+    }
+}
+
 impl error::Error for CompilationError {}
 
 impl fmt::Display for CompilationError {
@@ -127,6 +516,19 @@ impl fmt::Display for CompilationError {
                 write!(f, "cargo build command failed to start, is rust installed?")
             }
             CompilationError::CompileError(e) => write!(f, "{}", e),
+            CompilationError::Diagnostics(diagnostics) => {
+                for d in diagnostics {
+                    write!(f, "{}", d)?;
+                }
+                Ok(())
+            }
+            CompilationError::CrateUnavailable(name, _) => write!(
+                f,
+                "crate `{}` is not available locally, it needs to be vendored or cached before \
+                 compiling offline",
+                name
+            ),
+            CompilationError::Cancelled => write!(f, "compilation cancelled"),
             CompilationError::IOError(e) => write!(f, "io error occurred: {}", e),
         }
     }
@@ -141,7 +543,69 @@ fn compilation_error_fmt_test() {
     );
     let e = CompilationError::CompileError("compile err".to_string());
     assert_eq!(&e.to_string(), "compile err");
+    let e = CompilationError::CrateUnavailable("rand".to_string(), String::new());
+    assert_eq!(
+        &e.to_string(),
+        "crate `rand` is not available locally, it needs to be vendored or cached before compiling offline"
+    );
     let ioe = io::Error::new(io::ErrorKind::Other, "test");
     let e = CompilationError::IOError(ioe);
     assert_eq!(&e.to_string(), "io error occurred: test");
 }
+
+#[test]
+fn unavailable_crate_test() {
+    let stderr = "error: no matching package named `some_crate` found
+location searched: directory source `/tmp/vend` (which is replacing registry `crates-io`)";
+    assert_eq!(unavailable_crate(stderr), Some("some_crate".to_string()));
+
+    let stderr = "error: failed to select a version for the requirement `rand = \"^0.7\"`";
+    assert_eq!(unavailable_crate(stderr), Some("rand".to_string()));
+
+    assert_eq!(
+        unavailable_crate("error[E0425]: cannot find value `a`"),
+        None
+    );
+}
+
+#[test]
+fn write_cargo_config_test() {
+    let dir = Path::new("target/testing/write_cargo_config_test");
+    fs::create_dir_all(dir).unwrap();
+    let file = dir.join(".cargo/config.toml");
+
+    write_cargo_config(dir, Some(&CrateSource::Vendored("/opt/vendor".into()))).unwrap();
+    let s = fs::read_to_string(&file).unwrap();
+    assert!(s.contains("replace-with = \"papyrus-local\""));
+    assert!(s.contains("directory = \"/opt/vendor\""));
+
+    write_cargo_config(dir, Some(&CrateSource::LocalRegistry("/opt/reg".into()))).unwrap();
+    let s = fs::read_to_string(&file).unwrap();
+    assert!(s.contains("local-registry = \"/opt/reg\""));
+
+    // escaped as a TOML string
+    let path = "/opt/ven\"dor\\";
+    write_cargo_config(dir, Some(&CrateSource::Vendored(path.into()))).unwrap();
+    let s = fs::read_to_string(&file).unwrap();
+    let value = s.parse::<toml::Value>().unwrap();
+    assert_eq!(
+        value["source"]["papyrus-local"]["directory"].as_str(),
+        Some(path)
+    );
+
+    write_cargo_config(dir, None).unwrap();
+    assert!(!file.exists());
+}
+
+#[test]
+fn compile_options_target_dir_test() {
+    let mut opts = CompileOptions::default();
+    let dir = Path::new("/repl");
+    assert_eq!(opts.target_dir(dir), Path::new("/repl/target/debug"));
+    opts.release = true;
+    assert_eq!(opts.target_dir(dir), Path::new("/repl/target/release"));
+    opts.target_dir = Some("../shared".into());
+    assert_eq!(opts.target_dir(dir), Path::new("/repl/../shared/release"));
+    opts.target_dir = Some("/shared".into());
+    assert_eq!(opts.target_dir(dir), Path::new("/shared/release"));
+}
@@ -0,0 +1,242 @@
+//! A content addressed cache of compiled libraries.
+//!
+//! The cache key is a hash of the compilation directory's `Cargo.toml`, `Cargo.lock`, and source
+//! files, the `rustc` version, the linking configuration, and the compile options. If a library has
+//! been compiled with the same key, it is copied from the cache rather than compiling again. Any
+//! warnings from compiling the library are stored alongside it as JSON.
+//!
+//! The least recently used libraries are evicted, using the modification time of the warnings file
+//! which is rewritten whenever the library is fetched.
+use super::{CompileOptions, Diagnostic};
+use crate::linking::LinkingConfiguration;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const CACHE_DIR: &str = "target/papyrus-cache";
+
+/// Hashes the compilation inputs into a hex encoded key.
+pub(super) fn cache_key(
+    compile_dir: &Path,
+    linking_config: &LinkingConfiguration,
+    options: &CompileOptions,
+) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+
+    hasher.update(&fs::read(compile_dir.join("Cargo.toml"))?);
+    // the lock file only exists after the first compilation
+    if let Ok(lock) = fs::read(compile_dir.join("Cargo.lock")) {
+        hasher.update(&lock);
+    }
+    hasher.update(&rustc_version(compile_dir, options));
+
+    let mut files = Vec::new();
+    rs_files(&compile_dir.join("src"), &mut files)?;
+    files.sort();
+    for file in files {
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update(&fs::read(&file)?);
+    }
+
+    // linked libraries can be recompiled, so the modification time is used as well
+    let mut externals = linking_config
+        .external_libs
+        .iter()
+        .map(|x| {
+            let modified = fs::metadata(x.lib_path()).and_then(|x| x.modified()).ok();
+            format!("{} {:?} {:?}", x.lib_path().display(), x.alias(), modified)
+        })
+        .collect::<Vec<_>>();
+    externals.sort();
+    for external in externals {
+        hasher.update(external.as_bytes());
+    }
+
+    hasher.update(format!("{:?}", options).as_bytes());
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// The output of `rustc -V` for the toolchain which compiles in `compile_dir`. Empty if `rustc`
+/// could not be run.
+fn rustc_version(compile_dir: &Path, options: &CompileOptions) -> Vec<u8> {
+    let mut cmd = Command::new("rustc");
+    if let Some(toolchain) = &options.toolchain {
+        cmd.arg(format!("+{}", toolchain));
+    }
+    cmd.arg("-V")
+        .current_dir(compile_dir)
+        .output()
+        .map(|x| x.stdout)
+        .unwrap_or_default()
+}
+
+fn rs_files(dir: &Path, buf: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            rs_files(&path, buf)?;
+        } else if path.extension().map(|x| x == "rs").unwrap_or(false) {
+            buf.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn cache_file(compile_dir: &Path, key: &str) -> PathBuf {
+    compile_dir.join(CACHE_DIR).join(key)
+}
+
+/// Copies the cached library to `lib_file`, returning the warnings if the library was cached.
+pub(super) fn fetch(compile_dir: &Path, key: &str, lib_file: &Path) -> Option<Vec<Diagnostic>> {
+    let cached = cache_file(compile_dir, key);
+    if !cached.exists() || fs::copy(&cached, lib_file).is_err() {
+        return None;
+    }
+
+    let warnings = cached.with_extension("json");
+    let warnings = fs::read(&warnings)
+        .ok()
+        .and_then(|x| {
+            // rewritten to mark the library as recently used
+            fs::write(&warnings, &x).ok();
+            serde_json::from_slice(&x).ok()
+        })
+        .unwrap_or_default();
+    Some(warnings)
+}
+
+/// Stores the compiled library and its warnings in the cache, removing the least recently used
+/// entries to keep within `limit`.
+pub(super) fn store(
+    compile_dir: &Path,
+    key: &str,
+    lib_file: &Path,
+    warnings: &[Diagnostic],
+    limit: usize,
+) -> io::Result<()> {
+    let dir = compile_dir.join(CACHE_DIR);
+    fs::create_dir_all(&dir)?;
+    let cached = cache_file(compile_dir, key);
+    fs::copy(lib_file, &cached)?;
+    fs::write(cached.with_extension("json"), serde_json::to_vec(warnings)?)?;
+
+    // the warnings file is evicted with its library, and is touched when the library is used
+    let mut entries = fs::read_dir(&dir)?
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.extension().is_none())
+        .filter_map(|x| {
+            let used = fs::metadata(x.with_extension("json"))
+                .or_else(|_| fs::metadata(&x))
+                .and_then(|x| x.modified());
+            Some((used.ok()?, x))
+        })
+        .collect::<Vec<_>>();
+
+    if entries.len() > limit {
+        entries.sort();
+        for (_, path) in &entries[..entries.len() - limit] {
+            fs::remove_file(path)?;
+            fs::remove_file(path.with_extension("json")).ok();
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_key_test() {
+        let dir = Path::new("target/testing/cache_key_test");
+        fs::create_dir_all(dir.join("src/foo")).unwrap();
+        fs::write(dir.join("Cargo.toml"), "[package]").unwrap();
+        fs::write(dir.join("src/lib.rs"), "mod foo;").unwrap();
+        fs::write(dir.join("src/foo/mod.rs"), "pub fn a() {}").unwrap();
+
+        let linking = LinkingConfiguration::default();
+        let opts = CompileOptions::default();
+
+        let key = cache_key(dir, &linking, &opts).unwrap();
+        assert_eq!(key, cache_key(dir, &linking, &opts).unwrap());
+
+        fs::write(dir.join("src/foo/mod.rs"), "pub fn b() {}").unwrap();
+        let key2 = cache_key(dir, &linking, &opts).unwrap();
+        assert_ne!(key, key2);
+
+        fs::write(dir.join("src/foo/mod.rs"), "pub fn a() {}").unwrap();
+        assert_eq!(key, cache_key(dir, &linking, &opts).unwrap());
+
+        fs::write(dir.join("Cargo.lock"), "version = 3").unwrap();
+        assert_ne!(key, cache_key(dir, &linking, &opts).unwrap());
+        fs::remove_file(dir.join("Cargo.lock")).unwrap();
+        assert_eq!(key, cache_key(dir, &linking, &opts).unwrap());
+
+        let opts = CompileOptions {
+            offline: true,
+            ..Default::default()
+        };
+        assert_ne!(key, cache_key(dir, &linking, &opts).unwrap());
+    }
+
+    #[test]
+    fn store_and_fetch_test() {
+        let dir = Path::new("target/testing/cache_store_and_fetch_test");
+        fs::create_dir_all(dir).unwrap();
+        fs::remove_dir_all(dir.join(CACHE_DIR)).ok();
+        let lib = dir.join("lib.so");
+
+        assert!(fetch(dir, "a", &lib).is_none());
+
+        for key in &["a", "b", "c"] {
+            fs::write(&lib, key).unwrap();
+            store(dir, key, &lib, &[], 2).unwrap();
+            // modification times need to differ for eviction order
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+
+        assert!(fetch(dir, "a", &lib).is_none()); // evicted
+        assert!(!dir.join(CACHE_DIR).join("a.json").exists());
+        assert_eq!(fetch(dir, "b", &lib), Some(vec![]));
+        assert_eq!(fs::read_to_string(&lib).unwrap(), "b");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(fetch(dir, "c", &lib).is_some());
+        assert_eq!(fs::read_to_string(&lib).unwrap(), "c");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+
+        // fetching b marks it as used more recently than c
+        assert!(fetch(dir, "b", &lib).is_some());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(&lib, "d").unwrap();
+        store(dir, "d", &lib, &[], 2).unwrap();
+        assert!(fetch(dir, "c", &lib).is_none()); // evicted
+        assert!(fetch(dir, "b", &lib).is_some());
+        assert!(fetch(dir, "d", &lib).is_some());
+    }
+
+    #[test]
+    fn store_and_fetch_warnings_test() {
+        let dir = Path::new("target/testing/cache_store_and_fetch_warnings_test");
+        fs::create_dir_all(dir).unwrap();
+        fs::remove_dir_all(dir.join(CACHE_DIR)).ok();
+        let lib = dir.join("lib.so");
+        fs::write(&lib, "lib").unwrap();
+
+        let warning = Diagnostic {
+            message: "unused variable: `a`".to_string(),
+            code: Some("unused_variables".to_string()),
+            level: "warning".to_string(),
+            spans: vec![],
+            children: vec![],
+            rendered: None,
+        };
+
+        store(dir, "a", &lib, std::slice::from_ref(&warning), 2).unwrap();
+        assert_eq!(fetch(dir, "a", &lib), Some(vec![warning]));
+    }
+}
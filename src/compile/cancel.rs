@@ -0,0 +1,115 @@
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the watchdog checks for cancellation.
+const POLL: Duration = Duration::from_millis(20);
+
+/// A shared flag to cancel compiling or executing.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst)
+    }
+}
+
+/// Why the watchdog killed the process.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Stopped {
+    Cancelled,
+    TimedOut,
+}
+
+/// Watches a process on another thread, killing it if cancelled or after a timeout.
+pub(crate) struct Watchdog {
+    done: Sender<()>,
+    jh: JoinHandle<Option<Stopped>>,
+}
+
+impl Watchdog {
+    pub fn start<F>(cancel: &CancelToken, timeout: Option<Duration>, kill: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (done, rx) = bounded(1);
+        let cancel = cancel.clone();
+        let start = Instant::now();
+
+        let jh = std::thread::spawn(move || loop {
+            match rx.recv_timeout(POLL) {
+                Err(RecvTimeoutError::Timeout) => (),
+                _ => return None,
+            }
+
+            let stopped = if cancel.is_cancelled() {
+                Stopped::Cancelled
+            } else if timeout.map(|t| start.elapsed() >= t).unwrap_or(false) {
+                Stopped::TimedOut
+            } else {
+                continue;
+            };
+
+            kill();
+            return Some(stopped);
+        });
+
+        Self { done, jh }
+    }
+
+    /// Stops watching, returning if the process was killed.
+    ///
+    /// This must be called _before_ the process is reaped, otherwise the process id could be
+    /// reused.
+    pub fn finish(self) -> Option<Stopped> {
+        self.done.send(()).ok();
+        self.jh.join().unwrap_or(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn watchdog_test() {
+        let kills = Arc::new(AtomicUsize::new(0));
+        let kill = || {
+            let kills = Arc::clone(&kills);
+            move || {
+                kills.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let token = CancelToken::default();
+
+        let w = Watchdog::start(&token, None, kill());
+        assert_eq!(w.finish(), None);
+
+        let w = Watchdog::start(&token, Some(Duration::from_millis(50)), kill());
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(w.finish(), Some(Stopped::TimedOut));
+
+        let w = Watchdog::start(&token, None, kill());
+        token.cancel();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(w.finish(), Some(Stopped::Cancelled));
+        token.reset();
+        assert!(!token.is_cancelled());
+
+        assert_eq!(kills.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,4 +1,4 @@
-use super::LIBRARY_NAME;
+use super::{CompileOptions, LIBRARY_NAME};
 use crate::{
     code::{self, CrateType, ModsMap, StaticFiles},
     linking,
@@ -18,6 +18,49 @@ pub fn build_compile_dir<P>(
     linking_config: &linking::LinkingConfiguration,
     static_files: &StaticFiles,
 ) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    build_compile_dir_with_options(
+        compile_dir,
+        mods_map,
+        linking_config,
+        static_files,
+        &CompileOptions::default(),
+    )
+}
+
+/// Same as [`build_compile_dir`], using the edition in `options`.
+pub fn build_compile_dir_with_options<P>(
+    compile_dir: P,
+    mods_map: &ModsMap,
+    linking_config: &linking::LinkingConfiguration,
+    static_files: &StaticFiles,
+    options: &CompileOptions,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    build_compile_dir_persisting(
+        compile_dir,
+        mods_map,
+        linking_config,
+        static_files,
+        options,
+        None,
+    )
+}
+
+/// Same as [`build_compile_dir_with_options`], persisting the bindings of the module at `persist`
+/// (see [`construct_source_code_persisting`](code::construct_source_code_persisting)).
+pub(crate) fn build_compile_dir_persisting<P>(
+    compile_dir: P,
+    mods_map: &ModsMap,
+    linking_config: &linking::LinkingConfiguration,
+    static_files: &StaticFiles,
+    options: &CompileOptions,
+    persist: Option<&Path>,
+) -> io::Result<()>
 where
     P: AsRef<Path>,
 {
@@ -30,25 +73,28 @@ where
     let crates = dedup_crates(crates);
 
     // write cargo toml contents
-    create_file_and_dir(compile_dir.join("Cargo.toml"))?
-        .write_all(cargotoml_contents(LIBRARY_NAME, crates.into_iter()).as_bytes())?;
+    create_file_and_dir(compile_dir.join("Cargo.toml"))?.write_all(
+        cargotoml_contents(LIBRARY_NAME, &options.edition, crates.into_iter()).as_bytes(),
+    )?;
 
-    let (src_code, _map) = code::construct_source_code(mods_map, linking_config, static_files);
+    let (src_code, _map) =
+        code::construct_source_code_persisting(mods_map, linking_config, static_files, persist);
 
     create_file_and_dir(compile_dir.join("src/lib.rs"))?.write_all(src_code.as_bytes())?;
 
     Ok(())
 }
 
-fn dedup_crates<'a>(crates: impl Iterator<Item = &'a CrateType>) -> Vec<&'a CrateType> {
+pub(super) fn dedup_crates<'a>(crates: impl Iterator<Item = &'a CrateType>) -> Vec<&'a CrateType> {
     let mut crates: Vec<&CrateType> = crates.collect();
-    crates.sort_by_key(|x| &x.cargo_name);
+    // crates with a specification are preferred
+    crates.sort_by_key(|x| (&x.cargo_name, x.spec.is_default()));
     crates.dedup_by_key(|x| &x.cargo_name);
     crates
 }
 
 /// Creates the specified file along with the directory to it if it doesn't exist.
-fn create_file_and_dir<P: AsRef<Path>>(file: P) -> io::Result<fs::File> {
+pub(super) fn create_file_and_dir<P: AsRef<Path>>(file: P) -> io::Result<fs::File> {
     let file = file.as_ref();
     debug!("trying to create file: {}", file.display());
     if let Some(parent) = file.parent() {
@@ -57,12 +103,16 @@ fn create_file_and_dir<P: AsRef<Path>>(file: P) -> io::Result<fs::File> {
     fs::File::create(file)
 }
 
-fn cargotoml_contents<'a, I: Iterator<Item = &'a CrateType>>(lib_name: &str, crates: I) -> String {
+fn cargotoml_contents<'a, I: Iterator<Item = &'a CrateType>>(
+    lib_name: &str,
+    edition: &str,
+    crates: I,
+) -> String {
     format!(
         r#"[package]
 name = "{lib_name}"
 version = "0.1.0"
-edition = "2018"
+edition = "{edition}"
 
 [lib]
 name = "{lib_name}"
@@ -70,12 +120,14 @@ crate-type = [ "cdylib" ]
 path = "src/lib.rs"
 
 [dependencies]
-kserd = {{ version = "0.3", default-features = false, features = [ "format" ] }}
+kserd = {{ version = "0.3", default-features = false, features = [ "format", "encode" ] }}
+papyrus_backtrace = {{ package = "backtrace", version = "0.3" }}
 {crates}
 "#,
         lib_name = lib_name,
+        edition = edition,
         crates = crates
-            .map(|c| format!(r#"{} = "*""#, c.cargo_name))
+            .map(|c| format!("{} = {}", c.cargo_name, c.spec.toml_value()))
             .collect::<Vec<_>>()
             .join("\n")
     )
@@ -115,5 +167,28 @@ mod tests {
 
         let v: Vec<_> = crates.iter().map(|x| &x.cargo_name).collect();
         assert_eq!(&v, &["rand", "third"]);
+
+        let crates = [
+            CrateType::parse_str("extern crate rand;").unwrap(),
+            CrateType::parse_str(r#"#[papyrus(version = "0.7")] extern crate rand;"#).unwrap(),
+        ];
+        let crates = dedup_crates(crates.iter());
+        assert_eq!(crates.len(), 1);
+        assert_eq!(crates[0].spec.version.as_deref(), Some("0.7"));
+    }
+
+    #[test]
+    fn cargotoml_contents_spec_test() {
+        let crates = [
+            CrateType::parse_str("extern crate rand;").unwrap(),
+            CrateType::parse_str(
+                r#"#[papyrus(version = "1", features("derive"))] extern crate serde;"#,
+            )
+            .unwrap(),
+        ];
+        let s = cargotoml_contents("lib", "2021", crates.iter());
+        assert!(s.contains("\nedition = \"2021\"\n"));
+        assert!(s.contains("\nrand = \"*\"\n"));
+        assert!(s.contains("\nserde = { version = \"1\", features = [\"derive\"] }\n"));
     }
 }
@@ -0,0 +1,347 @@
+use crate::code::{SourceMap, SpanKind};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+/// A compiler diagnostic, parsed from `rustc`'s JSON output.
+///
+/// Diagnostic spans refer to the generated `lib.rs`. Use [`locate`](Diagnostic::locate) to map
+/// the spans back to the REPL input, after which the diagnostic renders pointing to what was
+/// typed.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Diagnostic {
+    /// The primary message.
+    pub message: String,
+    /// The diagnostic code, such as `E0425`.
+    #[serde(deserialize_with = "de_code")]
+    pub code: Option<String>,
+    /// The diagnostic level, such as `error`, `warning`, `note`, or `help`.
+    pub level: String,
+    /// The locations in the source code.
+    pub spans: Vec<DiagnosticSpan>,
+    /// Associated diagnostic messages.
+    pub children: Vec<Diagnostic>,
+    /// The diagnostic as rendered by `rustc`.
+    pub rendered: Option<String>,
+}
+
+/// A location in the source code of a diagnostic.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DiagnosticSpan {
+    /// The file name.
+    pub file_name: String,
+    /// The byte offset where the span starts (inclusive).
+    pub byte_start: usize,
+    /// The byte offset where the span ends (exclusive).
+    pub byte_end: usize,
+    /// The first line number (1-based).
+    pub line_start: usize,
+    /// The first character offset of `line_start` (1-based).
+    pub column_start: usize,
+    /// This is the primary span.
+    pub is_primary: bool,
+    /// A label for the span.
+    pub label: Option<String>,
+    /// A suggested replacement for the span.
+    pub suggested_replacement: Option<String>,
+    /// The REPL input the span originates from, populated by [`Diagnostic::locate`].
+    #[serde(skip)]
+    pub origin: Option<Origin>,
+}
+
+/// The REPL input a diagnostic span originates from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    /// The module path.
+    pub path: PathBuf,
+    /// The statement or item.
+    pub kind: SpanKind,
+    /// The statement or item text.
+    pub text: String,
+    /// The byte range _within_ `text`.
+    pub range: Range<usize>,
+}
+
+/// `rustc` writes the code as an object, a serialized `Diagnostic` writes it as a string.
+fn de_code<'de, D: serde::Deserializer<'de>>(de: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Code {
+        Code(String),
+        Rustc { code: String },
+    }
+
+    Option::<Code>::deserialize(de).map(|x| {
+        x.map(|x| match x {
+            Code::Code(x) | Code::Rustc { code: x } => x,
+        })
+    })
+}
+
+impl Diagnostic {
+    /// Populate the origin of each span (and of the children's spans) in the generated `lib.rs`
+    /// using `source_map`.
+    pub fn locate(&mut self, source_map: &SourceMap) {
+        for span in self
+            .spans
+            .iter_mut()
+            .filter(|x| Path::new(&x.file_name) == Path::new("src/lib.rs"))
+        {
+            span.origin = source_map.locate(span.byte_start).map(|x| {
+                let start = span.byte_start - x.range.start;
+                let end = std::cmp::min(span.byte_end, x.range.end) - x.range.start;
+                Origin {
+                    path: x.path.to_path_buf(),
+                    kind: x.kind,
+                    text: x.text.to_string(),
+                    range: start..std::cmp::max(start, end),
+                }
+            });
+        }
+
+        for child in &mut self.children {
+            child.locate(source_map);
+        }
+    }
+
+    /// The primary span, if there is one.
+    pub fn primary_span(&self) -> Option<&DiagnosticSpan> {
+        self.spans.iter().find(|x| x.is_primary)
+    }
+
+    /// Render the diagnostic.
+    ///
+    /// If the primary span has a known origin the diagnostic points to the REPL input, otherwise
+    /// the `rustc` rendering is used.
+    pub fn render(&self) -> String {
+        let (span, origin) = match self
+            .primary_span()
+            .and_then(|s| s.origin.as_ref().map(|o| (s, o)))
+        {
+            Some(x) => x,
+            None => {
+                return self
+                    .rendered
+                    .clone()
+                    .unwrap_or_else(|| format!("{}: {}\n", self.level, self.message))
+            }
+        };
+
+        let mut buf = self.level.clone();
+        if let Some(code) = &self.code {
+            buf.push('[');
+            buf.push_str(code);
+            buf.push(']');
+        }
+        buf.push_str(": ");
+        buf.push_str(&self.message);
+        buf.push('\n');
+
+        let text = &origin.text;
+        let line_start = text[..origin.range.start]
+            .rfind('\n')
+            .map(|x| x + 1)
+            .unwrap_or(0);
+        let line_end = text[origin.range.start..]
+            .find('\n')
+            .map(|x| x + origin.range.start)
+            .unwrap_or_else(|| text.len());
+        let line_no = text[..line_start].matches('\n').count() + 1;
+        let gutter = " ".repeat(line_no.to_string().len());
+
+        let col = text[line_start..origin.range.start].chars().count();
+        let carets = text[origin.range.start..std::cmp::min(origin.range.end, line_end)]
+            .chars()
+            .count();
+
+        buf.push_str(&format!(
+            "{} --> [{}] {}\n",
+            gutter,
+            origin.path.display(),
+            origin.kind
+        ));
+        buf.push_str(&format!("{} |\n", gutter));
+        buf.push_str(&format!("{} | {}\n", line_no, &text[line_start..line_end]));
+        buf.push_str(&format!(
+            "{} | {}{}",
+            gutter,
+            " ".repeat(col),
+            "^".repeat(std::cmp::max(carets, 1))
+        ));
+        if let Some(label) = &span.label {
+            buf.push(' ');
+            buf.push_str(label);
+        }
+        buf.push('\n');
+
+        for child in &self.children {
+            buf.push_str(&format!("{} = {}: {}", gutter, child.level, child.message));
+            let suggestion = child
+                .spans
+                .iter()
+                .find_map(|x| x.suggested_replacement.as_ref());
+            if let Some(suggestion) = suggestion {
+                buf.push_str(&format!(": `{}`", suggestion));
+            }
+            buf.push('\n');
+        }
+
+        buf
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render())
+    }
+}
+
+/// A compiler message in `cargo --message-format=json` output.
+#[derive(Debug, PartialEq)]
+pub(super) enum CargoMessage {
+    /// A diagnostic for the target crate.
+    Target(Diagnostic),
+    /// The rendered text of an error in another crate, such as a dependency failing to build.
+    Other(String),
+}
+
+/// Parses a line of `cargo --message-format=json` output, returning the diagnostic if it is a
+/// compiler message for the `target` crate, or the rendered text of errors for other crates.
+pub(super) fn parse_cargo_message(line: &str, target: &str) -> Option<CargoMessage> {
+    #[derive(Deserialize)]
+    struct Message {
+        reason: String,
+        target: Option<Target>,
+        message: Option<Diagnostic>,
+    }
+
+    #[derive(Deserialize)]
+    struct Target {
+        name: String,
+    }
+
+    let msg: Message = serde_json::from_str(line).ok()?;
+
+    if msg.reason != "compiler-message" {
+        return None;
+    }
+
+    let diagnostic = msg.message.filter(|x| {
+        // the summary messages are not useful
+        x.level != "failure-note" && !x.message.starts_with("aborting due to")
+    })?;
+
+    if msg.target.map(|x| x.name).as_deref() == Some(target) {
+        Some(CargoMessage::Target(diagnostic))
+    } else if diagnostic.level == "error" {
+        let rendered = match diagnostic.rendered {
+            Some(ref x) => x.clone(),
+            None => diagnostic.render(),
+        };
+        Some(CargoMessage::Other(rendered))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::*;
+    use crate::linking::LinkingConfiguration;
+
+    const MSG: &str = r#"{"reason":"compiler-message","package_id":"papyrus_mem_code 0.1.0","target":{"kind":["cdylib"],"crate_types":["cdylib"],"name":"papyrus_mem_code","src_path":"/tmp/src/lib.rs","edition":"2018"},"message":{"rendered":"error[E0425]: cannot find value `b` in this scope\n","children":[{"children":[],"code":null,"level":"help","message":"a local variable with a similar name exists","rendered":null,"spans":[{"byte_end":1,"byte_start":0,"column_end":1,"column_start":1,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":null,"line_end":1,"line_start":1,"suggested_replacement":"a","suggestion_applicability":"MaybeIncorrect","text":[]}]}],"code":{"code":"E0425","explanation":"..."},"level":"error","message":"cannot find value `b` in this scope","spans":[{"byte_end":BYTE_END,"byte_start":BYTE_START,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":"not found in this scope","line_end":3,"line_start":3,"suggested_replacement":null,"suggestion_applicability":null,"text":[]}]}}"#;
+
+    fn target_diagnostic(line: &str) -> Diagnostic {
+        match parse_cargo_message(line, "papyrus_mem_code") {
+            Some(CargoMessage::Target(d)) => d,
+            x => panic!("expecting Target, found {:?}", x),
+        }
+    }
+
+    #[test]
+    fn parse_cargo_message_test() {
+        let line = MSG.replace("BYTE_START", "10").replace("BYTE_END", "11");
+        let d = target_diagnostic(&line);
+        assert_eq!(d.code.as_deref(), Some("E0425"));
+        assert_eq!(d.level, "error");
+        assert_eq!(d.spans[0].byte_start, 10);
+        assert_eq!(d.children[0].level, "help");
+
+        // errors in other crates keep their rendered text
+        assert_eq!(
+            parse_cargo_message(&line, "other"),
+            Some(CargoMessage::Other(
+                "error[E0425]: cannot find value `b` in this scope\n".to_string()
+            ))
+        );
+        let warning = line.replace(
+            r#""level":"error","message""#,
+            r#""level":"warning","message""#,
+        );
+        assert_eq!(parse_cargo_message(&warning, "other"), None);
+        assert_eq!(
+            parse_cargo_message(r#"{"reason":"build-finished","success":false}"#, "a"),
+            None
+        );
+        assert_eq!(parse_cargo_message("not json", "a"), None);
+    }
+
+    #[test]
+    fn locate_and_render_test() {
+        let mut mods_map = ModsMap::new();
+        mods_map.insert(
+            PathBuf::from("lib"),
+            SourceCode {
+                items: vec![],
+                stmts: vec![StmtGrp(vec![
+                    Statement {
+                        expr: "let a = 1".to_string(),
+                        semi: true,
+                    },
+                    Statement {
+                        expr: "a +\nb".to_string(),
+                        semi: false,
+                    },
+                ])],
+                crates: vec![],
+            },
+        );
+        let linking = LinkingConfiguration::default();
+        let (code, _) = construct_source_code(&mods_map, &linking, &StaticFiles::new());
+        let source_map = construct_source_map(&mods_map, &linking, &StaticFiles::new());
+
+        let start = code.find("a +\nb").unwrap() + 4;
+        let line = MSG
+            .replace("BYTE_START", &start.to_string())
+            .replace("BYTE_END", &(start + 1).to_string());
+        let mut d = target_diagnostic(&line);
+        d.locate(&source_map);
+
+        let origin = d.spans[0].origin.as_ref().unwrap();
+        assert_eq!(origin.path, Path::new("lib"));
+        assert_eq!(origin.kind, SpanKind::Stmt { grp: 0, stmt: 1 });
+        assert_eq!(origin.range, 4..5);
+
+        assert_eq!(
+            d.render(),
+            "error[E0425]: cannot find value `b` in this scope
+  --> [lib] out0
+  |
+2 | b
+  | ^ not found in this scope
+  = help: a local variable with a similar name exists: `a`
+"
+        );
+
+        // unknown origin uses the rustc rendering
+        d.spans[0].origin = None;
+        assert_eq!(
+            d.render(),
+            "error[E0425]: cannot find value `b` in this scope\n"
+        );
+    }
+}
@@ -1,38 +1,814 @@
+use super::cancel::{CancelToken, Stopped, Watchdog};
+use crate::code::{PANIC_ID, RESTORE_FN};
 use ::kserd::Kserd;
 use libloading::{Library, Symbol};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{error, fmt, io};
 
 /// We don't type anything here. You must be **VERY** careful to pass through the correct borrow to match the
 /// function signature!
 type DataFunc<D> = unsafe fn(D) -> Kserd<'static>;
 
-type ExecResult = Result<(Kserd<'static>, Library), &'static str>;
+/// Clones the bindings restored by a persisting evaluation function.
+type RestoreFunc = unsafe extern "C" fn(*const Kserd<'static>);
 
-pub(crate) fn exec<P: AsRef<Path>, D>(
+/// The library is only returned when it is loaded into this process.
+type ExecResult = Result<(Kserd<'static>, Option<Library>), ExecError>;
+
+/// The first argument of a process started as a worker, see [`run_worker`].
+const WORKER_ARG: &str = "--worker";
+
+/// Options for executing the compiled library.
+///
+/// # Output
+/// Output printed by a worker process (see below) to stdout and stderr is captured line by line as
+/// it is printed, and written to the REPL's [`Output`](crate::output::Output) rather than directly
+/// to the terminal, such that [`output_listen`](crate::repl::Repl::output_listen) receives
+/// everything. This can be turned off with [`capture_output`].
+///
+/// Code evaluated in-process prints directly to stdout and stderr, unless [`capture_in_process`]
+/// is set. Capturing in-process redirects the whole process's stdout and stderr for the duration
+/// of the evaluation (which also captures any other threads printing), and the lines are written
+/// once the evaluation returns. Capturing is only supported on unix.
+///
+/// # Out-of-process evaluation
+/// By default the library is loaded into the REPL's process, and a segmentation fault, `abort`,
+/// stack overflow, or `process::exit` in the evaluated code takes down the REPL (and any
+/// application embedding it). Setting [`out_of_process`] evaluates in a worker process, which is
+/// the [`worker`] executable (the current executable by default) started in the worker mode of
+/// [`run_worker`]. The worker's output is streamed back to the REPL. A worker which crashes is
+/// reported as an [`ExecError`], and the REPL survives.
+///
+/// The worker is a fresh process, so app data cannot be passed to it. Code linked with app data is
+/// always evaluated in-process.
+///
+/// # Cancellation and timeouts
+/// A worker can be killed, either by cancelling the evaluation (see
+/// [`Evaluating::cancel`](crate::repl::Evaluating::cancel)) or after the [`timeout`]. Code
+/// evaluated in-process cannot be interrupted once running, so the timeout only applies to
+/// out-of-process evaluation.
+///
+/// # Resource limits
+/// The worker's memory and CPU time can be capped with [`memory_limit`] and [`cpu_limit`], which
+/// are applied to the worker process as resource limits (`RLIMIT_AS` and `RLIMIT_CPU`). The
+/// memory limit is on the worker's total address space, which includes the worker executable and
+/// the loaded library, so it should be set well above the executable's own usage. A worker
+/// exceeding a limit is reported as [`ExecError::MemoryLimit`] or [`ExecError::CpuLimit`], which
+/// are detected from the signal terminating the worker (so an `abort` with a memory limit set is
+/// also reported as exceeding it). As with the timeout, limits only apply to out-of-process
+/// evaluation.
+///
+/// Out-of-process evaluation is only supported on unix.
+///
+/// [`out_of_process`]: ExecOptions::out_of_process
+/// [`worker`]: ExecOptions::worker
+/// [`run_worker`]: run_worker
+/// [`capture_output`]: ExecOptions::capture_output
+/// [`capture_in_process`]: ExecOptions::capture_in_process
+/// [`timeout`]: ExecOptions::timeout
+/// [`memory_limit`]: ExecOptions::memory_limit
+/// [`cpu_limit`]: ExecOptions::cpu_limit
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecOptions {
+    /// Evaluate in a worker process.
+    pub out_of_process: bool,
+    /// The executable run as the worker process, which must call [`run_worker`](run_worker).
+    /// Defaults to the current executable.
+    pub worker: Option<PathBuf>,
+    /// Kill the worker process if evaluation takes longer than the timeout.
+    pub timeout: Option<Duration>,
+    /// Capture the output of the evaluated code. Defaults to `true`.
+    pub capture_output: bool,
+    /// Capture the output of code evaluated in-process, by redirecting this process's stdout and
+    /// stderr. Requires `capture_output`. Defaults to `false`.
+    pub capture_in_process: bool,
+    /// The maximum size, in bytes, of the worker process's address space.
+    pub memory_limit: Option<u64>,
+    /// The maximum CPU time of the worker process, rounded up to whole seconds.
+    pub cpu_limit: Option<Duration>,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        Self {
+            out_of_process: false,
+            worker: None,
+            timeout: None,
+            capture_output: true,
+            capture_in_process: false,
+            memory_limit: None,
+            cpu_limit: None,
+        }
+    }
+}
+
+/// Error executing the compiled library.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExecError {
+    /// The library file could not be loaded.
+    LoadLibrary,
+    /// The function was not found in the library.
+    MissingFunction,
+    /// The evaluated code panicked.
+    Panic(EvalPanic),
+    /// The worker process was terminated by a signal, such as a segmentation fault or `abort`.
+    Crashed(i32),
+    /// The worker process exited before returning a result, such as through `process::exit`.
+    Exited(i32),
+    /// The evaluation was cancelled.
+    Cancelled,
+    /// The evaluation was killed after exceeding the timeout.
+    TimedOut(Duration),
+    /// The worker process aborted, or was killed, with a memory limit set. This is how failing
+    /// to allocate memory within the limit ends.
+    MemoryLimit(u64),
+    /// The worker process was killed after exceeding the CPU time limit.
+    CpuLimit(Duration),
+    /// Running the worker process failed.
+    #[serde(skip)]
+    Worker(io::Error),
+    /// Redirecting the output failed.
+    #[serde(skip)]
+    Redirect(io::Error),
+}
+
+impl error::Error for ExecError {}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::LoadLibrary => write!(f, "failed to load library file"),
+            ExecError::MissingFunction => write!(f, "failed to find function in library"),
+            ExecError::Panic(panic) => write!(f, "{}", panic),
+            ExecError::Crashed(signal) => {
+                write!(f, "evaluation crashed, terminated by signal {}", signal)
+            }
+            ExecError::Exited(code) => write!(f, "evaluation exited with code {}", code),
+            ExecError::Cancelled => write!(f, "evaluation cancelled"),
+            ExecError::TimedOut(t) => {
+                write!(f, "evaluation timed out after {}s", t.as_secs_f64())
+            }
+            ExecError::MemoryLimit(bytes) => {
+                write!(f, "evaluation exceeded the memory limit of {} bytes", bytes)
+            }
+            ExecError::CpuLimit(t) => write!(
+                f,
+                "evaluation exceeded the CPU time limit of {}s",
+                t.as_secs_f64()
+            ),
+            ExecError::Worker(e) => write!(f, "failed to run evaluation worker: {}", e),
+            ExecError::Redirect(e) => write!(f, "failed to redirect output: {}", e),
+        }
+    }
+}
+
+/// A panic in the evaluated code.
+///
+/// The backtrace is captured with the [`backtrace`](https://docs.rs/backtrace) crate, and only if
+/// enabled through the `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE` environment variables (a value of
+/// `0` disables it).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EvalPanic {
+    /// The panic message.
+    pub message: String,
+    /// The file, line, and column of the panic.
+    pub location: Option<String>,
+    /// The backtrace at the panic.
+    pub backtrace: Option<String>,
+}
+
+impl EvalPanic {
+    /// Reads the panic from the value returned by the evaluation function, the value is returned
+    /// if it is not a panic.
+    fn from_kserd(kserd: Kserd<'static>) -> Result<Kserd<'static>, Self> {
+        if kserd.id() != Some(PANIC_ID) {
+            return Ok(kserd);
+        }
+
+        let field = |i: usize| match &kserd.val {
+            ::kserd::Value::Tuple(v) => v
+                .get(i)
+                .and_then(|x| x.str())
+                .filter(|x| !x.is_empty())
+                .map(String::from),
+            _ => None,
+        };
+
+        Err(Self {
+            message: field(0).unwrap_or_default(),
+            location: field(1),
+            backtrace: field(2),
+        })
+    }
+
+    /// The panic from a `catch_unwind` payload.
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(s) => s.clone(),
+                None => "Box<dyn Any>".to_string(),
+            },
+        };
+
+        Self {
+            message,
+            location: None,
+            backtrace: None,
+        }
+    }
+}
+
+impl fmt::Display for EvalPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "evaluation panicked at {}:\n{}", location, self.message)?,
+            None => write!(f, "evaluation panicked:\n{}", self.message)?,
+        }
+        if let Some(backtrace) = &self.backtrace {
+            write!(f, "\nstack backtrace:\n{}", backtrace.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Executes the function in the library. If `vars` is given, it is passed to the library first
+/// to restore the bindings of a persisting evaluation function (see
+/// [`construct_source_code_persisting`](crate::code::construct_source_code_persisting)).
+pub(crate) fn exec<P: AsRef<Path>, D, F: FnMut(&str)>(
     library_file: P,
     function_name: &str,
     app_data: D,
+    vars: Option<&Kserd<'static>>,
+    options: &ExecOptions,
+    cancel: &CancelToken,
+    on_line: F,
 ) -> ExecResult {
-    exec_no_redirect(library_file, function_name, app_data)
+    if options.out_of_process {
+        exec_worker(library_file, function_name, vars, options, cancel, on_line)
+    } else if options.capture_output && options.capture_in_process {
+        exec_and_redirect(library_file, function_name, app_data, vars, on_line)
+    } else {
+        exec_no_redirect(library_file, function_name, app_data, vars)
+    }
 }
 
 fn exec_no_redirect<P: AsRef<Path>, Data>(
     library_file: P,
     function_name: &str,
     app_data: Data,
+    vars: Option<&Kserd<'static>>,
 ) -> ExecResult {
     let lib = get_lib(library_file)?;
     let func = get_func(&lib, function_name)?;
 
+    if let Some(vars) = vars {
+        let restore = get_restore(&lib)?;
+        unsafe { restore(vars) };
+    }
+
     let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe { func(app_data) }));
 
     match res {
-        Ok(kserd) => Ok((kserd, lib)),
-        Err(_) => Err("a panic occured with evaluation"),
+        Ok(kserd) => EvalPanic::from_kserd(kserd)
+            .map(|kserd| (kserd, Some(lib)))
+            .map_err(ExecError::Panic),
+        Err(payload) => Err(ExecError::Panic(EvalPanic::from_payload(payload))),
+    }
+}
+
+/// Redirects this process's stdout and stderr while executing, the lines are passed to `on_line`
+/// once execution finishes.
+#[cfg(unix)]
+fn exec_and_redirect<P: AsRef<Path>, Data, F: FnMut(&str)>(
+    library_file: P,
+    function_name: &str,
+    app_data: Data,
+    vars: Option<&Kserd<'static>>,
+    mut on_line: F,
+) -> ExecResult {
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+
+    let dup = |fd| cvt(unsafe { libc::dup(fd) }).map(|x| unsafe { File::from_raw_fd(x) });
+    // restoring is attempted for both, stderr may be redirected even if stdout failed
+    let restore = |saved: &[File; 2]| {
+        let out = cvt(unsafe { libc::dup2(saved[0].as_raw_fd(), 1) });
+        let err = cvt(unsafe { libc::dup2(saved[1].as_raw_fd(), 2) });
+        out.and(err)
+    };
+
+    let (rx, tx) = pipe().map_err(ExecError::Redirect)?;
+    let (rx, tx) = unsafe { (File::from_raw_fd(rx), File::from_raw_fd(tx)) };
+
+    io::stdout().flush().ok();
+    io::stderr().flush().ok();
+    let saved = [
+        dup(1).map_err(ExecError::Redirect)?,
+        dup(2).map_err(ExecError::Redirect)?,
+    ];
+    let redirected = cvt(unsafe { libc::dup2(tx.as_raw_fd(), 1) })
+        .and_then(|_| cvt(unsafe { libc::dup2(tx.as_raw_fd(), 2) }));
+    drop(tx);
+    if let Err(e) = redirected {
+        restore(&saved).ok();
+        return Err(ExecError::Redirect(e));
+    }
+
+    let (sender, lines) = crossbeam_channel::unbounded();
+    let jh = read_lines(rx.into_raw_fd(), sender);
+
+    let res = exec_no_redirect(library_file, function_name, app_data, vars);
+
+    io::stdout().flush().ok();
+    io::stderr().flush().ok();
+    let restored = restore(&saved);
+    drop(saved);
+
+    // the write ends are closed, so the reader finishes
+    for msg in lines {
+        if let Msg::Line(line) = msg {
+            on_line(&line);
+        }
+    }
+    jh.join().ok();
+
+    restored.map_err(ExecError::Redirect)?;
+    res
+}
+
+#[cfg(not(unix))]
+fn exec_and_redirect<P: AsRef<Path>, Data, F: FnMut(&str)>(
+    library_file: P,
+    function_name: &str,
+    app_data: Data,
+    vars: Option<&Kserd<'static>>,
+    _: F,
+) -> ExecResult {
+    exec_no_redirect(library_file, function_name, app_data, vars)
+}
+
+/// Starts a worker process which executes the library, the result is written back through a pipe
+/// as a [`Wire`]. If capturing output, the worker's stdout and stderr are streamed line by line to
+/// `on_line`, otherwise the worker inherits them.
+#[cfg(unix)]
+fn exec_worker<P: AsRef<Path>, F: FnMut(&str)>(
+    library_file: P,
+    function_name: &str,
+    vars: Option<&Kserd<'static>>,
+    options: &ExecOptions,
+    cancel: &CancelToken,
+    mut on_line: F,
+) -> ExecResult {
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    let worker = match &options.worker {
+        Some(x) => x.clone(),
+        None => std::env::current_exe().map_err(ExecError::Worker)?,
+    };
+
+    let (rx, tx) = pipe().map_err(ExecError::Worker)?;
+    let rx = unsafe { File::from_raw_fd(rx) };
+    let tx = unsafe { File::from_raw_fd(tx) };
+    let tx_fd = tx.as_raw_fd();
+
+    let mut cmd = Command::new(worker);
+    cmd.arg(WORKER_ARG)
+        .arg(library_file.as_ref())
+        .arg(function_name)
+        .arg(tx_fd.to_string())
+        .stdin(Stdio::piped());
+
+    // stdout and stderr share a pipe to keep the order of the lines
+    let out_rx = if options.capture_output {
+        let (out_rx, out_tx) = pipe().map_err(ExecError::Worker)?;
+        let out_rx = unsafe { File::from_raw_fd(out_rx) };
+        let out_tx = unsafe { File::from_raw_fd(out_tx) };
+        cmd.stdout(out_tx.try_clone().map_err(ExecError::Worker)?)
+            .stderr(out_tx);
+        Some(out_rx)
+    } else {
+        None
+    };
+
+    // only async-signal-safe calls are made between fork and exec
+    unsafe {
+        cmd.pre_exec(move || cvt(libc::fcntl(tx_fd, libc::F_SETFD, 0)).map(|_| ()));
+    }
+
+    let mut child = cmd.spawn().map_err(ExecError::Worker)?;
+    // the write ends held by this process are closed, so the readers finish with the worker
+    drop(cmd);
+    drop(tx);
+    let pid = child.id() as libc::pid_t;
+
+    let (sender, msgs) = crossbeam_channel::unbounded();
+    let mut readers = vec![read_all(rx.into_raw_fd(), sender.clone())];
+    if let Some(out_rx) = out_rx {
+        readers.push(read_lines(out_rx.into_raw_fd(), sender));
+    }
+
+    let watchdog = Watchdog::start(cancel, options.timeout, move || unsafe {
+        // fails if the worker has already exited, which is fine
+        libc::kill(pid, libc::SIGKILL);
+    });
+
+    // the worker reads its input before evaluating, a worker which has exited is reported below
+    let input = WorkerInput {
+        vars: vars.cloned().map(Wire::from),
+        memory_limit: options.memory_limit,
+        cpu_limit: options.cpu_limit,
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        serde_json::to_writer(&mut stdin, &input).ok();
+        stdin.flush().ok();
+    }
+
+    // the channel disconnects once the worker closes its pipes
+    let mut buf = Ok(Vec::new());
+    for msg in msgs {
+        match msg {
+            Msg::Line(line) => on_line(&line),
+            Msg::Result(x) => buf = x,
+        }
+    }
+    for jh in readers {
+        jh.join().ok();
+    }
+    let stopped = watchdog.finish();
+
+    let mut status = 0;
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    cvt(unsafe { libc::wait4(pid, &mut status, 0, &mut usage) }).map_err(ExecError::Worker)?;
+    match stopped {
+        Some(Stopped::Cancelled) => return Err(ExecError::Cancelled),
+        Some(Stopped::TimedOut) => {
+            return Err(ExecError::TimedOut(options.timeout.unwrap_or_default()))
+        }
+        None => (),
+    }
+    let buf = buf.map_err(ExecError::Worker)?;
+
+    let (signaled, signal, code) = unsafe {
+        (
+            libc::WIFSIGNALED(status),
+            libc::WTERMSIG(status),
+            libc::WEXITSTATUS(status),
+        )
+    };
+    if signaled {
+        let cpu_time = timeval(usage.ru_utime) + timeval(usage.ru_stime);
+        return Err(match (options.memory_limit, options.cpu_limit) {
+            // the hard limit kills the worker if it ignores SIGXCPU
+            (_, Some(limit))
+                if signal == libc::SIGXCPU
+                    || (signal == libc::SIGKILL && cpu_time >= rlimit_secs(limit)) =>
+            {
+                ExecError::CpuLimit(limit)
+            }
+            // failing to allocate aborts, or the kernel kills the worker
+            (Some(limit), _) if signal == libc::SIGABRT || signal == libc::SIGKILL => {
+                ExecError::MemoryLimit(limit)
+            }
+            _ => ExecError::Crashed(signal),
+        });
+    }
+
+    match serde_json::from_slice::<Result<Wire, ExecError>>(&buf) {
+        Ok(Ok(wire)) => wire.into_kserd().map(|x| (x, None)).ok_or_else(|| {
+            ExecError::Worker(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "failed to read evaluation result",
+            ))
+        }),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(ExecError::Exited(code)),
+    }
+}
+
+#[cfg(not(unix))]
+fn exec_worker<P: AsRef<Path>, F: FnMut(&str)>(
+    _: P,
+    _: &str,
+    _: Option<&Kserd<'static>>,
+    _: &ExecOptions,
+    _: &CancelToken,
+    _: F,
+) -> ExecResult {
+    Err(ExecError::Worker(io::Error::new(
+        io::ErrorKind::Other,
+        "out-of-process evaluation is only supported on unix",
+    )))
+}
+
+/// Runs this process as an evaluation worker, if it was started as one.
+///
+/// Out-of-process evaluation (see [`ExecOptions`](ExecOptions)) runs the
+/// [`worker`](ExecOptions::worker) executable with hidden `--worker` arguments. An application
+/// which evaluates out-of-process calls this at the start of `main`. If the process is a worker,
+/// the library function is evaluated and the process exits, otherwise this returns immediately.
+///
+/// ```rust,no_run
+/// papyrus::compile::run_worker();
+/// // ... run the REPL
+/// ```
+pub fn run_worker() {
+    let mut args = std::env::args_os().skip(1);
+    if args.next().as_deref() != Some(std::ffi::OsStr::new(WORKER_ARG)) {
+        return;
+    }
+
+    #[cfg(unix)]
+    let res = worker(args);
+    #[cfg(not(unix))]
+    let res: io::Result<()> = Err(io::Error::new(
+        io::ErrorKind::Other,
+        "out-of-process evaluation is only supported on unix",
+    ));
+
+    match res {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("papyrus worker failed: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Evaluates the library function named in the worker arguments, with the [`WorkerInput`] read
+/// from stdin, writing the result to the file descriptor in the arguments.
+#[cfg(unix)]
+fn worker<I: Iterator<Item = std::ffi::OsString>>(mut args: I) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    let library_file = PathBuf::from(args.next().ok_or_else(|| invalid("missing library"))?);
+    let function_name = args
+        .next()
+        .and_then(|x| x.into_string().ok())
+        .ok_or_else(|| invalid("missing function name"))?;
+    let fd = args
+        .next()
+        .and_then(|x| x.to_str().and_then(|x| x.parse::<libc::c_int>().ok()))
+        .ok_or_else(|| invalid("missing result file descriptor"))?;
+    let mut result = unsafe { std::fs::File::from_raw_fd(fd) };
+
+    let input: WorkerInput = serde_json::from_reader(io::stdin())?;
+    let vars = match input.vars {
+        Some(x) => Some(x.into_kserd().ok_or_else(|| invalid("invalid bindings"))?),
+        None => None,
+    };
+
+    let res = set_limits(input.memory_limit, input.cpu_limit)
+        .map_err(ExecError::Worker)
+        .and_then(|_| exec_no_redirect(&library_file, &function_name, (), vars.as_ref()))
+        .map(|(kserd, _lib)| Wire::from(kserd));
+
+    serde_json::to_writer(&mut result, &res)?;
+    result.flush()?;
+    io::stdout().flush()?;
+    io::stderr().flush()
+}
+
+/// What a worker reads from stdin before evaluating.
+#[cfg(unix)]
+#[derive(Serialize, Deserialize)]
+struct WorkerInput {
+    /// The bindings to restore.
+    vars: Option<Wire>,
+    memory_limit: Option<u64>,
+    cpu_limit: Option<Duration>,
+}
+
+/// Applies the resource limits to this (worker) process.
+#[cfg(unix)]
+fn set_limits(memory_limit: Option<u64>, cpu_limit: Option<Duration>) -> io::Result<()> {
+    let set = |resource, soft: u64, hard: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: soft as libc::rlim_t,
+            rlim_max: hard as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+
+    if let Some(bytes) = memory_limit {
+        set(libc::RLIMIT_AS, bytes, bytes)?;
     }
+    if let Some(t) = cpu_limit {
+        // SIGXCPU is sent at the soft limit, SIGKILL at the hard limit
+        let secs = rlimit_secs(t).as_secs();
+        set(libc::RLIMIT_CPU, secs, secs + 1)?;
+    }
+
+    Ok(())
+}
+
+/// CPU time limits are whole seconds, at least one.
+#[cfg(unix)]
+fn rlimit_secs(t: Duration) -> Duration {
+    let secs = t.as_secs() + if t.subsec_nanos() > 0 { 1 } else { 0 };
+    Duration::from_secs(secs.max(1))
+}
+
+#[cfg(unix)]
+fn timeval(t: libc::timeval) -> Duration {
+    Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
 }
 
-fn get_lib<P: AsRef<Path>>(path: P) -> Result<Library, &'static str> {
+/// A message from a pipe reading thread.
+#[cfg(unix)]
+enum Msg {
+    Line(String),
+    Result(io::Result<Vec<u8>>),
+}
+
+/// A `Kserd` as sent from the worker.
+///
+/// The `kserd` text format does not keep the kind of a number (integers are parsed back as
+/// floats), which would fail decoding persisted bindings.
+#[cfg(unix)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Wire {
+    id: Option<String>,
+    val: WireVal,
+}
+
+#[cfg(unix)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum WireVal {
+    Unit,
+    Bool(bool),
+    Uint(u128),
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Barr(Vec<u8>),
+    Tuple(Vec<Wire>),
+    Cntr(Vec<(String, Wire)>),
+    Seq(Vec<Wire>),
+    Map(Vec<(Wire, Wire)>),
+}
+
+#[cfg(unix)]
+impl From<Kserd<'_>> for Wire {
+    fn from(kserd: Kserd) -> Self {
+        use ::kserd::{Number, Value};
+        let list = |x: Vec<Kserd>| x.into_iter().map(Wire::from).collect();
+        let val = match kserd.val {
+            Value::Unit => WireVal::Unit,
+            Value::Bool(x) => WireVal::Bool(x),
+            Value::Num(Number::Uint(x)) => WireVal::Uint(x),
+            Value::Num(Number::Int(x)) => WireVal::Int(x),
+            Value::Num(Number::Float(x)) => WireVal::Float(x),
+            Value::Str(x) => WireVal::Str(x.as_str().to_string()),
+            Value::Barr(x) => WireVal::Barr(x.as_bytes().to_vec()),
+            Value::Tuple(x) => WireVal::Tuple(list(x)),
+            Value::Cntr(x) => WireVal::Cntr(
+                x.into_iter()
+                    .map(|(k, v)| (k.as_str().to_string(), Wire::from(v)))
+                    .collect(),
+            ),
+            Value::Seq(x) => WireVal::Seq(list(x)),
+            Value::Map(x) => WireVal::Map(
+                x.into_iter()
+                    .map(|(k, v)| (Wire::from(k), Wire::from(v)))
+                    .collect(),
+            ),
+        };
+        Wire {
+            id: kserd.id.map(|x| x.as_str().to_string()),
+            val,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Wire {
+    /// Returns `None` if an identity or field name is invalid.
+    fn into_kserd(self) -> Option<Kserd<'static>> {
+        use ::kserd::{Barr, Kstr, Number, Value};
+        fn list(x: Vec<Wire>) -> Option<Vec<Kserd<'static>>> {
+            x.into_iter().map(Wire::into_kserd).collect()
+        }
+        let val = match self.val {
+            WireVal::Unit => Value::Unit,
+            WireVal::Bool(x) => Value::Bool(x),
+            WireVal::Uint(x) => Value::Num(Number::Uint(x)),
+            WireVal::Int(x) => Value::Num(Number::Int(x)),
+            WireVal::Float(x) => Value::Num(Number::Float(x)),
+            WireVal::Str(x) => Value::Str(Kstr::owned(x)),
+            WireVal::Barr(x) => Value::Barr(Barr::owned(x)),
+            WireVal::Tuple(x) => Value::Tuple(list(x)?),
+            WireVal::Cntr(x) => {
+                let fields = x
+                    .into_iter()
+                    .map(|(k, v)| v.into_kserd().map(|v| (k, v)))
+                    .collect::<Option<Vec<_>>>()?;
+                Value::new_cntr(fields).ok()?
+            }
+            WireVal::Seq(x) => Value::Seq(list(x)?),
+            WireVal::Map(x) => Value::Map(
+                x.into_iter()
+                    .map(|(k, v)| Some((k.into_kserd()?, v.into_kserd()?)))
+                    .collect::<Option<_>>()?,
+            ),
+        };
+        match self.id {
+            Some(id) => Kserd::with_id(id, val).ok(),
+            None => Some(Kserd::new(val)),
+        }
+    }
+}
+
+/// Returns the `(read, write)` ends of a new pipe.
+///
+/// The ends are closed on `exec`, so processes spawned concurrently do not hold them open.
+#[cfg(unix)]
+fn pipe() -> io::Result<(libc::c_int, libc::c_int)> {
+    let mut fds = [0; 2];
+    cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    for fd in &fds {
+        if let Err(e) = cvt(unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) }) {
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            return Err(e);
+        }
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// Converts a libc return value, where `-1` is an error, into a result.
+#[cfg(unix)]
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Reads the pipe to the end on another thread.
+#[cfg(unix)]
+fn read_all(
+    fd: libc::c_int,
+    sender: crossbeam_channel::Sender<Msg>,
+) -> std::thread::JoinHandle<()> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let res = file.read_to_end(&mut buf).map(|_| buf);
+        sender.send(Msg::Result(res)).ok();
+    })
+}
+
+/// Reads the pipe line by line on another thread.
+#[cfg(unix)]
+fn read_lines(
+    fd: libc::c_int,
+    sender: crossbeam_channel::Sender<Msg>,
+) -> std::thread::JoinHandle<()> {
+    use std::io::BufRead;
+    use std::os::unix::io::FromRawFd;
+
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+    std::thread::spawn(move || {
+        let mut rdr = io::BufReader::new(file);
+        let mut buf = Vec::new();
+        while let Ok(n) = rdr.read_until(b'\n', &mut buf) {
+            if n == 0 {
+                break;
+            }
+            if buf.last() == Some(&b'\n') {
+                buf.pop();
+            }
+            sender
+                .send(Msg::Line(String::from_utf8_lossy(&buf).into_owned()))
+                .ok();
+            buf.clear();
+        }
+    })
+}
+
+fn get_lib<P: AsRef<Path>>(path: P) -> Result<Library, ExecError> {
     // If segfaults are occurring maybe use this, SIGSEV?
     // This is shown in https://github.com/nagisa/rust_libloading/issues/41
     // let lib: Library =
@@ -41,16 +817,50 @@ fn get_lib<P: AsRef<Path>>(path: P) -> Result<Library, &'static str> {
     // 		.into();
     Library::new(path.as_ref()).map_err(|e| {
         error!("failed to load library file: {}", e);
-        "failed to load library file"
+        ExecError::LoadLibrary
     })
 }
 
 fn get_func<'l, Data>(
     lib: &'l Library,
     name: &str,
-) -> Result<Symbol<'l, DataFunc<Data>>, &'static str> {
+) -> Result<Symbol<'l, DataFunc<Data>>, ExecError> {
     unsafe {
         lib.get(name.as_bytes())
-            .map_err(|_| "failed to find function in library")
+            .map_err(|_| ExecError::MissingFunction)
+    }
+}
+
+fn get_restore(lib: &Library) -> Result<Symbol<'_, RestoreFunc>, ExecError> {
+    unsafe {
+        lib.get(RESTORE_FN.as_bytes())
+            .map_err(|_| ExecError::MissingFunction)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_round_trip_test() {
+        let mut kserd = ::kserd::parse::parse(
+            r#"Foo (
+                a = [1, 2]
+                b = { "x": (true, 3.5) }
+                c = ()
+                d = "hello"
+            )"#,
+        )
+        .unwrap()
+        .into_owned();
+        if let ::kserd::Value::Cntr(x) = &mut kserd.val {
+            x.insert(::kserd::Kstr::brwed("e"), Kserd::new_num(-3i32));
+            x.insert(::kserd::Kstr::brwed("f"), Kserd::new_num(u64::MAX));
+        }
+
+        let json = serde_json::to_string(&Wire::from(kserd.clone())).unwrap();
+        let wire: Wire = serde_json::from_str(&json).unwrap();
+        assert_eq!(wire.into_kserd(), Some(kserd));
     }
 }
@@ -0,0 +1,460 @@
+use super::construct::{create_file_and_dir, dedup_crates};
+use super::LIBRARY_NAME;
+use crate::{
+    code::{self, CrateType, ModsMap, SourceCode, StaticFiles},
+    linking::LinkingConfiguration,
+};
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Exports the REPL code as a standalone binary crate in `export_dir`.
+///
+/// The exported crate consists of:
+/// - a `Cargo.toml` with the referenced crates, using the crate's `#[papyrus(...)]` specification
+///   if given, otherwise the versions resolved in the compilation directory's `Cargo.lock` (or
+///   `"*"` if the crate has not been compiled yet),
+/// - a `src/main.rs` with the items at module level and a `main` function which replays each
+///   statement group and prints the `out#` values using `Debug`,
+/// - each static file, copied from the compilation directory.
+///
+/// Other modules are written as nested `mod` blocks, their statements replayed in a `pub fn main`
+/// within the module. Linked external libraries and `app_data` are _not_ exported.
+/// If `rustfmt` is available, `src/main.rs` is formatted.
+pub fn export<P, Q>(
+    export_dir: P,
+    compile_dir: Q,
+    mods_map: &ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let export_dir = export_dir.as_ref();
+    let compile_dir = compile_dir.as_ref();
+
+    let crates = mods_map
+        .iter()
+        .flat_map(|kvp| kvp.1.crates.iter())
+        .chain(static_files.iter().flat_map(|x| x.crates.iter()));
+    let crates = dedup_crates(crates);
+
+    let versions = fs::read_to_string(compile_dir.join("Cargo.lock"))
+        .map(|lock| locked_versions(&lock))
+        .unwrap_or_default();
+
+    let name = export_dir
+        .file_name()
+        .and_then(|x| x.to_str())
+        .map(package_name)
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "papyrus-export".to_string());
+
+    create_file_and_dir(export_dir.join("Cargo.toml"))?
+        .write_all(cargotoml_contents(&name, &crates, &versions).as_bytes())?;
+
+    let main_file = export_dir.join("src/main.rs");
+    create_file_and_dir(&main_file)?
+        .write_all(main_contents(mods_map, linking_config, static_files).as_bytes())?;
+
+    for sf in static_files {
+        let to = export_dir.join("src").join(&sf.path);
+        create_file_and_dir(&to)?;
+        fs::copy(compile_dir.join("src").join(&sf.path), to)?;
+    }
+
+    // formatting is a nicety, failures are ignored
+    Command::new("rustfmt")
+        .arg("--edition=2018")
+        .arg(&main_file)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .ok();
+
+    Ok(())
+}
+
+/// Lowercase, with anything other than alphanumerics replaced with a dash.
+fn package_name(dir_name: &str) -> String {
+    dir_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_string()
+}
+
+/// Parses a `Cargo.lock` for the resolved versions of the _direct_ dependencies of the REPL
+/// library. Keys are the package names.
+fn locked_versions(lock: &str) -> BTreeMap<String, String> {
+    #[derive(Default)]
+    struct Package<'a> {
+        name: &'a str,
+        version: &'a str,
+        deps: Vec<&'a str>,
+    }
+
+    fn value(line: &str) -> &str {
+        line.find('=')
+            .map(|i| &line[i + 1..])
+            .unwrap_or("")
+            .trim()
+            .trim_matches('"')
+    }
+
+    let mut pkgs = Vec::new();
+    let mut in_deps = false;
+
+    for line in lock.lines().map(str::trim) {
+        if line == "[[package]]" {
+            pkgs.push(Package::default());
+            in_deps = false;
+        } else if let Some(pkg) = pkgs.last_mut() {
+            if in_deps {
+                if line.starts_with(']') {
+                    in_deps = false;
+                } else {
+                    pkg.deps.push(line.trim_end_matches(',').trim_matches('"'));
+                }
+            } else if line.starts_with("name =") {
+                pkg.name = value(line);
+            } else if line.starts_with("version =") {
+                pkg.version = value(line);
+            } else if line.starts_with("dependencies =") {
+                in_deps = !line.ends_with(']');
+            }
+        }
+    }
+
+    let root = match pkgs.iter().find(|x| x.name == LIBRARY_NAME) {
+        Some(x) => x,
+        None => return BTreeMap::new(),
+    };
+
+    root.deps
+        .iter()
+        .filter_map(|dep| {
+            // dependencies are listed as `name` or `name version` if ambiguous
+            let mut split = dep.split(' ');
+            let name = split.next()?;
+            let version = match split.next() {
+                Some(v) => v,
+                None => pkgs.iter().find(|x| x.name == name)?.version,
+            };
+            Some((name.to_string(), version.to_string()))
+        })
+        .collect()
+}
+
+fn cargotoml_contents(
+    name: &str,
+    crates: &[&CrateType],
+    versions: &BTreeMap<String, String>,
+) -> String {
+    let normalise = |s: &str| s.replace('_', "-");
+
+    let deps = crates
+        .iter()
+        .map(|c| {
+            if !c.spec.is_default() {
+                return format!("{} = {}", c.cargo_name, c.spec.toml_value());
+            }
+            versions
+                .iter()
+                .find(|(name, _)| normalise(name) == normalise(&c.cargo_name))
+                .map(|(name, version)| format!(r#"{} = "{}""#, name, version))
+                .unwrap_or_else(|| format!(r#"{} = "*""#, c.cargo_name))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+{deps}
+"#,
+        name = name,
+        deps = deps
+    )
+}
+
+fn main_contents(
+    mods_map: &ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+) -> String {
+    let mut buf = String::new();
+
+    let sf_mods = static_files
+        .iter()
+        .map(|x| x.path.as_path())
+        .filter_map(code::static_file_mod_name)
+        .collect::<Vec<_>>();
+
+    let lib = mods_map.get(Path::new("lib"));
+
+    if let Some(lib) = lib {
+        top_items(lib, &mut buf);
+    }
+
+    for m in &sf_mods {
+        buf.push_str("mod ");
+        buf.push_str(m);
+        buf.push_str(";\n");
+    }
+
+    if let Some(lib) = lib {
+        module_body(lib, linking_config, &[], true, &mut buf);
+    }
+
+    let mut prev_lvl = 0;
+    for (path, src_code) in mods_map.iter().filter(|x| x.0 != Path::new("lib")) {
+        let lvl = path.iter().count();
+        match lvl.cmp(&prev_lvl) {
+            Ordering::Equal | Ordering::Less => {
+                for _ in 0..=(prev_lvl - lvl) {
+                    buf.push_str("}\n");
+                }
+            }
+            _ => (),
+        }
+        prev_lvl = lvl;
+
+        buf.push_str("\nmod ");
+        buf.push_str(
+            path.iter()
+                .next_back()
+                .and_then(|x| x.to_str())
+                .expect("should convert fine"),
+        );
+        buf.push_str(" {\n");
+        top_items(src_code, &mut buf);
+        module_body(src_code, linking_config, &sf_mods, false, &mut buf);
+    }
+
+    for _ in 0..prev_lvl {
+        buf.push_str("}\n");
+    }
+
+    buf
+}
+
+fn top_items(src_code: &SourceCode, buf: &mut String) {
+    for item in src_code.items.iter().filter(|x| x.1) {
+        buf.push_str(&item.0);
+        buf.push('\n');
+    }
+}
+
+fn module_body(
+    src_code: &SourceCode,
+    linking_config: &LinkingConfiguration,
+    sf_mods: &[&str],
+    root: bool,
+    buf: &mut String,
+) {
+    if !linking_config.persistent_module_code.is_empty() {
+        buf.push_str(&linking_config.persistent_module_code);
+        buf.push('\n');
+    }
+
+    for m in sf_mods {
+        buf.push_str("use crate::");
+        buf.push_str(m);
+        buf.push_str(";\n");
+    }
+
+    for item in src_code.items.iter().filter(|x| !x.1) {
+        buf.push('\n');
+        buf.push_str(&item.0);
+        buf.push('\n');
+    }
+
+    // the crate root always requires a main function
+    if root || !src_code.stmts.is_empty() {
+        buf.push('\n');
+        buf.push_str(if root {
+            "fn main() {\n"
+        } else {
+            "pub fn main() {\n"
+        });
+        for (i, grp) in src_code.stmts.iter().enumerate() {
+            if i > 0 {
+                buf.push('\n');
+            }
+            let (last, stmts) = grp.0.split_last().expect("groups are not empty");
+            for stmt in stmts {
+                buf.push_str(&stmt.expr);
+                if stmt.semi {
+                    buf.push(';');
+                }
+                buf.push('\n');
+            }
+            if last.semi {
+                buf.push_str(&last.expr);
+                buf.push_str(";\n");
+            } else {
+                let out = format!("out{}", i);
+                buf.push_str(&format!("let {} = {};\n", out, last.expr));
+                buf.push_str(&format!("println!(\"{}: {{:?}}\", {});\n", out, out));
+            }
+        }
+        buf.push_str("}\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{Statement, StmtGrp};
+    use std::path::PathBuf;
+
+    fn stmt(expr: &str, semi: bool) -> Statement {
+        Statement {
+            expr: expr.to_string(),
+            semi,
+        }
+    }
+
+    #[test]
+    fn package_name_test() {
+        assert_eq!(package_name("my_proto"), "my-proto");
+        assert_eq!(package_name("My Proto"), "my-proto");
+        assert_eq!(package_name("_x_"), "x");
+    }
+
+    #[test]
+    fn locked_versions_test() {
+        let lock = r#"# This file is automatically @generated by Cargo.
+[[package]]
+name = "kserd"
+version = "0.3.0"
+
+[[package]]
+name = "papyrus_mem_code"
+version = "0.1.0"
+dependencies = [
+ "kserd",
+ "rand 0.7.3",
+]
+
+[[package]]
+name = "rand"
+version = "0.7.3"
+
+[[package]]
+name = "rand"
+version = "0.6.5"
+"#;
+        let v = locked_versions(lock);
+        assert_eq!(v.len(), 2);
+        assert_eq!(v["kserd"], "0.3.0");
+        assert_eq!(v["rand"], "0.7.3");
+
+        assert!(locked_versions("").is_empty());
+    }
+
+    #[test]
+    fn cargotoml_contents_test() {
+        let rand = CrateType::parse_str("extern crate rand;").unwrap();
+        let serde = CrateType::parse_str("extern crate serde_json;").unwrap();
+        let kserd =
+            CrateType::parse_str(r#"#[papyrus(version = "0.3")] extern crate kserd;"#).unwrap();
+        let mut versions = BTreeMap::new();
+        versions.insert("serde_json".to_string(), "1.0.56".to_string());
+        versions.insert("kserd".to_string(), "0.3.0".to_string());
+
+        let s = cargotoml_contents("proto", &[&kserd, &rand, &serde], &versions);
+        assert_eq!(
+            s,
+            r#"[package]
+name = "proto"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+kserd = "0.3"
+rand = "*"
+serde_json = "1.0.56"
+"#
+        );
+    }
+
+    #[test]
+    fn main_contents_test() {
+        let mut mods_map = ModsMap::new();
+        mods_map.insert(
+            PathBuf::from("lib"),
+            SourceCode {
+                items: vec![
+                    ("#![allow(unused)]".to_string(), true),
+                    ("fn two() -> i32 { 2 }".to_string(), false),
+                ],
+                stmts: vec![
+                    StmtGrp(vec![stmt("let a = two()", true), stmt("a + 1", false)]),
+                    StmtGrp(vec![stmt("println!(\"{}\", a)", true)]),
+                ],
+                crates: vec![],
+            },
+        );
+        mods_map.insert(PathBuf::from("a"), SourceCode::default());
+        mods_map.insert(
+            PathBuf::from("a/b"),
+            SourceCode {
+                items: vec![],
+                stmts: vec![StmtGrp(vec![stmt("1", false)])],
+                crates: vec![],
+            },
+        );
+
+        let s = main_contents(
+            &mods_map,
+            &LinkingConfiguration::default(),
+            &StaticFiles::new(),
+        );
+        assert_eq!(
+            s,
+            r#"#![allow(unused)]
+
+fn two() -> i32 { 2 }
+
+fn main() {
+let a = two();
+let out0 = a + 1;
+println!("out0: {:?}", out0);
+
+println!("{}", a);
+}
+
+mod a {
+
+mod b {
+
+pub fn main() {
+let out0 = 1;
+println!("out0: {:?}", out0);
+}
+}
+}
+"#
+        );
+    }
+}
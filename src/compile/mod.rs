@@ -1,12 +1,25 @@
 //! Pertains to compiling a working directory into a library, then executing a function in that library.
 
 mod build;
+mod cache;
+mod cancel;
 mod construct;
+mod diagnostics;
 mod execute;
-
-pub use self::build::{compile, unshackle_library_file, CompilationError};
-pub use self::construct::build_compile_dir;
+mod export;
+
+pub(crate) use self::build::compile_cancellable;
+pub use self::build::{
+    compile, compile_with_diagnostics, compile_with_options, unshackle_library_file,
+    CompilationError, CompileOptions, CrateSource,
+};
+pub(crate) use self::cancel::CancelToken;
+pub(crate) use self::construct::build_compile_dir_persisting;
+pub use self::construct::{build_compile_dir, build_compile_dir_with_options};
+pub use self::diagnostics::{Diagnostic, DiagnosticSpan, Origin};
 pub(crate) use self::execute::exec;
+pub use self::execute::{run_worker, EvalPanic, ExecError, ExecOptions};
+pub use self::export::export;
 
 /// The library name to compile as.c
 const LIBRARY_NAME: &str = "papyrus_mem_code";
@@ -17,7 +30,10 @@ mod tests {
     use crate::code::*;
     use crate::linking::{Extern, LinkingConfiguration};
     use ::kserd::Kserd;
-    use std::{fs, path::PathBuf};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
 
     #[test]
     fn nodata_build_fmt_compile_eval_test() {
@@ -35,7 +51,16 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, _>(
+            path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        )
+        .unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -60,7 +85,16 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, _>(
+            path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        )
+        .unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -91,7 +125,16 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, _>(
+            path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        )
+        .unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -122,8 +165,120 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec(
+            path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        )
+        .unwrap(); // execute library fn
+
+        assert_eq!(r.0, Kserd::new_num(4));
+    }
+
+    #[test]
+    fn cached_compile_test() {
+        let compile_dir = "target/testing/cached_compile_test";
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+        let options = CompileOptions::default();
+
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        let path = compile_with_options(compile_dir, &linking_config, &options, |_| ()).unwrap();
+        unshackle_library_file(path);
+
+        // second compile is fetched from the cache, cargo is not invoked
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        let mut lines = 0;
+        let path =
+            compile_with_options(compile_dir, &linking_config, &options, |_| lines += 1).unwrap();
+        assert_eq!(lines, 0);
+
+        let r = exec(
+            path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        )
+        .unwrap();
+        assert_eq!(r.0, Kserd::new_num(4));
+    }
+
+    #[test]
+    fn cancel_compile_test() {
+        let compile_dir = "target/testing/cancel_compile_test";
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+        let opts = CompileOptions {
+            cache_limit: 0,
+            ..Default::default()
+        };
+
+        build_compile_dir_with_options(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &opts,
+        )
+        .unwrap();
+
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        match compile_cancellable(compile_dir, &linking_config, &opts, &cancel, |_| ()) {
+            Err(CompilationError::Cancelled) => (),
+            x => panic!("expecting Cancelled, found {:?}", x.map(|x| x.0)),
+        }
+    }
 
+    #[test]
+    fn compile_options_test() {
+        let compile_dir = "target/testing/compile_options_test";
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+        let options = CompileOptions {
+            release: true,
+            opt_level: Some("1".to_string()),
+            rustflags: vec!["-Cdebuginfo=0".to_string()],
+            edition: "2021".to_string(),
+            target_dir: Some("../compile_options_test_target".into()),
+            cache_limit: 0,
+            ..Default::default()
+        };
+
+        build_compile_dir_with_options(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &options,
+        )
+        .unwrap();
+        assert!(fs::read_to_string(format!("{}/Cargo.toml", compile_dir))
+            .unwrap()
+            .contains("edition = \"2021\""));
+
+        let path = compile_with_options(compile_dir, &linking_config, &options, |_| ()).unwrap();
+        assert!(path.starts_with(
+            "target/testing/compile_options_test/../compile_options_test_target/release"
+        ));
+
+        let r = exec(
+            path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        )
+        .unwrap();
         assert_eq!(r.0, Kserd::new_num(4));
     }
 
@@ -142,34 +297,199 @@ mod tests {
         // compile
         let r = compile(&compile_dir, &linking_config, |_| ());
         assert!(r.is_err());
-        match r.unwrap_err() {
-            CompilationError::CompileError(_) => (),
-            _ => panic!("expecting CompileError"),
+        let mut e = r.unwrap_err();
+        e.locate(&construct_source_map(
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+        ));
+        match e {
+            CompilationError::Diagnostics(d) => {
+                let origin = d[0].primary_span().unwrap().origin.as_ref().unwrap();
+                assert_eq!(origin.path, Path::new("lib"));
+                assert_eq!(origin.kind, SpanKind::Stmt { grp: 0, stmt: 0 });
+                assert_eq!(origin.range, 2..2);
+            }
+            _ => panic!("expecting Diagnostics"),
+        }
+    }
+
+    #[test]
+    fn compile_warnings_test() {
+        let compile_dir = "target/testing/compile_warnings";
+        let files = vec![{
+            let mut code = SourceCode::default();
+            code.stmts.push(StmtGrp(vec![
+                Statement {
+                    expr: "let unused = 1".to_string(),
+                    semi: true,
+                },
+                Statement {
+                    expr: "2+2".to_string(),
+                    semi: false,
+                },
+            ]));
+            ("lib".into(), code)
+        }]
+        .into_iter()
+        .collect();
+        let linking_config = LinkingConfiguration::default();
+        let opts = CompileOptions {
+            warnings: true,
+            ..Default::default()
+        };
+
+        build_compile_dir_with_options(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &opts,
+        )
+        .unwrap();
+
+        let source_map = construct_source_map(&files, &linking_config, &StaticFiles::new());
+        let find = |warnings: Vec<Diagnostic>| {
+            warnings.into_iter().find_map(|mut w| {
+                w.locate(&source_map);
+                let span = w.primary_span()?;
+                span.origin
+                    .as_ref()
+                    .filter(|_| w.code.as_deref() == Some("unused_variables"))
+                    .map(|x| x.kind)
+            })
+        };
+
+        let (_, warnings) =
+            compile_with_diagnostics(compile_dir, &linking_config, &opts, |_| ()).unwrap();
+        assert_eq!(find(warnings), Some(SpanKind::Stmt { grp: 0, stmt: 0 }));
+
+        // cached library returns the same warnings
+        let (_, warnings) =
+            compile_with_diagnostics(compile_dir, &linking_config, &opts, |_| ()).unwrap();
+        assert_eq!(find(warnings), Some(SpanKind::Stmt { grp: 0, stmt: 0 }));
+
+        // warnings are off by default
+        let (_, warnings) = compile_with_diagnostics(
+            compile_dir,
+            &linking_config,
+            &CompileOptions::default(),
+            |_| (),
+        )
+        .unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn crate_unavailable_test() {
+        let compile_dir = "target/testing/crate_unavailable";
+        let vendor_dir = "target/testing/crate_unavailable_vendor";
+        fs::create_dir_all(vendor_dir).unwrap();
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+        let options = CompileOptions {
+            offline: true,
+            source: Some(CrateSource::Vendored(vendor_dir.into())),
+            ..Default::default()
+        };
+
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+
+        // kserd is always a dependency
+        match compile_with_options(compile_dir, &linking_config, &options, |_| ()) {
+            Err(CompilationError::CrateUnavailable(name, _)) => assert_eq!(name, "kserd"),
+            x => panic!("expecting CrateUnavailable, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn dependency_compile_error_test() {
+        let compile_dir = "target/testing/dependency_compile_error";
+        let dep_dir = "target/testing/dependency_compile_error_dep";
+        fs::create_dir_all(format!("{}/src", dep_dir)).unwrap();
+        fs::write(
+            format!("{}/Cargo.toml", dep_dir),
+            "[package]\nname = \"broken-dep\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/src/lib.rs", dep_dir),
+            "pub fn f() -> u8 { \"a\" }\n",
+        )
+        .unwrap();
+        let dep_dir = fs::canonicalize(dep_dir).unwrap();
+
+        let mut code = SourceCode::default();
+        code.crates.push(
+            CrateType::parse_str(&format!(
+                "#[papyrus(path = {:?})] extern crate broken_dep;",
+                dep_dir
+            ))
+            .unwrap(),
+        );
+        let files = vec![("lib".into(), code)].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+
+        // the dependency's error is only reported on stdout
+        match compile(compile_dir, &linking_config, |_| ()) {
+            Err(CompilationError::CompileError(e)) => {
+                assert!(e.contains("error[E0308]: mismatched types"), "{}", e)
+            }
+            x => panic!("expecting CompileError, got {:?}", x),
         }
     }
 
-    // TODO enable when not on nightly
-    // Maybe look into why it doesn't work on nightly?
-    // #[test]
-    // fn fail_eval_test() {
-    //     let compile_dir = "target/testing/fail_eval_test";
-    //     let files = vec![fail_eval_file()];
-    //     let linking_config = LinkingConfiguration::default();
+    #[test]
+    fn fail_eval_test() {
+        let compile_dir = "target/testing/fail_eval_test";
+        let files = vec![fail_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
 
-    //     // build
-    //     build_compile_dir(&compile_dir, files.iter(), &linking_config).unwrap();
-    //     assert!(fs::read_to_string(&format!("{}/src/lib.rs", compile_dir))
-    //         .unwrap()
-    //         .contains("\nlet out0 = panic!(\"eval panic\");"));
+        // build
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        assert!(fs::read_to_string(format!("{}/src/lib.rs", compile_dir))
+            .unwrap()
+            .contains("\nif true { panic!(\"eval panic {}\", 1) };"));
 
-    //     // compile
-    //     let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
+        // compile
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+        let path = unshackle_library_file(path);
 
-    //     // eval
-    //     let r = exec::<_, _, std::io::Sink>(&path, "_lib_intern_eval", &(), None); // execute library fn
-    //     assert!(r.is_err());
-    //     assert_eq!(r, Err("a panic occured with evaluation"));
-    // }
+        // eval
+        let r = exec(
+            &path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        );
+        match r {
+            Err(ExecError::Panic(panic)) => {
+                assert_eq!(panic.message, "eval panic 1");
+                assert!(panic.location.unwrap().starts_with("src/lib.rs:"));
+            }
+            x => panic!("expecting Panic, found {:?}", x.map(|x| x.0)),
+        }
+    }
+
+    fn fail_eval_file() -> (PathBuf, SourceCode) {
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "if true { panic!(\"eval panic {}\", 1) }".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "1".to_string(),
+                semi: false,
+            },
+        ]));
+        ("lib".into(), code)
+    }
 
     fn pass_compile_eval_file() -> (PathBuf, SourceCode) {
         let mut code = SourceCode::default();
@@ -227,7 +547,16 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, _>(
+            path,
+            "_lib_intern_eval",
+            &(),
+            None,
+            &ExecOptions::default(),
+            &CancelToken::default(),
+            |_| (),
+        )
+        .unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
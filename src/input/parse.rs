@@ -70,7 +70,7 @@ pub fn parse_program(code: &str) -> InputResult {
                         ParseItemResult::ExternCrate(string) => {
                             match CrateType::parse_str(&fmt(string)) {
                                 Ok(c) => crates.push(c),
-                                Err(e) => error!("crate parsing failed: {}", e),
+                                Err(e) => return InputResult::InputError(e.to_string()),
                             }
                         }
                         ParseItemResult::Span(string) => items.push((fmt(string), false)),
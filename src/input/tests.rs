@@ -46,6 +46,20 @@ struct A {
             crates: vec![CrateType::parse_str(&"extern crate rand as r;").unwrap()]
         })
     ); // Item::ExternCrate
+    match parse_program(r#"#[papyrus(version = "0.7")] extern crate rand;"#) {
+        InputResult::Program(input) => {
+            assert_eq!(input.crates[0].spec.version.as_deref(), Some("0.7"))
+        }
+        x => panic!("expecting program, got {:?}", x),
+    }
+    assert_eq!(
+        parse_program(r#"#[papyrus(version = 7)] extern crate rand;"#),
+        InputResult::InputError(
+            CrateType::parse_str(r#"#[papyrus(version = 7)] extern crate rand;"#)
+                .unwrap_err()
+                .to_string()
+        )
+    );
     assert_eq!(
         parse_program("impl Eq for MyStruct {}"),
         InputResult::Program(Input {
@@ -0,0 +1,637 @@
+//! Run papyrus as a [Jupyter](https://jupyter.org) kernel.
+//!
+//! Requires the _jupyter_ feature.
+//!
+//! The kernel is installed for the current user with `papyrus jupyter install`, which writes the
+//! kernel spec into the Jupyter data directory. _Rust (papyrus)_ is then available in JupyterLab
+//! and the notebook, which start the kernel with `papyrus jupyter <connection-file>`.
+//!
+//! # Messages
+//! The kernel implements the shell and control messages of the
+//! [messaging protocol](https://jupyter-client.readthedocs.io/en/stable/messaging.html) needed to
+//! drive a REPL:
+//!
+//! - `execute_request` feeds the cell into the REPL and evaluates it. Output printed while evaluating
+//!   (if captured, see [`ExecOptions`](crate::compile::ExecOptions)) and the output of commands is
+//!   published as `stream` messages, the result is published as an `execute_result` holding the
+//!   [renderings](crate::repl::render) of the data, and failures are published as `error`
+//!   messages.
+//! - `complete_request` completes commands and module paths, and code with the _racer-completion_
+//!   feature.
+//! - `is_complete_request` reports whether a cell has unclosed delimiters.
+//! - `interrupt_request` cancels the current evaluation, see
+//!   [`Evaluating::cancel`](crate::repl::Evaluating::cancel).
+//! - `kernel_info_request` and `shutdown_request`.
+//!
+//! Unlike the terminal, a cell ending in a statement with a semi-colon is evaluated rather than
+//! waiting for more input, and evaluating to `()` does not publish a result.
+//!
+//! Each cell evaluates the statements of the previous cells again, unless a cell runs
+//! `:persist on` to persist the bindings between cells, see
+//! [`ReplData::persist_bindings`](crate::repl::ReplData::persist_bindings).
+//!
+//! Input requests on the stdin socket are not supported. Only the `tcp` transport is supported, and
+//! messages are signed with `hmac-sha256`.
+//!
+//! # Example
+//! ```rust,no_run
+//! # #[macro_use] extern crate papyrus;
+//! use papyrus::jupyter::{ConnectionInfo, Kernel};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let info = ConnectionInfo::read("kernel-1234.json")?;
+//! let kernel = Kernel::bind(&info)?;
+//! kernel.run(repl!(), &mut ())
+//! # }
+//! ```
+use crate::complete::{cmdr::TreeCompleter, modules::ModulesCompleter};
+use crate::input::{self, InputResult};
+use crate::output::OutputChange;
+use crate::repl::{self, EvalOutcome, EvalResult, Read, ReadResult, Repl, ReplData, Signal};
+use crossbeam_channel::{bounded, select};
+use msg::{Message, Signer, PROTOCOL_VERSION};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::{fs, io, thread};
+use zmtp::{Publisher, Router};
+
+mod msg;
+mod zmtp;
+
+/// The connection file Jupyter writes when starting a kernel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    /// The transport, only `tcp` is supported.
+    pub transport: String,
+    /// The address to bind the sockets to.
+    pub ip: String,
+    /// The port of the shell socket.
+    pub shell_port: u16,
+    /// The port of the IOPub socket.
+    pub iopub_port: u16,
+    /// The port of the stdin socket.
+    pub stdin_port: u16,
+    /// The port of the control socket.
+    pub control_port: u16,
+    /// The port of the heartbeat socket.
+    pub hb_port: u16,
+    /// The key messages are signed with. Messages are not signed if empty.
+    pub key: String,
+    /// The signature scheme, only `hmac-sha256` is supported.
+    pub signature_scheme: String,
+}
+
+impl ConnectionInfo {
+    /// Read the connection file at `path`.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        serde_json::from_reader(io::BufReader::new(file)).map_err(io::Error::from)
+    }
+}
+
+/// A Jupyter kernel serving a REPL.
+pub struct Kernel {
+    info: ConnectionInfo,
+    signer: Signer,
+    shell: Router,
+    control: Router,
+    iopub: Publisher,
+    _stdin: Router,
+}
+
+impl Kernel {
+    /// Bind the sockets of the connection info.
+    ///
+    /// A port of zero binds to any free port, the bound ports are in
+    /// [`connection_info`](Kernel::connection_info).
+    pub fn bind(info: &ConnectionInfo) -> io::Result<Self> {
+        if info.transport != "tcp" {
+            return Err(unsupported("transport", &info.transport));
+        }
+        if info.signature_scheme != "hmac-sha256" {
+            return Err(unsupported("signature scheme", &info.signature_scheme));
+        }
+
+        let addr = |port| (info.ip.as_str(), port);
+
+        let shell = Router::bind(addr(info.shell_port))?;
+        let control = Router::bind(addr(info.control_port))?;
+        let iopub = Publisher::bind(addr(info.iopub_port))?;
+        let stdin = Router::bind(addr(info.stdin_port))?;
+        let hb = zmtp::echo(addr(info.hb_port))?;
+
+        let info = ConnectionInfo {
+            shell_port: shell.local_addr().port(),
+            control_port: control.local_addr().port(),
+            iopub_port: iopub.local_addr().port(),
+            stdin_port: stdin.local_addr().port(),
+            hb_port: hb.port(),
+            ..info.clone()
+        };
+
+        Ok(Kernel {
+            signer: Signer::new(&info.key),
+            info,
+            shell,
+            control,
+            iopub,
+            _stdin: stdin,
+        })
+    }
+
+    /// The connection info, with the bound ports.
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Serve requests, evaluating cells with `repl`, until a shutdown is requested.
+    pub fn run<D>(self, repl: Repl<Read, D>, app_data: &mut D) -> io::Result<()> {
+        let Kernel {
+            signer,
+            shell,
+            control,
+            iopub,
+            ..
+        } = self;
+
+        let (shutdown_tx, shutdown_rx) = bounded(1);
+
+        let ctrl = Sockets {
+            router: control,
+            iopub: iopub.clone(),
+            signer: signer.clone(),
+        };
+        let cancel = repl.data.cancel.clone();
+        thread::spawn(move || {
+            for msg in ctrl.router.rx().iter() {
+                let req = match ctrl.decode(msg) {
+                    Some(req) => req,
+                    None => continue,
+                };
+                match req.header.msg_type.as_str() {
+                    "interrupt_request" => {
+                        cancel.cancel();
+                        ctrl.reply(&req, "interrupt_reply", json!({ "status": "ok" }));
+                    }
+                    "shutdown_request" => {
+                        cancel.cancel();
+                        ctrl.reply(&req, "shutdown_reply", shutdown_content(&req));
+                        shutdown_tx.send(()).ok();
+                        break;
+                    }
+                    "kernel_info_request" => {
+                        ctrl.reply(&req, "kernel_info_reply", kernel_info());
+                    }
+                    x => debug!("unhandled control message `{}`", x),
+                }
+            }
+        });
+
+        let mut shell = Shell {
+            sockets: Sockets {
+                router: shell,
+                iopub,
+                signer,
+            },
+            repl: Some(repl),
+            execution_count: 0,
+            #[cfg(feature = "racer-completion")]
+            cache: crate::complete::code::CodeCache::new().unwrap_or_else(|e| e.0),
+        };
+
+        loop {
+            select! {
+                recv(shell.sockets.router.rx()) -> msg => match msg {
+                    Ok(msg) => if !shell.handle(msg, app_data) {
+                        break Ok(());
+                    },
+                    Err(_) => break Ok(()),
+                },
+                recv(shutdown_rx) -> _ => break Ok(()),
+            }
+        }
+    }
+}
+
+/// The kernel spec, to be written to `kernel.json`, for the papyrus executable at `exe`.
+pub fn kernelspec(exe: &Path) -> Value {
+    json!({
+        "argv": [exe, "jupyter", "{connection_file}"],
+        "display_name": "Rust (papyrus)",
+        "language": "rust",
+        "interrupt_mode": "message",
+    })
+}
+
+/// Install the kernel spec of the current executable into the user's Jupyter data directory,
+/// returning the directory of the kernel spec.
+///
+/// The data directory is `$JUPYTER_DATA_DIR` if set, otherwise the platform's default.
+pub fn install() -> io::Result<PathBuf> {
+    let data_dir = std::env::var_os("JUPYTER_DATA_DIR")
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(target_os = "macos") {
+                dirs::home_dir().map(|x| x.join("Library").join("Jupyter"))
+            } else {
+                dirs::data_dir().map(|x| x.join("jupyter"))
+            }
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "could not find the jupyter data directory",
+            )
+        })?;
+
+    let dir = data_dir.join("kernels").join("papyrus");
+    fs::create_dir_all(&dir)?;
+    let spec = kernelspec(&std::env::current_exe()?);
+    fs::write(
+        dir.join("kernel.json"),
+        serde_json::to_string_pretty(&spec)?,
+    )?;
+
+    Ok(dir)
+}
+
+/// A router socket and the IOPub socket to publish on.
+#[derive(Clone)]
+struct Sockets {
+    router: Router,
+    iopub: Publisher,
+    signer: Signer,
+}
+
+impl Sockets {
+    fn decode(&self, msg: zmtp::Msg) -> Option<Message> {
+        Message::decode(msg, &self.signer)
+            .map_err(|e| warn!("dropping message: {}", e))
+            .ok()
+    }
+
+    fn reply(&self, req: &Message, msg_type: &str, content: Value) {
+        let msg = req.reply(msg_type, content).encode(&self.signer);
+        if let Err(e) = self.router.send(&msg) {
+            warn!("failed to send `{}`: {}", msg_type, e);
+        }
+    }
+
+    fn publish(&self, req: &Message, msg_type: &str, content: Value) {
+        self.iopub
+            .send(&req.publish(msg_type, content).encode(&self.signer));
+    }
+}
+
+/// The state of the shell socket, which evaluates cells.
+struct Shell<D> {
+    sockets: Sockets,
+    /// Taken while evaluating.
+    repl: Option<Repl<Read, D>>,
+    execution_count: usize,
+    #[cfg(feature = "racer-completion")]
+    cache: crate::complete::code::CodeCache,
+}
+
+impl<D> Shell<D> {
+    /// Handle a shell message, returning `false` if the kernel should shut down.
+    fn handle(&mut self, msg: zmtp::Msg, app_data: &mut D) -> bool {
+        let req = match self.sockets.decode(msg) {
+            Some(req) => req,
+            None => return true,
+        };
+
+        let status = |state| json!({ "execution_state": state });
+        self.sockets.publish(&req, "status", status("busy"));
+
+        let mut run = true;
+        let (reply, content) = match req.header.msg_type.as_str() {
+            "kernel_info_request" => ("kernel_info_reply", kernel_info()),
+            "execute_request" => ("execute_reply", self.execute(&req, app_data)),
+            "complete_request" => ("complete_reply", self.complete(&req)),
+            "is_complete_request" => ("is_complete_reply", is_complete(&req)),
+            "inspect_request" => (
+                "inspect_reply",
+                json!({ "status": "ok", "found": false, "data": {}, "metadata": {} }),
+            ),
+            "history_request" => ("history_reply", json!({ "status": "ok", "history": [] })),
+            "comm_info_request" => ("comm_info_reply", json!({ "status": "ok", "comms": {} })),
+            "shutdown_request" => {
+                run = false;
+                ("shutdown_reply", shutdown_content(&req))
+            }
+            x => {
+                debug!("unhandled shell message `{}`", x);
+                self.sockets.publish(&req, "status", status("idle"));
+                return true;
+            }
+        };
+
+        self.sockets.reply(&req, reply, content);
+        self.sockets.publish(&req, "status", status("idle"));
+
+        run
+    }
+
+    fn execute(&mut self, req: &Message, app_data: &mut D) -> Value {
+        let code = req.content["code"].as_str().unwrap_or_default();
+        let silent = req.content["silent"].as_bool().unwrap_or(false);
+
+        if !silent {
+            self.execution_count += 1;
+        }
+        let count = self.execution_count;
+
+        self.sockets.publish(
+            req,
+            "execute_input",
+            json!({ "code": code, "execution_count": count }),
+        );
+
+        let repl = self
+            .repl
+            .take()
+            .expect("the repl is returned after evaluating");
+        let sockets = self.sockets.clone();
+        let parent = req.clone();
+        let stream = move |text: String| {
+            if !silent {
+                sockets.publish(&parent, "stream", json!({ "name": "stdout", "text": text }))
+            }
+        };
+        let (repl, result) = run_cell(repl, code, app_data, stream);
+        self.repl = Some(repl);
+
+        match result {
+            Ok(renderings) => {
+                if !silent && !renderings.is_empty() {
+                    let data = renderings
+                        .into_iter()
+                        .map(|x| (x.mime, Value::String(x.data)))
+                        .collect::<serde_json::Map<_, _>>();
+                    self.sockets.publish(
+                        req,
+                        "execute_result",
+                        json!({ "execution_count": count, "data": data, "metadata": {} }),
+                    );
+                }
+                json!({
+                    "status": "ok",
+                    "execution_count": count,
+                    "payload": [],
+                    "user_expressions": {},
+                })
+            }
+            Err((ename, evalue)) => {
+                let error = json!({
+                    "ename": ename,
+                    "evalue": evalue,
+                    "traceback": evalue.lines().collect::<Vec<_>>(),
+                });
+                if !silent {
+                    self.sockets.publish(req, "error", error.clone());
+                }
+                let mut reply = json!({ "status": "error", "execution_count": count });
+                reply
+                    .as_object_mut()
+                    .expect("is an object")
+                    .extend(error.as_object().cloned().unwrap_or_default());
+                reply
+            }
+        }
+    }
+
+    fn complete(&self, req: &Message) -> Value {
+        let code = req.content["code"].as_str().unwrap_or_default();
+        let cursor = req.content["cursor_pos"]
+            .as_u64()
+            .map(|x| x as usize)
+            .unwrap_or_else(|| code.chars().count());
+        let data = &self.repl.as_ref().expect("the repl is available").data;
+
+        // the cursor position is in unicode code points
+        let end = code
+            .char_indices()
+            .nth(cursor)
+            .map(|x| x.0)
+            .unwrap_or_else(|| code.len());
+        let line_start = code[..end].rfind('\n').map(|x| x + 1).unwrap_or(0);
+        let line = &code[line_start..end];
+
+        let (start, matches) = if line.starts_with(crate::CMD_PREFIX) {
+            complete_cmd(data, line)
+        } else {
+            self.complete_code(data, &code[..end], line)
+        };
+
+        let chars = |byte| code[..byte].chars().count();
+        json!({
+            "status": "ok",
+            "matches": matches,
+            "cursor_start": chars(line_start + start),
+            "cursor_end": chars(end),
+            "metadata": {},
+        })
+    }
+
+    #[cfg(feature = "racer-completion")]
+    fn complete_code(
+        &self,
+        data: &ReplData<D>,
+        injection: &str,
+        line: &str,
+    ) -> (usize, Vec<String>) {
+        use crate::complete::code::CodeCompleter;
+
+        let matches = CodeCompleter::build(data)
+            .complete(injection, Some(CODE_COMPLETIONS), &self.cache)
+            .into_iter()
+            .map(|x| x.matchstr)
+            .collect();
+        (CodeCompleter::word_break(line), matches)
+    }
+
+    #[cfg(not(feature = "racer-completion"))]
+    fn complete_code(&self, _: &ReplData<D>, _: &str, line: &str) -> (usize, Vec<String>) {
+        (line.len(), Vec::new())
+    }
+}
+
+#[cfg(feature = "racer-completion")]
+const CODE_COMPLETIONS: usize = 50;
+
+/// Completes a command line, returning the byte position the matches start at.
+fn complete_cmd<D>(data: &ReplData<D>, line: &str) -> (usize, Vec<String>) {
+    let tree = TreeCompleter::build(&data.cmdtree)
+        .complete(line)
+        .map(|x| x.0.to_owned())
+        .collect::<Vec<_>>();
+    if !tree.is_empty() {
+        return (TreeCompleter::word_break(line), tree);
+    }
+
+    let mods = ModulesCompleter::build(&data.cmdtree, data.mods_map())
+        .complete(line)
+        .collect();
+    (ModulesCompleter::word_break(line), mods)
+}
+
+/// How a cell is fed to the REPL.
+#[derive(Debug, PartialEq)]
+enum CellInput {
+    Complete(String),
+    /// There are unclosed delimiters.
+    Incomplete,
+}
+
+fn cell_input(code: &str) -> CellInput {
+    match input::determine_result(code, code, false) {
+        // a trailing statement with a semi-colon waits for more input in the terminal,
+        // the cell is finished with a unit expression instead
+        InputResult::More => match input::parse_program(code) {
+            InputResult::Program(_) => CellInput::Complete(format!("{}\n()", code)),
+            _ => CellInput::Incomplete,
+        },
+        _ => CellInput::Complete(code.to_string()),
+    }
+}
+
+/// The renderings of the data, or the name and description of the failure.
+type CellResult = Result<Vec<repl::Rendering>, (String, String)>;
+
+/// Evaluates a cell, passing the lines written to the output while evaluating to `stream`.
+fn run_cell<D, F>(
+    mut repl: Repl<Read, D>,
+    code: &str,
+    app_data: &mut D,
+    stream: F,
+) -> (Repl<Read, D>, CellResult)
+where
+    F: Fn(String) + Clone + Send + 'static,
+{
+    let mut input = match cell_input(code) {
+        CellInput::Complete(input) => input,
+        CellInput::Incomplete => {
+            let e = "the cell has unclosed delimiters".to_string();
+            return (repl, Err(("IncompleteInput".into(), e)));
+        }
+    };
+
+    loop {
+        repl.line_input(&input);
+        let mut eval = match repl.read() {
+            ReadResult::Eval(eval) => eval,
+            ReadResult::Read(read) => {
+                let e = "the cell needs more input".to_string();
+                return (read, Err(("IncompleteInput".into(), e)));
+            }
+        };
+
+        let rx = eval.output_listen();
+        let lines = stream.clone();
+        let jh = thread::spawn(move || {
+            let mut line = String::new();
+            for chg in rx.iter() {
+                match chg {
+                    OutputChange::CurrentLine(l) => line = l,
+                    OutputChange::NewLine => {
+                        line.push('\n');
+                        lines(std::mem::take(&mut line));
+                    }
+                }
+            }
+            line
+        });
+
+        let EvalResult {
+            repl: mut print,
+            signal,
+            outcome,
+            renderings,
+        } = eval.eval(app_data);
+
+        // the output of commands is streamed, data and failures have their own messages
+        if !matches!(outcome, EvalOutcome::Command | EvalOutcome::Empty) {
+            print.close_channel();
+        }
+        let (mut read, _) = print.print();
+        read.close_channel();
+
+        // the unfinished line ends with the prompt drawn after printing, if it was sent
+        let mut line = jh.join().unwrap_or_default();
+        let prompt = read.prompt(true);
+        if line.ends_with(&prompt) {
+            line.truncate(line.len() - prompt.len());
+        }
+        if !line.is_empty() {
+            stream(line);
+        }
+
+        if let Signal::ReEvaluate(s) = signal {
+            input = s;
+            repl = read;
+            continue;
+        }
+
+        let result = match outcome {
+            EvalOutcome::Ok(kserd) if kserd.unit() => Ok(Vec::new()),
+            EvalOutcome::Ok(_) => Ok(renderings),
+            EvalOutcome::Empty | EvalOutcome::Command => Ok(Vec::new()),
+            EvalOutcome::ParseFailed(e) => Err(("ParseError", e)),
+            EvalOutcome::BuildFailed(e) => Err(("BuildError", e.to_string())),
+            EvalOutcome::CompileFailed(e) => Err(("CompileError", e.to_string())),
+            EvalOutcome::LoadFailed(e) | EvalOutcome::ExecFailed(e) => {
+                Err(("ExecError", e.to_string()))
+            }
+            EvalOutcome::Panicked(e) => Err(("Panic", e.to_string())),
+            EvalOutcome::Cancelled => Err(("Interrupted", "evaluation cancelled".to_string())),
+        };
+
+        break (read, result.map_err(|(name, e)| (name.to_string(), e)));
+    }
+}
+
+fn is_complete(req: &Message) -> Value {
+    let code = req.content["code"].as_str().unwrap_or_default();
+    match cell_input(code) {
+        CellInput::Complete(_) => json!({ "status": "complete" }),
+        CellInput::Incomplete => json!({ "status": "incomplete", "indent": "" }),
+    }
+}
+
+fn kernel_info() -> Value {
+    json!({
+        "status": "ok",
+        "protocol_version": PROTOCOL_VERSION,
+        "implementation": "papyrus",
+        "implementation_version": env!("CARGO_PKG_VERSION"),
+        "language_info": {
+            "name": "rust",
+            "version": "",
+            "mimetype": "text/rust",
+            "file_extension": ".rs",
+            "pygments_lexer": "rust",
+            "codemirror_mode": "rust",
+        },
+        "banner": format!("papyrus {}", env!("CARGO_PKG_VERSION")),
+        "help_links": [],
+    })
+}
+
+fn shutdown_content(req: &Message) -> Value {
+    json!({
+        "status": "ok",
+        "restart": req.content["restart"].as_bool().unwrap_or(false),
+    })
+}
+
+fn unsupported(what: &str, value: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unsupported {} `{}`", what, value),
+    )
+}
+
+#[cfg(test)]
+mod tests;
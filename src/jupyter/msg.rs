@@ -0,0 +1,264 @@
+//! The Jupyter message wire format and signing.
+use super::zmtp::Msg;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The version of the messaging protocol implemented.
+pub const PROTOCOL_VERSION: &str = "5.3";
+
+/// Separates the routing identities from the message.
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+/// Signs messages with HMAC-SHA256. Messages are not signed if the key is empty.
+#[derive(Clone)]
+pub struct Signer(Option<Hmac<Sha256>>);
+
+impl Signer {
+    pub fn new(key: &str) -> Self {
+        Signer(if key.is_empty() {
+            None
+        } else {
+            Some(Hmac::new_varkey(key.as_bytes()).expect("hmac accepts any key length"))
+        })
+    }
+
+    fn mac(&self, parts: &[Vec<u8>]) -> Option<Hmac<Sha256>> {
+        self.0.clone().map(|mut mac| {
+            for part in parts {
+                mac.update(part);
+            }
+            mac
+        })
+    }
+
+    fn sign(&self, parts: &[Vec<u8>]) -> String {
+        self.mac(parts)
+            .map(|mac| hex(&mac.finalize().into_bytes()))
+            .unwrap_or_default()
+    }
+
+    fn verify(&self, parts: &[Vec<u8>], signature: &[u8]) -> bool {
+        match (self.mac(parts), unhex(signature)) {
+            (Some(mac), Some(signature)) => mac.verify(&signature).is_ok(),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub msg_id: String,
+    pub session: String,
+    pub username: String,
+    #[serde(default)]
+    pub date: String,
+    pub msg_type: String,
+    #[serde(default)]
+    pub version: String,
+}
+
+impl Header {
+    pub fn new(session: &str, username: &str, msg_type: &str) -> Self {
+        Header {
+            msg_id: uuid::Uuid::new_v4().to_string(),
+            session: session.to_string(),
+            username: username.to_string(),
+            date: iso8601(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default(),
+            ),
+            msg_type: msg_type.to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// The routing identities.
+    pub ids: Vec<Vec<u8>>,
+    pub header: Header,
+    pub parent_header: Value,
+    pub metadata: Value,
+    pub content: Value,
+}
+
+impl Message {
+    /// Decodes the frames received on a socket, verifying the signature.
+    pub fn decode(msg: Msg, signer: &Signer) -> Result<Self, String> {
+        let pos = msg
+            .iter()
+            .position(|x| x == DELIMITER)
+            .ok_or("missing <IDS|MSG> delimiter")?;
+
+        let mut frames = msg.into_iter();
+        let ids = frames.by_ref().take(pos).collect();
+        let signature = frames.nth(1).ok_or("missing signature")?;
+        let parts = frames.take(4).collect::<Vec<_>>();
+        if parts.len() < 4 {
+            return Err("missing message parts".into());
+        }
+
+        if !signer.verify(&parts, &signature) {
+            return Err("invalid signature".into());
+        }
+
+        Ok(Message {
+            ids,
+            header: json(&parts[0])?,
+            parent_header: json(&parts[1])?,
+            metadata: json(&parts[2])?,
+            content: json(&parts[3])?,
+        })
+    }
+
+    /// Encodes the frames to send on a socket, signing the message.
+    pub fn encode(&self, signer: &Signer) -> Msg {
+        let parts = vec![
+            serde_json::to_vec(&self.header).expect("header serializes"),
+            serde_json::to_vec(&self.parent_header).expect("json serializes"),
+            serde_json::to_vec(&self.metadata).expect("json serializes"),
+            serde_json::to_vec(&self.content).expect("json serializes"),
+        ];
+
+        let mut msg = self.ids.clone();
+        msg.push(DELIMITER.to_vec());
+        msg.push(signer.sign(&parts).into_bytes());
+        msg.extend(parts);
+        msg
+    }
+
+    /// A message in response to this one, routed to the same identities.
+    pub fn reply(&self, msg_type: &str, content: Value) -> Self {
+        Message {
+            ids: self.ids.clone(),
+            header: Header::new(&self.header.session, &self.header.username, msg_type),
+            parent_header: serde_json::to_value(&self.header).expect("header serializes"),
+            metadata: json!({}),
+            content,
+        }
+    }
+
+    /// A message published on the IOPub socket in response to this one.
+    pub fn publish(&self, msg_type: &str, content: Value) -> Self {
+        Message {
+            ids: vec![msg_type.as_bytes().to_vec()],
+            ..self.reply(msg_type, content)
+        }
+    }
+}
+
+fn json<'a, T: Deserialize<'a>>(x: &'a [u8]) -> Result<T, String> {
+    serde_json::from_slice(x).map_err(|e| e.to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(s: &[u8]) -> Option<Vec<u8>> {
+    let chunks = s.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+
+    chunks
+        .map(|x| {
+            std::str::from_utf8(x)
+                .ok()
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+        })
+        .collect()
+}
+
+/// Formats the time since the unix epoch as an ISO 8601 UTC date time.
+fn iso8601(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // civil date from days, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_test() {
+        // RFC 4231 test case 2
+        let signer = Signer::new("Jefe");
+        let parts = vec![b"what do ya want ".to_vec(), b"for nothing?".to_vec()];
+        let sig = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(signer.sign(&parts), sig);
+        assert!(signer.verify(&parts, sig.as_bytes()));
+        assert!(!signer.verify(&parts, b"5bdc"));
+        assert!(!signer.verify(&parts, b"not hex"));
+
+        let unsigned = Signer::new("");
+        assert_eq!(unsigned.sign(&parts), "");
+        assert!(unsigned.verify(&parts, b""));
+    }
+
+    #[test]
+    fn encode_decode_test() {
+        let signer = Signer::new("key");
+        let req = Message {
+            ids: vec![b"id".to_vec()],
+            header: Header::new("session", "user", "kernel_info_request"),
+            parent_header: json!({}),
+            metadata: json!({}),
+            content: json!({}),
+        };
+
+        let frames = req.encode(&signer);
+        assert_eq!(frames[1], DELIMITER);
+        assert_eq!(Message::decode(frames.clone(), &signer), Ok(req.clone()));
+        assert!(Message::decode(frames.clone(), &Signer::new("other")).is_err());
+        assert!(Message::decode(frames[2..].to_vec(), &signer).is_err());
+
+        let reply = req.reply("kernel_info_reply", json!({ "status": "ok" }));
+        assert_eq!(reply.ids, req.ids);
+        assert_eq!(reply.header.session, "session");
+        assert_eq!(reply.parent_header["msg_id"], json!(req.header.msg_id));
+
+        let status = req.publish("status", json!({}));
+        assert_eq!(status.ids, vec![b"status".to_vec()]);
+    }
+
+    #[test]
+    fn iso8601_test() {
+        assert_eq!(
+            iso8601(Duration::from_secs(0)),
+            "1970-01-01T00:00:00.000000Z"
+        );
+        assert_eq!(
+            iso8601(Duration::from_micros(951_827_696_000_123)),
+            "2000-02-29T12:34:56.000123Z"
+        );
+    }
+}
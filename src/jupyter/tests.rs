@@ -0,0 +1,223 @@
+use super::msg::Header;
+use super::zmtp::{Client, SocketType};
+use super::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[test]
+fn cell_input_test() {
+    use CellInput::*;
+
+    assert_eq!(cell_input("2+2"), Complete("2+2".to_string()));
+    assert_eq!(cell_input(":help"), Complete(":help".to_string()));
+    assert_eq!(
+        cell_input("let a = 1;"),
+        Complete("let a = 1;\n()".to_string())
+    );
+    assert_eq!(
+        cell_input("fn a() {}\nlet b = 1;"),
+        Complete("fn a() {}\nlet b = 1;\n()".to_string())
+    );
+    assert_eq!(cell_input("fn a() {"), Incomplete);
+    assert_eq!(cell_input("let a = vec![1,"), Incomplete);
+}
+
+#[test]
+fn bind_test() {
+    let info = ConnectionInfo {
+        transport: "ipc".to_string(),
+        ..connection_info()
+    };
+    assert!(Kernel::bind(&info).is_err());
+
+    let info = ConnectionInfo {
+        signature_scheme: "hmac-md5".to_string(),
+        ..connection_info()
+    };
+    assert!(Kernel::bind(&info).is_err());
+
+    let kernel = Kernel::bind(&connection_info()).unwrap();
+    let info = kernel.connection_info();
+    assert_ne!(info.shell_port, 0);
+    assert_ne!(info.hb_port, 0);
+    assert_eq!(info.key, "secret");
+
+    let file: ConnectionInfo = serde_json::from_str(&serde_json::to_string(info).unwrap()).unwrap();
+    assert_eq!(&file, info);
+}
+
+#[test]
+fn kernelspec_test() {
+    let spec = kernelspec(Path::new("/bin/papyrus"));
+    assert_eq!(
+        spec["argv"],
+        json!(["/bin/papyrus", "jupyter", "{connection_file}"])
+    );
+    assert_eq!(spec["language"], "rust");
+}
+
+#[test]
+fn kernel_test() {
+    let mut fe = Frontend::start(crate::repl::Repl::default());
+
+    let (reply, iopub) = fe.request("kernel_info_request", json!({}));
+    assert_eq!(reply.content["implementation"], "papyrus");
+    assert_eq!(reply.content["language_info"]["name"], "rust");
+    let iopub = iopub.iter().map(|x| &x.content["execution_state"]);
+    assert_eq!(iopub.collect::<Vec<_>>(), vec!["busy", "idle"]);
+
+    let (reply, _) = fe.request("is_complete_request", json!({ "code": "fn a() {" }));
+    assert_eq!(reply.content["status"], "incomplete");
+    let (reply, _) = fe.request("is_complete_request", json!({ "code": "let a = 1;" }));
+    assert_eq!(reply.content["status"], "complete");
+
+    let (reply, _) = fe.request(
+        "complete_request",
+        json!({ "code": "2+2\n:mo", "cursor_pos": 7 }),
+    );
+    assert_eq!(reply.content["matches"][0], ":mod");
+    assert_eq!(reply.content["cursor_start"], 4);
+    assert_eq!(reply.content["cursor_end"], 7);
+
+    let (reply, iopub) = fe.request("execute_request", json!({ "code": ":help" }));
+    assert_eq!(reply.content["status"], "ok");
+    assert_eq!(reply.content["execution_count"], 1);
+    assert_eq!(iopub[1].header.msg_type, "execute_input");
+    assert_eq!(iopub[2].header.msg_type, "stream");
+    assert!(iopub[2].content["text"]
+        .as_str()
+        .unwrap()
+        .contains("prints the help messages"));
+    let streamed = iopub
+        .iter()
+        .filter(|x| x.header.msg_type == "stream")
+        .map(|x| x.content["text"].as_str().unwrap())
+        .collect::<String>();
+    assert!(!streamed.contains("papyrus=>"), "{}", streamed);
+
+    let (reply, _) = fe.request("shutdown_request", json!({ "restart": false }));
+    assert_eq!(reply.content["status"], "ok");
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn execute_test() {
+    let mut repl = crate::repl::Repl::default();
+    repl.data.exec_options.capture_in_process = true;
+    let mut fe = Frontend::start(repl);
+
+    let (reply, iopub) = fe.request("execute_request", json!({ "code": "2+2" }));
+    assert_eq!(reply.content["status"], "ok");
+    let result = iopub
+        .iter()
+        .find(|x| x.header.msg_type == "execute_result")
+        .expect("a result is published");
+    assert_eq!(result.content["data"]["text/plain"], "4");
+    assert_eq!(result.content["execution_count"], 1);
+
+    let (reply, iopub) = fe.request("execute_request", json!({ "code": "println!(\"Hello\");" }));
+    assert_eq!(reply.content["status"], "ok");
+    assert!(iopub
+        .iter()
+        .any(|x| x.header.msg_type == "stream" && x.content["text"] == "Hello\n"));
+    assert!(iopub.iter().all(|x| x.header.msg_type != "execute_result"));
+
+    let (reply, iopub) = fe.request("execute_request", json!({ "code": "let a: u8 = \"a\";" }));
+    assert_eq!(reply.content["status"], "error");
+    assert_eq!(reply.content["ename"], "CompileError");
+    assert!(iopub.iter().any(|x| x.header.msg_type == "error"));
+}
+
+#[test]
+fn partial_line_test() {
+    use crate::cmds::CommandResult;
+    use crate::cmdtree::{Builder, BuilderChain};
+
+    let mut repl = crate::repl::Repl::default();
+    let cmds = Builder::new("papyrus")
+        .add_action("partial", "write without a new line", |wtr, _| {
+            write!(wtr, "partial").ok();
+            CommandResult::Empty
+        })
+        .unwrap();
+    repl.data.with_cmdtree_builder(cmds).unwrap();
+    let mut fe = Frontend::start(repl);
+
+    // the final line is streamed without the prompt
+    let (reply, iopub) = fe.request("execute_request", json!({ "code": ":partial" }));
+    assert_eq!(reply.content["status"], "ok");
+    let streamed = iopub
+        .iter()
+        .filter(|x| x.header.msg_type == "stream")
+        .map(|x| x.content["text"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(streamed, vec!["partial"]);
+}
+
+fn connection_info() -> ConnectionInfo {
+    ConnectionInfo {
+        transport: "tcp".to_string(),
+        ip: "127.0.0.1".to_string(),
+        shell_port: 0,
+        iopub_port: 0,
+        stdin_port: 0,
+        control_port: 0,
+        hb_port: 0,
+        key: "secret".to_string(),
+        signature_scheme: "hmac-sha256".to_string(),
+    }
+}
+
+/// A stand-in frontend connected to a running kernel.
+struct Frontend {
+    shell: Client,
+    iopub: Client,
+    signer: Signer,
+}
+
+impl Frontend {
+    fn start(repl: Repl<Read, ()>) -> Self {
+        let kernel = Kernel::bind(&connection_info()).unwrap();
+        let info = kernel.connection_info().clone();
+        thread::spawn(move || kernel.run(repl, &mut ()));
+
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let shell = Client::connect(addr(info.shell_port), SocketType::Dealer).unwrap();
+        let iopub = Client::connect(addr(info.iopub_port), SocketType::Sub).unwrap();
+        // the subscription is registered after the handshake
+        thread::sleep(Duration::from_millis(200));
+
+        Frontend {
+            shell,
+            iopub,
+            signer: Signer::new(&info.key),
+        }
+    }
+
+    /// Sends a request, returning the reply and the messages published up to the kernel being idle.
+    fn request(&mut self, msg_type: &str, content: Value) -> (Message, Vec<Message>) {
+        let req = Message {
+            ids: Vec::new(),
+            header: Header::new("test-session", "test", msg_type),
+            parent_header: json!({}),
+            metadata: json!({}),
+            content,
+        };
+        self.shell.send(&req.encode(&self.signer)).unwrap();
+        let reply = Message::decode(self.shell.recv().unwrap(), &self.signer).unwrap();
+        assert_eq!(reply.parent_header["msg_id"], json!(req.header.msg_id));
+        (reply, self.published())
+    }
+
+    fn published(&mut self) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        loop {
+            let msg = Message::decode(self.iopub.recv().unwrap(), &self.signer).unwrap();
+            let idle = msg.header.msg_type == "status" && msg.content["execution_state"] == "idle";
+            msgs.push(msg);
+            if idle {
+                break msgs;
+            }
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! A minimal implementation of the ZeroMQ message transport protocol
+//! ([ZMTP 3.0](https://rfc.zeromq.org/spec/23/)) over TCP, using the `NULL` security mechanism.
+//!
+//! Only what a Jupyter kernel needs is implemented: binding `ROUTER`, `PUB`, and `REP` sockets
+//! which frontends connect to. Each connection is handled on its own thread.
+use crossbeam_channel::{unbounded, Receiver};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A multipart message.
+pub type Msg = Vec<Vec<u8>>;
+
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+
+/// The largest frame body accepted, the length of a frame is sent by the peer and so is not
+/// trusted to allocate.
+const MAX_FRAME: u64 = 1 << 28;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SocketType {
+    Router,
+    Pub,
+    Rep,
+    #[cfg(test)]
+    Dealer,
+    #[cfg(test)]
+    Sub,
+    #[cfg(test)]
+    Req,
+}
+
+impl SocketType {
+    fn name(self) -> &'static str {
+        match self {
+            SocketType::Router => "ROUTER",
+            SocketType::Pub => "PUB",
+            SocketType::Rep => "REP",
+            #[cfg(test)]
+            SocketType::Dealer => "DEALER",
+            #[cfg(test)]
+            SocketType::Sub => "SUB",
+            #[cfg(test)]
+            SocketType::Req => "REQ",
+        }
+    }
+}
+
+/// A socket which routes messages by the identity of the connected peer.
+///
+/// Received messages are prefixed with the peer identity, and sent messages are routed to the peer
+/// with the identity of the first frame. Messages to unknown peers are dropped.
+#[derive(Clone)]
+pub struct Router {
+    addr: SocketAddr,
+    peers: Arc<Mutex<HashMap<Vec<u8>, TcpStream>>>,
+    rx: Receiver<Msg>,
+}
+
+impl Router {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let peers: Arc<Mutex<HashMap<_, TcpStream>>> = Arc::default();
+        let (tx, rx) = unbounded();
+        let next_id = AtomicU32::new(0);
+
+        let p = Arc::clone(&peers);
+        let addr = serve(addr, SocketType::Router, move |mut stream, id| {
+            // peers without an identity are given one, the same as libzmq does
+            let id = id.unwrap_or_else(|| {
+                let mut id = vec![0];
+                id.extend_from_slice(&next_id.fetch_add(1, Ordering::SeqCst).to_be_bytes());
+                id
+            });
+
+            match stream.try_clone() {
+                Ok(w) => p.lock().unwrap().insert(id.clone(), w),
+                Err(_) => return,
+            };
+
+            while let Ok(mut msg) = read_msg(&mut stream) {
+                msg.insert(0, id.clone());
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+
+            p.lock().unwrap().remove(&id);
+        })?;
+
+        Ok(Self { addr, peers, rx })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The received messages.
+    pub fn rx(&self) -> &Receiver<Msg> {
+        &self.rx
+    }
+
+    pub fn send(&self, msg: &[Vec<u8>]) -> io::Result<()> {
+        let peers = self.peers.lock().unwrap();
+        match msg.split_first() {
+            Some((id, msg)) if !msg.is_empty() => match peers.get(id) {
+                Some(mut stream) => write_msg(&mut stream, msg),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A socket which sends each message to every connected subscriber.
+///
+/// Subscriptions are not filtered, a Jupyter frontend subscribes to all messages.
+#[derive(Clone)]
+pub struct Publisher {
+    addr: SocketAddr,
+    subs: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Publisher {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let subs: Arc<Mutex<Vec<TcpStream>>> = Arc::default();
+
+        let s = Arc::clone(&subs);
+        let addr = serve(addr, SocketType::Pub, move |mut stream, _| {
+            if let Ok(w) = stream.try_clone() {
+                s.lock().unwrap().push(w);
+            }
+            // read (and ignore) subscriptions until the subscriber disconnects
+            while read_frame(&mut stream).is_ok() {}
+        })?;
+
+        Ok(Self { addr, subs })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends to all subscribers, dropping any subscriber which fails to be written to.
+    pub fn send(&self, msg: &[Vec<u8>]) {
+        self.subs
+            .lock()
+            .unwrap()
+            .retain(|mut stream| write_msg(&mut stream, msg).is_ok());
+    }
+}
+
+/// Binds a `REP` socket which replies to each message with the same message.
+pub fn echo<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    serve(addr, SocketType::Rep, |mut stream, _| {
+        while let Ok(msg) = read_msg(&mut stream) {
+            if write_msg(&mut stream, &msg).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// A socket connected to a bound socket. Used as a stand-in frontend in tests.
+#[cfg(test)]
+pub struct Client(TcpStream);
+
+#[cfg(test)]
+impl Client {
+    pub fn connect(addr: SocketAddr, ty: SocketType) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        handshake(&mut stream, ty, false)?;
+        Ok(Client(stream))
+    }
+
+    pub fn send(&mut self, msg: &[Vec<u8>]) -> io::Result<()> {
+        write_msg(&mut self.0, msg)
+    }
+
+    pub fn recv(&mut self) -> io::Result<Msg> {
+        read_msg(&mut self.0)
+    }
+}
+
+/// Binds to `addr`, handshaking each connection on its own thread before handing it to `f` with
+/// the identity of the peer, if it has one.
+fn serve<A, F>(addr: A, ty: SocketType, f: F) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+    F: Fn(TcpStream, Option<Vec<u8>>) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let f = Arc::new(f);
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().filter_map(Result::ok) {
+            let f = Arc::clone(&f);
+            thread::spawn(move || match handshake(&mut stream, ty, true) {
+                Ok(id) => f(stream, id),
+                Err(e) => warn!("{} handshake failed: {}", ty.name(), e),
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Exchanges greetings and `READY` commands, returning the identity of the peer, if it has one.
+fn handshake(
+    stream: &mut TcpStream,
+    ty: SocketType,
+    as_server: bool,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut greeting = [0; 64];
+    greeting[0] = 0xff; // signature
+    greeting[9] = 0x7f;
+    greeting[10] = 3; // version 3.0
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting[32] = as_server as u8;
+    stream.write_all(&greeting)?;
+
+    let mut peer = [0; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xff || peer[9] != 0x7f || peer[10] < 3 || &peer[12..17] != b"NULL\0" {
+        return Err(invalid("unsupported greeting"));
+    }
+
+    let mut ready = b"\x05READY".to_vec();
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(ty.name().len() as u32).to_be_bytes());
+    ready.extend_from_slice(ty.name().as_bytes());
+    write_frame(stream, COMMAND, &ready)?;
+
+    let (flags, body) = read_frame(stream)?;
+    if flags & COMMAND == 0 || !body.starts_with(b"\x05READY") {
+        return Err(invalid("expecting a READY command"));
+    }
+
+    let id = properties(&body[6..])?
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Identity"))
+        .map(|x| x.1)
+        .filter(|x| !x.is_empty());
+
+    Ok(id)
+}
+
+/// Parses the metadata properties of a command.
+fn properties(mut buf: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut props = Vec::new();
+    while let Some((&len, rest)) = buf.split_first() {
+        let len = len as usize;
+        if rest.len() < len + 4 {
+            return Err(invalid("truncated property"));
+        }
+        let name = String::from_utf8_lossy(&rest[..len]).into_owned();
+        let mut vlen = [0; 4];
+        vlen.copy_from_slice(&rest[len..len + 4]);
+        let vlen = u32::from_be_bytes(vlen) as usize;
+        let rest = &rest[len + 4..];
+        if rest.len() < vlen {
+            return Err(invalid("truncated property"));
+        }
+        props.push((name, rest[..vlen].to_vec()));
+        buf = &rest[vlen..];
+    }
+    Ok(props)
+}
+
+fn write_frame<W: Write>(wtr: &mut W, flags: u8, body: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(body.len() + 9);
+    encode_frame(&mut buf, flags, body);
+    wtr.write_all(&buf)
+}
+
+fn encode_frame(buf: &mut Vec<u8>, flags: u8, body: &[u8]) {
+    if body.len() > 255 {
+        buf.push(flags | LONG);
+        buf.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        buf.push(flags);
+        buf.push(body.len() as u8);
+    }
+    buf.extend_from_slice(body);
+}
+
+fn read_frame<R: Read>(rdr: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut flags = [0];
+    rdr.read_exact(&mut flags)?;
+    let flags = flags[0];
+
+    let len = if flags & LONG == 0 {
+        let mut len = [0];
+        rdr.read_exact(&mut len)?;
+        u64::from(len[0])
+    } else {
+        let mut len = [0; 8];
+        rdr.read_exact(&mut len)?;
+        u64::from_be_bytes(len)
+    };
+
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes exceeds the maximum of {}",
+                len, MAX_FRAME
+            ),
+        ));
+    }
+
+    // read as the bytes arrive rather than allocating the whole length up front
+    let mut body = Vec::new();
+    rdr.take(len).read_to_end(&mut body)?;
+    if body.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((flags, body))
+}
+
+/// Writes the frames of a message in one write, such that messages are not interleaved.
+fn write_msg<W: Write>(wtr: &mut W, msg: &[Vec<u8>]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for (i, frame) in msg.iter().enumerate() {
+        let flags = if i + 1 < msg.len() { MORE } else { 0 };
+        encode_frame(&mut buf, flags, frame);
+    }
+    wtr.write_all(&buf)
+}
+
+/// Reads the frames of the next message, skipping any commands.
+fn read_msg<R: Read>(rdr: &mut R) -> io::Result<Msg> {
+    let mut msg = Vec::new();
+    loop {
+        let (flags, body) = read_frame(rdr)?;
+        if flags & COMMAND != 0 {
+            continue;
+        }
+        msg.push(body);
+        if flags & MORE == 0 {
+            break Ok(msg);
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn msg(frames: &[&str]) -> Msg {
+        frames.iter().map(|x| x.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn frame_test() {
+        let long = vec![7; 300];
+        let mut buf = Vec::new();
+        write_msg(&mut buf, &[b"short".to_vec(), long.clone()]).unwrap();
+        assert_eq!(&buf[..7], b"\x01\x05short");
+        assert_eq!(&buf[7..16], &[2, 0, 0, 0, 0, 0, 0, 1, 44]);
+
+        let mut rdr = buf.as_slice();
+        assert_eq!(read_msg(&mut rdr).unwrap(), vec![b"short".to_vec(), long]);
+        assert!(read_msg(&mut rdr).is_err());
+
+        // truncated
+        let mut rdr = &buf[..100];
+        assert_eq!(
+            read_msg(&mut rdr).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // too long to accept
+        let mut rdr: &[u8] = &[2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(
+            read_msg(&mut rdr).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn properties_test() {
+        let props = properties(b"\x0bSocket-Type\0\0\0\x06ROUTER\x08Identity\0\0\0\0").unwrap();
+        assert_eq!(
+            props,
+            vec![
+                ("Socket-Type".to_string(), b"ROUTER".to_vec()),
+                ("Identity".to_string(), Vec::new())
+            ]
+        );
+        assert!(properties(b"\x0bSocket-Type\0\0\0\x06ROU").is_err());
+    }
+
+    #[test]
+    fn router_test() {
+        let router = Router::bind("127.0.0.1:0").unwrap();
+        let mut a = Client::connect(router.local_addr(), SocketType::Dealer).unwrap();
+        let mut b = Client::connect(router.local_addr(), SocketType::Dealer).unwrap();
+
+        a.send(&msg(&["from a"])).unwrap();
+        let recv = router.rx().recv().unwrap();
+        assert_eq!(&recv[1..], msg(&["from a"]).as_slice());
+        let a_id = recv[0].clone();
+
+        b.send(&msg(&["from b"])).unwrap();
+        let recv = router.rx().recv().unwrap();
+        assert_ne!(recv[0], a_id);
+
+        router
+            .send(&[a_id, b"to a".to_vec(), b"more".to_vec()])
+            .unwrap();
+        assert_eq!(a.recv().unwrap(), msg(&["to a", "more"]));
+    }
+
+    #[test]
+    fn publisher_test() {
+        let publisher = Publisher::bind("127.0.0.1:0").unwrap();
+        let mut sub = Client::connect(publisher.local_addr(), SocketType::Sub).unwrap();
+        while publisher.subs.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        publisher.send(&msg(&["topic", "data"]));
+        assert_eq!(sub.recv().unwrap(), msg(&["topic", "data"]));
+    }
+
+    #[test]
+    fn echo_test() {
+        let addr = echo("127.0.0.1:0").unwrap();
+        let mut req = Client::connect(addr, SocketType::Req).unwrap();
+        req.send(&msg(&["", "ping"])).unwrap();
+        assert_eq!(req.recv().unwrap(), msg(&["", "ping"]));
+    }
+}
@@ -44,6 +44,22 @@
 //! [lib] papyrus=> Thanks for using papyrus!
 //! ```
 //!
+//! ### Scripts
+//! Papyrus can also run a rust source file as a script. The file is compiled and evaluated once,
+//! printing the result of the trailing expression. If the file only contains items and has a
+//! `fn main()`, then `main` is called.
+//! ```sh
+//! papyrus run script.rs
+//! ```
+//!
+//! A shebang line is ignored, so a script can be made executable and run directly.
+//! ```sh
+//! #!/usr/bin/env papyrus
+//! let x = 2 + 2;
+//! x * 2
+//! ```
+//! The process exits with a non-zero code if the script fails to parse, compile, or evaluate.
+//!
 //! ## Installation
 //! Papyrus can be installed from `crates.io` or building from source on github.
 //! The default installation feature set requires a `nightly` toolchain, but `stable` can be used with
@@ -65,11 +81,12 @@
 //! ### Features
 //! Papyrus has features sets:
 //! - _format_: format code snippets using `rustfmt`
+//! - _jupyter_: run papyrus as a [Jupyter](https://jupyter.org) kernel, see [`jupyter`](jupyter)
 //! - _racer-completion_: enable code completion using [`racer`](https://github.com/racer-rust/racer).
 //!     **Requires a nightly compiler**
 //! - _runnable_: papyrus can be _run_, without needing to manually handle repl states and output
 //!
-//! All features are enabled by default.
+//! All features except _jupyter_ are enabled by default.
 //!
 //! ### Cargo
 //! Papyrus leverages installed binaries of both `cargo` and `rustc`. This requirement may lift in the
@@ -142,9 +159,13 @@ pub mod complete;
 pub mod fmt;
 /// Parsing of input.
 pub mod input;
+#[cfg(feature = "jupyter")]
+pub mod jupyter;
 pub mod linking;
 pub mod output;
 pub mod repl;
+pub mod script;
+pub mod server;
 
 /// Running the repl. Requires `runnable` feature.
 #[cfg(feature = "runnable")]
@@ -44,6 +44,48 @@
 //! [lib] papyrus=> Thanks for using papyrus!
 //! ```
 //!
+//! ### Persisting bindings
+//! Each input evaluates the previous statements again, repeating their side effects. Passing
+//! `--persist` (or entering `:persist on`) keeps the bindings between inputs instead, so only the
+//! new statements are evaluated. Restored bindings are copies made by a `serde` round trip, so
+//! `#[serde(skip)]` fields are reset and values tied to process state (such as file handles) are
+//! not the same. Bindings which cannot be stored fall back to evaluating the previous statements.
+//! ```sh
+//! papyrus --persist
+//! ```
+//!
+//! ### Scripts
+//! Papyrus can also run a rust source file as a script. The file is compiled and evaluated once,
+//! printing the result of the trailing expression. If the file only contains items and has a
+//! `fn main()`, then `main` is called.
+//! ```sh
+//! papyrus run script.rs
+//! ```
+//!
+//! A shebang line is ignored, so a script can be made executable and run directly.
+//! ```sh
+//! #!/usr/bin/env papyrus
+//! let x = 2 + 2;
+//! x * 2
+//! ```
+//! The process exits with a non-zero code if the script fails to parse, compile, or evaluate.
+//!
+//! ### Server
+//! `papyrus --server` drives a REPL with line-delimited JSON requests on stdin, writing responses
+//! and output events to stdout, so editors and scripts can run a REPL session as a subprocess. See
+//! the [`server`](https://docs.rs/papyrus/latest/papyrus/server/index.html) module for the protocol.
+//! ```sh
+//! echo '{"id": 1, "method": "eval", "code": "2+2"}' | papyrus --server
+//! ```
+//!
+//! ### Jupyter
+//! With the _jupyter_ feature, papyrus can be used as a Jupyter kernel. Install the kernel spec and
+//! select _Rust (papyrus)_ in JupyterLab or the notebook.
+//! ```sh
+//! cargo install papyrus --features jupyter
+//! papyrus jupyter install
+//! ```
+//!
 //! ## Installation
 //! Papyrus can be installed from `crates.io` or building from source on github.
 //! The default installation feature set requires a `nightly` toolchain, but `stable` can be used with
@@ -65,21 +107,63 @@
 //! ### Features
 //! Papyrus has features sets:
 //! - _format_: format code snippets using `rustfmt`
+//! - _jupyter_: run papyrus as a [Jupyter](https://jupyter.org) kernel
 //! - _racer-completion_: enable code completion using [`racer`](https://github.com/racer-rust/racer).
 //!     **Requires a nightly compiler**
 //! - _runnable_: papyrus can be _run_, without needing to manually handle repl states and output
 //!
-//! All features are enabled by default.
+//! All features except _jupyter_ are enabled by default.
 //!
 //! ### Cargo
 //! Papyrus leverages installed binaries of both `cargo` and `rustc`. This requirement may lift in the
 //! future but for now, any user wanting to use Papyrus will need an installation of Rust.
 use papyrus::*;
+use std::io;
+use std::path::Path;
 
 fn main() {
+    // a worker process evaluates out-of-process and exits
+    compile::run_worker();
+
     windows_term_hack();
 
-    let repl = repl!();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // `--persist` can be given with any other argument
+    let persist = args.iter().any(|x| x == "--persist");
+    args.retain(|x| x != "--persist");
+
+    match args.first().map(|x| x.as_str()) {
+        None => run_repl(persist),
+        Some("--server") => run_server(persist),
+        Some("run") => match args.get(1) {
+            Some(file) => run_script(file),
+            None => {
+                eprintln!("run expects a path to a script file");
+                std::process::exit(2);
+            }
+        },
+        #[cfg(feature = "jupyter")]
+        Some("jupyter") => match args.get(1).map(|x| x.as_str()) {
+            Some("install") => install_kernel(),
+            Some(connection_file) => run_kernel(connection_file, persist),
+            None => {
+                eprintln!("jupyter expects `install` or a path to a connection file");
+                std::process::exit(2);
+            }
+        },
+        // invoked through a shebang, or directly with a file
+        Some(file) if Path::new(file).is_file() => run_script(file),
+        Some(arg) => {
+            eprintln!("unrecognised argument: {}", arg);
+            eprintln!("usage: papyrus [--persist] [--server] [run] [script.rs]");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn run_repl(persist: bool) {
+    let mut repl = repl!();
+    repl.data.persist_bindings = persist;
 
     let app_data = &mut ();
 
@@ -94,6 +178,70 @@ fn main() {
     }
 }
 
+fn run_server(persist: bool) {
+    let mut repl = repl!();
+    repl.data.persist_bindings = persist;
+    // printing would corrupt the responses on stdout
+    repl.data.exec_options.capture_in_process = true;
+
+    let stdin = io::BufReader::new(io::stdin());
+    if let Err(e) = server::serve(repl, &mut (), stdin, io::stdout()) {
+        eprintln!("papyrus server crashed! {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run_script(file: &str) {
+    use script::ScriptError;
+
+    match script::run(file, script::default_compile_dir(file)) {
+        Ok(Some(kserd)) => println!("{}", repl::fmt_output(&kserd, Default::default())),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            let code = match e {
+                ScriptError::Eval(_) => 101, // same as a panicking rust binary
+                _ => 1,
+            };
+            std::process::exit(code);
+        }
+    }
+}
+
+#[cfg(feature = "jupyter")]
+fn install_kernel() {
+    match jupyter::install() {
+        Ok(dir) => println!("installed the papyrus kernel spec to {}", dir.display()),
+        Err(e) => {
+            eprintln!("failed to install the kernel spec: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "jupyter")]
+fn run_kernel(connection_file: &str, persist: bool) {
+    let kernel =
+        jupyter::ConnectionInfo::read(connection_file).and_then(|x| jupyter::Kernel::bind(&x));
+    let kernel = match kernel {
+        Ok(kernel) => kernel,
+        Err(e) => {
+            eprintln!("failed to start the kernel: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut repl = repl!();
+    repl.data.persist_bindings = persist;
+    // printed output is published to the frontend
+    repl.data.exec_options.capture_in_process = true;
+
+    if let Err(e) = kernel.run(repl, &mut ()) {
+        eprintln!("papyrus kernel crashed! {}", e);
+        std::process::exit(1);
+    }
+}
+
 #[cfg(windows)]
 fn windows_term_hack() {
     colored::control::set_virtual_terminal(true).ok();
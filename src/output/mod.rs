@@ -132,7 +132,7 @@
 //!            let output_thread_jh = write_output_to_stdout(rx);
 //!
 //!            // evaluate using a unit value for data
-//!            let EvalResult { repl, signal } = eval.eval(&mut ());
+//!            let EvalResult { repl, signal, .. } = eval.eval(&mut ());
 //!
 //!            // handle the signal, other values are elided but would be
 //!            // handled in a more complete implementation
@@ -247,7 +247,7 @@
 //!             // The repl is ready for evaluating
 //!
 //!             // evaluate using a unit value for data
-//!             let EvalResult { repl, signal } = eval.eval(&mut ());
+//!             let EvalResult { repl, signal, .. } = eval.eval(&mut ());
 //!
 //!             // handle the signal, other values are elided but would be
 //!             // handled in a more complete implementation
@@ -270,6 +270,7 @@ mod read;
 mod write;
 
 use crossbeam_channel as channel;
+use serde::Serialize;
 
 /// Line change receiving end.
 pub type Receiver = channel::Receiver<OutputChange>;
@@ -296,7 +297,10 @@ pub struct Output<S> {
 }
 
 /// Line change event.
-#[derive(Debug, PartialEq)]
+///
+/// Serializes as `{"current_line": ".."}` or `"new_line"`.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum OutputChange {
     /// A change was made on the current line.
     CurrentLine(String),
@@ -19,11 +19,19 @@ impl<Data> Default for ReplData<Data> {
             out_colour: Color::BrightGreen,
             compilation_dir: default_compile_dir(),
             linking: LinkingConfiguration::default(),
+            compile_options: CompileOptions::default(),
+            exec_options: ExecOptions::default(),
+            cancel: CancelToken::default(),
             editing: None,
             editing_src: None,
             static_files: StaticFiles::new(),
             loadedlibs: VecDeque::new(),
             loaded_libs_size_limit: 0,
+            history_size: 1000,
+            persist_bindings: false,
+            persisted: HashMap::new(),
+            output_history_size: 100,
+            outputs: HashMap::new(),
         };
 
         r.with_cmdtree_builder(Builder::new("papyrus"))
@@ -151,6 +159,24 @@ impl<Data> ReplData<Data> {
         self.compilation_dir.join("src").join(path)
     }
 
+    /// Export the REPL code as a standalone binary crate in `dir`.
+    ///
+    /// See [`compile::export`](crate::compile::export).
+    pub fn export<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        crate::compile::export(
+            dir,
+            &self.compilation_dir,
+            &self.mods_map,
+            &self.linking,
+            &self.static_files,
+        )
+    }
+
+    /// The file input history is persisted to.
+    pub(crate) fn history_file(&self) -> PathBuf {
+        self.compilation_dir.join("history")
+    }
+
     /// Clears the cached loaded libraries.
     ///
     /// This can be used to clear resources. Loaded libraries are stored up to the
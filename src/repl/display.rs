@@ -0,0 +1,449 @@
+//! Rich display of evaluation outputs.
+use super::*;
+use crate::code::{DEBUG_ID, MIME_ID, TYPE_NAME_ID};
+use ::kserd::{fmt::FormattingConfig, Value};
+use serde::Serialize;
+
+/// The width of the longest bar of a histogram.
+const BAR_WIDTH: usize = 40;
+
+/// A rendering of an evaluation's output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rendering {
+    /// The MIME type, such as `text/plain` or `text/html`.
+    pub mime: String,
+    /// The rendered data.
+    pub data: String,
+}
+
+impl Rendering {
+    fn new<M: Into<String>>(mime: M, data: String) -> Self {
+        Rendering {
+            mime: mime.into(),
+            data,
+        }
+    }
+}
+
+/// Renders the output of an evaluation. The first rendering is the `text/plain` rendering
+/// printed in the terminal (see [`fmt_output`]).
+///
+/// A sequence of containers or tuples (such as a `Vec` of structs) is rendered as an aligned
+/// table, and a map of numbers as a histogram. Front-ends which can display more than text, such
+/// as notebooks, can use the other renderings, which are also on [`EvalResult::renderings`].
+/// Tables are also rendered as `text/csv` and `text/html`.
+///
+/// # Custom display
+/// A type defined in the REPL can provide its own renderings by implementing the
+/// `__papyrus_display::Mime` trait. The `text/plain` rendering is printed in the terminal.
+///
+/// ```text
+/// [lib] papyrus=> struct Bold(&'static str);
+/// [lib] papyrus=> impl __papyrus_display::Mime for Bold {
+/// [lib] papyrus.>     fn mime(&self) -> Vec<(String, String)> {
+/// [lib] papyrus.>         vec![
+/// [lib] papyrus.>             ("text/plain".into(), self.0.into()),
+/// [lib] papyrus.>             ("text/html".into(), format!("<b>{}</b>", self.0)),
+/// [lib] papyrus.>         ]
+/// [lib] papyrus.>     }
+/// [lib] papyrus.> }
+/// [lib] papyrus=> Bold("Hello")
+/// papyrus [out0]: Hello
+/// ```
+pub fn render(kserd: &Kserd) -> Vec<Rendering> {
+    let mut renderings = vec![Rendering::new(
+        "text/plain",
+        fmt_output(kserd, FormattingConfig::default()),
+    )];
+
+    if let Some(mimes) = mime(kserd) {
+        renderings.extend(
+            mimes
+                .into_iter()
+                .filter(|x| x.0 != "text/plain")
+                .map(|(mime, data)| Rendering::new(mime, data.to_string())),
+        );
+    } else if let Some(table) = Table::new(kserd) {
+        renderings.push(Rendering::new("text/csv", table.csv()));
+        renderings.push(Rendering::new("text/html", table.html()));
+    }
+
+    renderings
+}
+
+/// Formats the output of an evaluation for the terminal with the given formatting configuration.
+///
+/// Tables and histograms are rendered as text. Outputs which do not implement `ToKserd` are
+/// returned as a string of their `Debug` formatting (with the [`DEBUG_ID`] identity) or of their
+/// type name (with the [`TYPE_NAME_ID`] identity), these are written as is, the type name in
+/// angle brackets. An output implementing `Mime` (with the [`MIME_ID`] identity) is written as its
+/// `text/plain` rendering, or its MIME types in angle brackets.
+///
+/// [`DEBUG_ID`]: crate::code::DEBUG_ID
+/// [`TYPE_NAME_ID`]: crate::code::TYPE_NAME_ID
+/// [`MIME_ID`]: crate::code::MIME_ID
+pub fn fmt_output(kserd: &Kserd, config: FormattingConfig) -> String {
+    match (kserd.id(), &kserd.val) {
+        (Some(DEBUG_ID), Value::Str(s)) => s.as_str().to_string(),
+        (Some(TYPE_NAME_ID), Value::Str(s)) => format!("<{}>", s.as_str()),
+        _ => {
+            if let Some(mimes) = mime(kserd) {
+                match mimes.iter().find(|x| x.0 == "text/plain") {
+                    Some(x) => x.1.to_string(),
+                    None => format!(
+                        "<{}>",
+                        mimes.iter().map(|x| x.0).collect::<Vec<_>>().join(", ")
+                    ),
+                }
+            } else if let Some(table) = Table::new(kserd) {
+                table.text()
+            } else if let Some(histogram) = histogram(kserd) {
+                histogram
+            } else {
+                kserd.as_str_with_config(config)
+            }
+        }
+    }
+}
+
+/// The `(mime type, data)` renderings of an output implementing `Mime`.
+fn mime<'a>(kserd: &'a Kserd) -> Option<Vec<(&'a str, &'a str)>> {
+    let seq = match (kserd.id(), &kserd.val) {
+        (Some(MIME_ID), Value::Seq(x)) => x,
+        _ => return None,
+    };
+
+    seq.iter()
+        .map(|x| match &x.val {
+            Value::Tuple(x) if x.len() == 2 => match (&x[0].val, &x[1].val) {
+                (Value::Str(mime), Value::Str(data)) => Some((mime.as_str(), data.as_str())),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// A table cell, numbers are right aligned.
+struct Cell {
+    text: String,
+    num: bool,
+}
+
+impl Cell {
+    fn new(kserd: &Kserd) -> Self {
+        let text = match &kserd.val {
+            Value::Str(s) => s.as_str().to_string(),
+            _ => kserd.as_str_with_config(FormattingConfig {
+                width_limit: None,
+                ..Default::default()
+            }),
+        };
+        let num = matches!(kserd.val, Value::Num(_));
+
+        Cell { text, num }
+    }
+
+    fn width(&self) -> usize {
+        self.text.chars().count()
+    }
+}
+
+/// A sequence of containers with the same fields, or tuples of the same length.
+struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    fn new(kserd: &Kserd) -> Option<Self> {
+        let seq = match &kserd.val {
+            Value::Seq(x) if !x.is_empty() => x,
+            _ => return None,
+        };
+
+        let header: Vec<String> = match &seq[0].val {
+            Value::Cntr(x) if !x.is_empty() => x.keys().map(|k| k.as_str().to_string()).collect(),
+            Value::Tuple(x) if !x.is_empty() => (0..x.len()).map(|i| i.to_string()).collect(),
+            _ => return None,
+        };
+
+        let rows = seq
+            .iter()
+            .map(|row| match &row.val {
+                Value::Cntr(x)
+                    if x.len() == header.len()
+                        && x.keys().zip(&header).all(|(k, h)| k.as_str() == h) =>
+                {
+                    Some(x.values().map(Cell::new).collect())
+                }
+                // field names cannot be numbers, so a container header is never matched
+                Value::Tuple(x)
+                    if x.len() == header.len()
+                        && header.first().map(String::as_str) == Some("0") =>
+                {
+                    Some(x.iter().map(Cell::new).collect())
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Table { header, rows })
+    }
+
+    fn text(&self) -> String {
+        let widths = (0..self.header.len())
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .map(|row| row[i].width())
+                    .chain(Some(self.header[i].chars().count()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+
+        let mut lines = Vec::with_capacity(self.rows.len() + 2);
+        lines.push(
+            self.header
+                .iter()
+                .zip(&widths)
+                .map(|(h, w)| format!("{:<w$}", h, w = w))
+                .collect::<Vec<_>>()
+                .join(" | "),
+        );
+        lines.push(
+            widths
+                .iter()
+                .map(|w| "-".repeat(*w))
+                .collect::<Vec<_>>()
+                .join("-+-"),
+        );
+        for row in &self.rows {
+            lines.push(
+                row.iter()
+                    .zip(&widths)
+                    .map(|(c, w)| {
+                        if c.num {
+                            format!("{:>w$}", c.text, w = w)
+                        } else {
+                            format!("{:<w$}", c.text, w = w)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" | "),
+            );
+        }
+
+        lines
+            .iter()
+            .map(|x| x.trim_end())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn csv(&self) -> String {
+        fn field(s: &str) -> String {
+            if s.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s.to_string()
+            }
+        }
+
+        let header = self.header.iter().map(|h| field(h));
+        let rows = self.rows.iter().map(|row| {
+            row.iter()
+                .map(|c| field(&c.text))
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+
+        Some(header.collect::<Vec<_>>().join(","))
+            .into_iter()
+            .chain(rows)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn html(&self) -> String {
+        let mut html = String::from("<table>\n<thead><tr>");
+        for h in &self.header {
+            html.push_str(&format!("<th>{}</th>", escape_html(h)));
+        }
+        html.push_str("</tr></thead>\n<tbody>\n");
+        for row in &self.rows {
+            html.push_str("<tr>");
+            for c in row {
+                html.push_str(&format!("<td>{}</td>", escape_html(&c.text)));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</tbody>\n</table>");
+        html
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A map of non-negative numbers, rendered as a bar for each key.
+fn histogram(kserd: &Kserd) -> Option<String> {
+    let map = match &kserd.val {
+        Value::Map(x) if !x.is_empty() => x,
+        _ => return None,
+    };
+
+    let bars = map
+        .iter()
+        .map(|(k, v)| match &v.val {
+            Value::Num(n) if n.as_f64() >= 0.0 => Some((Cell::new(k), Cell::new(v), n.as_f64())),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let max = bars.iter().map(|x| x.2).fold(0.0, f64::max);
+    let width = bars.iter().map(|x| x.0.width()).max().unwrap_or(0);
+
+    let lines = bars
+        .iter()
+        .map(|(k, v, n)| {
+            let len = if max > 0.0 {
+                (n / max * BAR_WIDTH as f64).round() as usize
+            } else {
+                0
+            };
+            format!("{:<w$} | {} {}", k.text, "█".repeat(len), v.text, w = width)
+        })
+        .collect::<Vec<_>>();
+
+    Some(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Kserd<'static> {
+        ::kserd::parse::parse(s).unwrap().into_owned()
+    }
+
+    #[test]
+    fn table_test() {
+        let kserd = parse(
+            r#"[
+                (name = "apple", weight = 150)
+                (name = "watermelon", weight = 2000)
+            ]"#,
+        );
+
+        assert_eq!(
+            fmt_output(&kserd, Default::default()),
+            "name       | weight
+-----------+-------
+apple      |    150
+watermelon |   2000"
+        );
+
+        let renderings = render(&kserd);
+        let mimes = renderings
+            .iter()
+            .map(|x| x.mime.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(mimes, vec!["text/plain", "text/csv", "text/html"]);
+        assert_eq!(
+            renderings[1].data,
+            "name,weight\napple,150\nwatermelon,2000"
+        );
+        assert_eq!(
+            renderings[2].data,
+            "<table>
+<thead><tr><th>name</th><th>weight</th></tr></thead>
+<tbody>
+<tr><td>apple</td><td>150</td></tr>
+<tr><td>watermelon</td><td>2000</td></tr>
+</tbody>
+</table>"
+        );
+
+        let kserd = parse(r#"[ (1, "a, b"), (2, "c") ]"#);
+        let table = Table::new(&kserd).unwrap();
+        assert_eq!(table.text(), "0 | 1\n--+-----\n1 | a, b\n2 | c");
+        assert_eq!(table.csv(), "0,1\n1,\"a, b\"\n2,c");
+
+        // not tables
+        assert!(Table::new(&parse("[1, 2]")).is_none());
+        assert!(Table::new(&parse("[]")).is_none());
+        assert!(Table::new(&parse("[ (a = 1), (b = 1) ]")).is_none());
+        assert!(Table::new(&parse("[ (1, 2), (1, 2, 3) ]")).is_none());
+    }
+
+    #[test]
+    fn histogram_test() {
+        let kserd = parse(r#"{ "a": 4, "bb": 1, "c": 0 }"#);
+        assert_eq!(
+            fmt_output(&kserd, Default::default()),
+            format!(
+                "a  | {} 4\nbb | {} 1\nc  |  0",
+                "█".repeat(40),
+                "█".repeat(10)
+            )
+        );
+
+        assert_eq!(histogram(&parse(r#"{ "a": -1 }"#)), None);
+        assert_eq!(histogram(&parse(r#"{ "a": "b" }"#)), None);
+        assert_eq!(render(&kserd).len(), 1);
+    }
+
+    #[test]
+    fn fallbacks_test() {
+        let kserd = Kserd::with_id(DEBUG_ID, Value::Str("A(1)".into())).unwrap();
+        assert_eq!(fmt_output(&kserd, Default::default()), "A(1)");
+
+        let kserd = Kserd::with_id(TYPE_NAME_ID, Value::Str("lib::B".into())).unwrap();
+        assert_eq!(fmt_output(&kserd, Default::default()), "<lib::B>");
+
+        assert_eq!(
+            fmt_output(&Kserd::new_str("a"), Default::default()),
+            "\"a\""
+        );
+    }
+
+    #[test]
+    fn mime_test() {
+        let rendering = |mime: &'static str, data: &'static str| {
+            Kserd::new(Value::Tuple(vec![
+                Kserd::new_str(mime),
+                Kserd::new_str(data),
+            ]))
+        };
+
+        let kserd = Kserd::with_id(
+            MIME_ID,
+            Value::Seq(vec![
+                rendering("text/html", "<b>Hello</b>"),
+                rendering("text/plain", "Hello"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(fmt_output(&kserd, Default::default()), "Hello");
+        assert_eq!(
+            render(&kserd),
+            vec![
+                Rendering::new("text/plain", "Hello".to_string()),
+                Rendering::new("text/html", "<b>Hello</b>".to_string()),
+            ]
+        );
+
+        let kserd = Kserd::with_id(
+            MIME_ID,
+            Value::Seq(vec![rendering("text/html", "<b>Hello</b>")]),
+        )
+        .unwrap();
+        assert_eq!(fmt_output(&kserd, Default::default()), "<text/html>");
+    }
+}
@@ -2,7 +2,7 @@ use super::*;
 use crate::{
     cmds::{self, CommandResult},
     code::{self, Input, SourceCode, StmtGrp},
-    compile,
+    compile::{self, Diagnostic},
 };
 use std::borrow::{Borrow, BorrowMut};
 use std::ops::{Deref, DerefMut};
@@ -13,6 +13,13 @@ impl<D> Repl<Evaluate, D> {
     /// Evaluates the read input, compiling and executing the code and printing all line prints until
     /// a result is found. This result gets passed back as a print ready repl.
     pub fn eval(self, app_data: &mut D) -> EvalResult<D> {
+        self.data.cancel.reset();
+        self.eval_keep_cancel(app_data)
+    }
+
+    /// Same as `eval`, but a cancellation made before evaluating is kept rather than reset, such
+    /// that it cancels this evaluation.
+    pub(crate) fn eval_keep_cancel(self, app_data: &mut D) -> EvalResult<D> {
         use std::cell::Cell;
         use std::rc::Rc;
 
@@ -52,6 +59,9 @@ impl<D> Repl<Evaluate, D> {
 
         let clone = Arc::clone(app_data);
 
+        self.data.cancel.reset();
+        let cancel = self.data.cancel.clone();
+
         std::thread::spawn(move || {
             let eval = map_variants(
                 self,
@@ -62,7 +72,7 @@ impl<D> Repl<Evaluate, D> {
             tx.send(eval).unwrap();
         });
 
-        Evaluating { jh: rx }
+        Evaluating { jh: rx, cancel }
     }
 
     /// Begin listening to line change events on the output.
@@ -89,6 +99,15 @@ impl<D> Evaluating<D> {
         !self.jh.is_empty()
     }
 
+    /// Cancel the evaluation, the input is discarded and the REPL returns to the read state.
+    ///
+    /// A compilation in progress is killed, as is an out-of-process evaluation (see
+    /// [`ExecOptions`](crate::compile::ExecOptions)). Code evaluated in-process cannot be
+    /// interrupted once running, so the evaluation only finishes when the code returns.
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+
     /// Waits for the evaluating to finish before return the result.
     /// If evaluating is `completed` this will return immediately.
     pub fn wait(self) -> EvalResult<D> {
@@ -121,24 +140,30 @@ where
     let mut keep_mutating = false; // default to stop mutating phase
                                    // can't cancel before as handle program requires it for decisions
 
-    // map variants into Result<HandleInputResult, EvalSignal>
+    // map variants into Result<(EvalOutput, EvalOutcome), EvalSignal>
     let mapped = match result {
         InputResult::Command(cmds) => {
             let r = data.handle_command(&cmds, &mut output, obtain_mut_data);
             keep_mutating = data.linking.mutable; // a command can alter the mutating state, needs to persist
-            r.map(EvalOutput::Print)
+            r.map(|x| (EvalOutput::Print(x), EvalOutcome::Command))
         }
         InputResult::Program(input) => {
             Ok(data.handle_program(input, &mut output, obtain_mut_data, obtain_brw_data))
         }
-        InputResult::InputError(err) => Ok(EvalOutput::Print(Cow::Owned(err))),
+        InputResult::InputError(err) => Ok((
+            EvalOutput::Print(Cow::Owned(err.clone())),
+            EvalOutcome::ParseFailed(err),
+        )),
         InputResult::Eof => Err(Signal::Exit),
-        _ => Ok(EvalOutput::Print(Cow::Borrowed(""))),
+        _ => Ok((EvalOutput::Print(Cow::Borrowed("")), EvalOutcome::Empty)),
     };
 
-    let (eval_output, sig) = match mapped {
-        Ok(hir) => (hir, Signal::None),
-        Err(sig) => (EvalOutput::Print(Cow::Borrowed("")), sig),
+    let ((eval_output, outcome), sig) = match mapped {
+        Ok(x) => (x, Signal::None),
+        Err(sig) => (
+            (EvalOutput::Print(Cow::Borrowed("")), EvalOutcome::Empty),
+            sig,
+        ),
     };
 
     data.linking.mutable = keep_mutating; // always cancel a mutating block on evaluation??
@@ -146,8 +171,15 @@ where
                                           // this would have to be individually handled in each match arm and it, rather let the user
                                           // have to reinstate mutability if they fuck up input.
 
+    let renderings = match &outcome {
+        EvalOutcome::Ok(kserd) => display::render(kserd),
+        _ => Vec::new(),
+    };
+
     EvalResult {
         signal: sig,
+        outcome,
+        renderings,
         repl: Repl {
             state: Print {
                 output,
@@ -196,6 +228,7 @@ impl<D> ReplData<D> {
                         r
                     }
                 }
+                CommandResult::EditRemove(ei) => Cow::Owned(cmds::edit_remove(self, ei)),
                 CommandResult::SwitchModule(path) => {
                     Cow::Borrowed(crate::cmds::switch_module(self, &path))
                 }
@@ -221,7 +254,7 @@ impl<D> ReplData<D> {
         writer: &mut Output<output::Write>,
         obtain_mut_data: Fmut,
         obtain_brw_data: Fbrw,
-    ) -> EvalOutput
+    ) -> (EvalOutput, EvalOutcome)
     where
         Fmut: FnOnce() -> Rmut,
         Rmut: DerefMut<Target = D>,
@@ -300,34 +333,69 @@ impl<D> ReplData<D> {
             }
         };
 
-        // build directory
-        let res = compile::build_compile_dir(
-            &self.compilation_dir,
-            &self.mods_map,
-            &self.linking,
-            &self.static_files,
-        );
-        if let Err(e) = res {
-            maybe_pop_input(self); // failed so don't save
-            return EvalOutput::Print(Cow::Owned(format!(
-                "failed to build compile directory: {}",
-                e
-            )));
-        }
+        // persist the bindings if only appending statements
+        let (persist, vars) = if has_stmts && undo {
+            self.persisting()
+        } else {
+            (false, None)
+        };
 
-        // compile
-        let lib_file = compile::compile(&self.compilation_dir, &self.linking, |line| {
-            writer.erase_last_line();
-            writer.write_str(line);
-        });
+        let mut compiled = self.build_and_compile(persist, writer);
+        let mut persist_failed = false;
+        if persist {
+            if let Ok(Err(e)) = &compiled {
+                if from_persisting(e, self.current_mod(), stmt_idx) {
+                    // compile again without persisting, the bindings are evaluated again
+                    compiled = self.build_and_compile(false, writer);
+                    persist_failed = true;
+                }
+            }
+        }
+        let persist = persist && !persist_failed;
 
-        writer.erase_last_line();
+        let res = match compiled {
+            Ok(x) => x,
+            Err(e) => {
+                maybe_pop_input(self); // failed so don't save
+                return (
+                    EvalOutput::Print(Cow::Owned(format!(
+                        "failed to build compile directory: {}",
+                        e
+                    ))),
+                    EvalOutcome::BuildFailed(e),
+                );
+            }
+        };
 
-        let lib_file = match lib_file {
-            Ok(f) => f,
+        let lib_file = match res {
+            Ok((f, warnings)) => {
+                // only warn about the latest input, earlier inputs have been warned about
+                let cmod = self.current_mod();
+                for w in warnings {
+                    let latest = w
+                        .primary_span()
+                        .and_then(|x| x.origin.as_ref())
+                        .filter(|x| x.path == cmod)
+                        .map(|x| match x.kind {
+                            code::SpanKind::Stmt { grp, .. } => has_stmts && grp == stmt_idx,
+                            code::SpanKind::Item(i) => i >= item_idx && i < item_idx + nitems,
+                        })
+                        .unwrap_or(false);
+                    if latest {
+                        writer.write_str(&w.render());
+                    }
+                }
+                f
+            }
             Err(e) => {
+                // diagnostics are located before popping the input, they point to the new input
                 maybe_pop_input(self); // failed so don't save
-                return EvalOutput::Print(Cow::Owned(format!("{}", e)));
+                let print = EvalOutput::Print(Cow::Owned(format!("{}", e)));
+                let outcome = match e {
+                    CompilationError::Cancelled => EvalOutcome::Cancelled,
+                    e => EvalOutcome::CompileFailed(e),
+                };
+                return (print, outcome);
             }
         };
 
@@ -342,44 +410,175 @@ impl<D> ReplData<D> {
                 let mut fn_name = String::new();
                 code::eval_fn_name(&code::into_mod_path_vec(self.current_mod()), &mut fn_name);
 
+                // captured output is written to the output
+                let on_line = |line: &str| writer.write_line(line);
+
+                // app data cannot be passed to a worker process
+                let mut opts = self.exec_options.clone();
+                opts.out_of_process &= self.linking.data_type.is_none();
+
                 if self.linking.mutable {
                     let mut r = obtain_mut_data();
                     let app_data: &mut D = r.borrow_mut();
-                    compile::exec(&lib_file, &fn_name, app_data)
+                    compile::exec(
+                        &lib_file,
+                        &fn_name,
+                        app_data,
+                        None,
+                        &opts,
+                        &self.cancel,
+                        on_line,
+                    )
                 } else {
                     let r = obtain_brw_data();
                     let app_data: &D = r.borrow();
-                    compile::exec(&lib_file, &fn_name, app_data)
+                    compile::exec(
+                        &lib_file,
+                        &fn_name,
+                        app_data,
+                        vars.as_ref().filter(|_| persist),
+                        &opts,
+                        &self.cancel,
+                        on_line,
+                    )
                 }
             };
             match exec_res {
                 Ok((kserd, lib)) => {
                     // store vec, maybe
-                    add_to_limit_vec(
-                        &mut self.loadedlibs,
-                        Box::new(lib),
-                        self.loaded_libs_size_limit,
-                    );
+                    if let Some(lib) = lib {
+                        add_to_limit_vec(
+                            &mut self.loadedlibs,
+                            Box::new(lib),
+                            self.loaded_libs_size_limit,
+                        );
+                    }
 
-                    if self.linking.mutable {
+                    let kserd = if persist || persist_failed {
+                        let (kserd, vars) = split_persisted(kserd);
+                        self.snapshot(vars, persist_failed);
+                        kserd
+                    } else {
+                        kserd
+                    };
+
+                    let print = if self.linking.mutable {
                         maybe_pop_input(self); // don't save mutating inputs
-                        EvalOutput::Print(Cow::Owned(format!("finished mutating block: {}", kserd)))
+                        EvalOutput::Print(Cow::Owned(format!(
+                            "finished mutating block: {}",
+                            display::fmt_output(&kserd, Default::default())
+                        )))
                     // don't print as `out#`
                     } else {
-                        EvalOutput::Data(kserd)
-                    }
+                        if has_stmts {
+                            self.push_output(stmt_idx, kserd.clone());
+                        }
+                        EvalOutput::Data(kserd.clone())
+                    };
+                    (print, EvalOutcome::Ok(kserd))
                 }
                 Err(e) => {
                     maybe_pop_input(self); // failed so don't save
-                    EvalOutput::Print(Cow::Borrowed(e))
+                    let print = EvalOutput::Print(Cow::Owned(e.to_string()));
+                    let outcome = match e {
+                        ExecError::LoadLibrary | ExecError::MissingFunction => {
+                            EvalOutcome::LoadFailed(e)
+                        }
+                        ExecError::Panic(panic) => EvalOutcome::Panicked(panic),
+                        ExecError::Cancelled => EvalOutcome::Cancelled,
+                        e => EvalOutcome::ExecFailed(e),
+                    };
+                    (print, outcome)
                 }
             }
         } else {
             // this will keep inputs, might not be preferrable to do so in mutating state?
-            EvalOutput::Print(Cow::Borrowed("")) // do not execute if no extra statements have been added
+            // do not execute if no extra statements have been added
+            (EvalOutput::Print(Cow::Borrowed("")), EvalOutcome::Empty)
+        }
+    }
+
+    /// Whether to persist the current module's bindings when evaluating its last statement group,
+    /// and the bindings to restore if they were kept from evaluating the previous groups.
+    fn persisting(&self) -> (bool, Option<Kserd<'static>>) {
+        if !self.persist_bindings || self.linking.mutable {
+            return (false, None);
+        }
+
+        let stmts = src_lines(self.current_src());
+        let prev = &stmts[..stmts.len().saturating_sub(1)];
+        match self.persisted.get(self.current_mod()) {
+            Some(Snapshot { stmts, vars: None }) if prev.starts_with(stmts) => (false, None),
+            Some(Snapshot {
+                stmts,
+                vars: Some(vars),
+            }) if stmts.as_slice() == prev => (true, Some(vars.clone())),
+            _ => (true, None),
         }
     }
 
+    /// Keeps the bindings after evaluating the current module's statements, `vars` is `None` if
+    /// they could not be encoded. If persisting `failed` to compile, this is kept such that the
+    /// statements are evaluated again.
+    fn snapshot(&mut self, vars: Option<Kserd<'static>>, failed: bool) {
+        let cmod = self.current_mod.clone();
+        if vars.is_some() || failed {
+            let stmts = src_lines(self.current_src());
+            self.persisted.insert(cmod, Snapshot { stmts, vars });
+        } else {
+            self.persisted.remove(&cmod);
+        }
+    }
+
+    /// Builds the compilation directory and compiles, persisting the current module's bindings if
+    /// `persist`. Diagnostics are located in the source code.
+    fn build_and_compile(
+        &self,
+        persist: bool,
+        writer: &mut Output<output::Write>,
+    ) -> io::Result<Result<(PathBuf, Vec<Diagnostic>), CompilationError>> {
+        let persist = Some(self.current_mod()).filter(|_| persist);
+
+        compile::build_compile_dir_persisting(
+            &self.compilation_dir,
+            &self.mods_map,
+            &self.linking,
+            &self.static_files,
+            &self.compile_options,
+            persist,
+        )?;
+
+        let res = compile::compile_cancellable(
+            &self.compilation_dir,
+            &self.linking,
+            &self.compile_options,
+            &self.cancel,
+            |line| {
+                writer.erase_last_line();
+                writer.write_str(line);
+            },
+        );
+
+        writer.erase_last_line();
+
+        let source_map = code::construct_source_map_persisting(
+            &self.mods_map,
+            &self.linking,
+            &self.static_files,
+            persist,
+        );
+        Ok(match res {
+            Ok((f, mut warnings)) => {
+                warnings.iter_mut().for_each(|x| x.locate(&source_map));
+                Ok((f, warnings))
+            }
+            Err(mut e) => {
+                e.locate(&source_map);
+                Err(e)
+            }
+        })
+    }
+
     fn insert_input(&mut self, input: Input, stmt_idx: usize, item_idx: usize, crate_idx: usize) {
         let Input {
             items,
@@ -410,6 +609,58 @@ impl<D> ReplData<D> {
     }
 }
 
+/// The source lines of the statement groups.
+fn src_lines(src: &SourceCode) -> Vec<String> {
+    src.stmts.iter().map(StmtGrp::src_line).collect()
+}
+
+/// Whether compiling failed because of the code persisting the bindings, rather than the latest
+/// input (statement group `grp` of `module`).
+///
+/// Errors in generated code or earlier statements are from persisting, as are unresolved names in
+/// the latest statement group since a moved binding is not restored.
+fn from_persisting(e: &CompilationError, module: &Path, grp: usize) -> bool {
+    let diagnostics = match e {
+        CompilationError::Diagnostics(x) => x,
+        _ => return false,
+    };
+
+    diagnostics.iter().any(|d| {
+        let origin = match d.primary_span() {
+            Some(span) => span.origin.as_ref(),
+            None => return false, // such as the 'aborting due to' error
+        };
+        match origin {
+            None => true,
+            Some(x) if x.path != module => false,
+            Some(x) => match x.kind {
+                code::SpanKind::Stmt { grp: g, .. } if g == grp => {
+                    d.code.as_deref() == Some("E0425")
+                }
+                code::SpanKind::Stmt { .. } => true,
+                code::SpanKind::Item(_) => false,
+            },
+        }
+    })
+}
+
+/// Splits the value returned by a persisting evaluation function into the output and the
+/// bindings. The bindings are `None` if they could not be encoded.
+fn split_persisted(kserd: Kserd<'static>) -> (Kserd<'static>, Option<Kserd<'static>>) {
+    if kserd.id() != Some(code::PERSIST_ID) {
+        return (kserd, None);
+    }
+
+    match kserd.val {
+        kserd::Value::Tuple(mut v) if v.len() == 2 => {
+            let vars = v.pop();
+            let out = v.pop().expect("two elements");
+            (out, vars)
+        }
+        val => (Kserd::new(val), None),
+    }
+}
+
 fn add_to_limit_vec<T>(store: &mut VecDeque<T>, item: T, limit: usize) {
     match (limit, store.len()) {
         (0, 0) => (),             // do nothing, lib will drop after this
@@ -3,13 +3,23 @@
 //! The REPL uses a state machine to control what methods can be applied to it.
 mod any_state;
 mod data;
+mod display;
 mod eval;
+mod outputs;
 mod print;
 mod read;
+mod session;
+mod types;
+
+pub use display::{fmt_output, render, Rendering};
+pub(crate) use outputs::to_json;
+pub use outputs::{OutputError, OutputFormat};
+pub use session::{Session, SessionError};
 
 use crate::{
     cmds::CommandResult,
     code::{ModsMap, StaticFile, StaticFiles},
+    compile::{CancelToken, CompilationError, CompileOptions, EvalPanic, ExecError, ExecOptions},
     input::InputResult,
     linking::{self, LinkingConfiguration},
     output::{self, Output},
@@ -20,7 +30,7 @@ use crossbeam_channel::Receiver;
 use kserd::Kserd;
 use std::{
     borrow::Cow,
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     fmt, fs, io,
     marker::PhantomData,
     path::{Path, PathBuf},
@@ -64,6 +74,17 @@ pub struct ReplData<Data> {
     /// The external crate linking configuration,
     linking: LinkingConfiguration,
 
+    /// Options passed to `cargo` when compiling, such as compiling offline or from vendored
+    /// crates. See [`CompileOptions`](crate::compile::CompileOptions).
+    pub compile_options: CompileOptions,
+
+    /// Options for executing the compiled code, such as evaluating in a worker process.
+    /// See [`ExecOptions`](crate::compile::ExecOptions).
+    pub exec_options: ExecOptions,
+
+    /// Cancels the current evaluation, reset at the beginning of each evaluation.
+    pub(crate) cancel: CancelToken,
+
     /// Flag for editing a statement, item, or crate.
     ///
     /// If a value is set when an evaluation starts, the input buffer
@@ -99,6 +120,50 @@ pub struct ReplData<Data> {
     /// The default is to keep the size limit at zero, thus ensuring no libraries are kept in
     /// memory. This is recommended unless issues are arising from esoteric use cases.
     pub loaded_libs_size_limit: usize,
+
+    /// The maximum number of input history entries kept when running in a terminal.
+    ///
+    /// History is persisted to a `history` file in the compilation directory, such that it is
+    /// available across sessions. Setting the size to zero disables history. Defaults to 1000.
+    pub history_size: usize,
+
+    /// Persist the bindings of evaluated statements, such that evaluating an input does not
+    /// evaluate all the previous statements again (and repeat their side effects).
+    ///
+    /// After each evaluation the bindings in scope are encoded and kept. The next input restores
+    /// them and only evaluates its own statements. Bindings are encoded with their `serde`
+    /// implementations, if any binding cannot be encoded or decoded (such as a reference, closure,
+    /// or a type without `serde` implementations) the previous statements are evaluated again.
+    /// Previous statements are also evaluated again in mutable blocks, when editing, and if a
+    /// binding has been moved.
+    ///
+    /// A restored binding is a copy made by a `serde` round trip, rather than the value the
+    /// previous statements produced. Fields marked `#[serde(skip)]` are reset to their default,
+    /// and values which depend on their address or on process state (such as a file handle, an
+    /// `Rc` shared between bindings, or a counter in a static) are not the same after restoring.
+    /// Defaults to `false`, the `persist` command and the `--persist` argument of the binary turn
+    /// it on.
+    pub persist_bindings: bool,
+    /// The bindings kept for each module.
+    persisted: HashMap<PathBuf, Snapshot>,
+
+    /// The maximum number of results kept for each module.
+    ///
+    /// Results are kept such that they can be printed again (`:out #`) or saved to a file
+    /// (`:save-out #`), see [`output`](ReplData::output). Setting the size to zero disables the
+    /// history. Defaults to 100.
+    pub output_history_size: usize,
+    /// The results kept for each module, the newest at the front.
+    outputs: HashMap<PathBuf, VecDeque<(usize, Kserd<'static>)>>,
+}
+
+/// The bindings in scope after evaluating a module's statements.
+struct Snapshot {
+    /// The source lines of the statement groups evaluated.
+    stmts: Vec<String>,
+    /// The encoded bindings. `None` if the statements could not be compiled persisting bindings,
+    /// in which case statements are evaluated again until these statements change.
+    vars: Option<Kserd<'static>>,
 }
 
 /// Repl read state.
@@ -117,6 +182,7 @@ pub struct Evaluate {
 /// Repl evaluating state. This can be constructed via a `eval_async` call.
 pub struct Evaluating<D> {
     jh: Receiver<EvalResult<D>>,
+    cancel: CancelToken,
 }
 
 /// Repl print state.
@@ -140,6 +206,44 @@ pub struct EvalResult<D> {
     pub repl: Repl<Print, D>,
     /// The signal, if any.
     pub signal: Signal,
+    /// What happened evaluating the input. The text describing the outcome is written when
+    /// [`print`](Repl::print)ing.
+    pub outcome: EvalOutcome,
+    /// The renderings of the evaluated data, for front-ends which can display more than text.
+    /// Empty if no data was evaluated. See [`render`].
+    pub renderings: Vec<Rendering>,
+}
+
+/// The outcome of evaluating an input.
+///
+/// Failed inputs are not kept in the REPL's source code.
+#[derive(Debug)]
+pub enum EvalOutcome {
+    /// Nothing was evaluated, such as an empty input or an input of only items or crates.
+    Empty,
+    /// A command was run.
+    Command,
+    /// The statements evaluated to the data. A mutable block's data is not printed as `[out#]`.
+    ///
+    /// Data which does not implement `ToKserd` is a string of its `Debug` formatting or type name,
+    /// see [`fmt_output`].
+    Ok(Kserd<'static>),
+    /// The input could not be parsed, the message describes why.
+    ParseFailed(String),
+    /// Writing the compilation directory failed.
+    BuildFailed(io::Error),
+    /// The code failed to compile.
+    ///
+    /// Compiler errors are in [`CompilationError::Diagnostics`].
+    CompileFailed(CompilationError),
+    /// The compiled library could not be loaded, or the evaluation function was not found in it.
+    LoadFailed(ExecError),
+    /// The evaluated code panicked.
+    Panicked(EvalPanic),
+    /// The compilation or evaluation was cancelled, see [`Evaluating::cancel`].
+    Cancelled,
+    /// Evaluation failed otherwise, such as the worker process crashing or timing out.
+    ExecFailed(ExecError),
 }
 
 /// Return signals from evaluating.
@@ -193,7 +297,7 @@ pub enum Editing {
 }
 
 /// `$HOME/.papyrus`
-fn default_compile_dir() -> PathBuf {
+pub(crate) fn default_compile_dir() -> PathBuf {
     dirs::home_dir().unwrap_or_default().join(".papyrus/")
 }
 
@@ -0,0 +1,257 @@
+use super::*;
+use kserd::Value;
+use serde_json::{Map, Number, Value as Json};
+use std::{convert::TryFrom, error, str};
+
+impl<Data> ReplData<Data> {
+    /// The result of the statement `n` in the current module, if it is kept in the history.
+    ///
+    /// Each successful evaluation keeps its result under the statement number it is printed as
+    /// (`[out#]`). Only the latest [`output_history_size`](ReplData::output_history_size) results
+    /// of each module are kept.
+    pub fn output(&self, n: usize) -> Option<&Kserd<'static>> {
+        self.outputs
+            .get(self.current_mod())
+            .and_then(|outs| outs.iter().find(|x| x.0 == n))
+            .map(|x| &x.1)
+    }
+
+    /// Write the result of the statement `n` in the current module to the file at `path`.
+    ///
+    /// See [`OutputFormat`](OutputFormat) for the supported formats.
+    pub fn save_output<P: AsRef<Path>>(
+        &self,
+        n: usize,
+        path: P,
+        format: OutputFormat,
+    ) -> Result<(), OutputError> {
+        let kserd = self.output(n).ok_or(OutputError::Missing(n))?;
+
+        let s = match format {
+            OutputFormat::Kserd => kserd.as_str(),
+            OutputFormat::Json => {
+                serde_json::to_string_pretty(&to_json(kserd)).map_err(OutputError::Json)?
+            }
+            OutputFormat::Toml => match to_json(kserd) {
+                // a toml document is a table
+                json @ Json::Object(_) => {
+                    toml::to_string_pretty(&json).map_err(|e| OutputError::Toml(e.to_string()))?
+                }
+                _ => return Err(OutputError::Toml("expecting a struct or map".to_string())),
+            },
+        };
+
+        fs::write(path, s).map_err(OutputError::Io)
+    }
+
+    /// Keep `kserd` as the result of statement `n` in the current module, replacing any previous
+    /// result of `n`. The newest results are at the front.
+    pub(crate) fn push_output(&mut self, n: usize, kserd: Kserd<'static>) {
+        let limit = self.output_history_size;
+        let outs = self.outputs.entry(self.current_mod.clone()).or_default();
+
+        outs.retain(|x| x.0 != n);
+        if limit > 0 {
+            outs.truncate(limit - 1);
+            outs.push_front((n, kserd));
+        } else {
+            outs.clear();
+        }
+    }
+
+    /// Remove the result history of the modules matching `pred`.
+    pub(crate) fn clear_outputs<F: FnMut(&Path) -> bool>(&mut self, mut pred: F) {
+        self.outputs.retain(|path, _| !pred(path));
+    }
+}
+
+/// The file format used when saving a result.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    /// The `kserd` text format, which keeps the type identifiers.
+    Kserd,
+    /// JSON. Maps with non-string keys are written with the keys formatted as strings.
+    Json,
+    /// TOML. The result must be a struct or a map, and cannot contain unit values.
+    Toml,
+}
+
+impl OutputFormat {
+    /// Infer the format from the extension of `path`, defaulting to `Kserd`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        path.as_ref()
+            .extension()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(OutputFormat::Kserd)
+    }
+}
+
+impl str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kserd" => Ok(OutputFormat::Kserd),
+            "json" => Ok(OutputFormat::Json),
+            "toml" => Ok(OutputFormat::Toml),
+            s => Err(format!(
+                "unknown format `{}`, expecting json, toml, or kserd",
+                s
+            )),
+        }
+    }
+}
+
+/// Error type for saving results.
+#[derive(Debug)]
+pub enum OutputError {
+    /// There is no result for the statement number in the history.
+    Missing(usize),
+    /// IO error.
+    Io(io::Error),
+    /// Failed to format as JSON.
+    Json(serde_json::Error),
+    /// Failed to format as TOML.
+    Toml(String),
+}
+
+impl error::Error for OutputError {}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::Missing(n) => write!(f, "no result for out{} in the history", n),
+            OutputError::Io(e) => write!(f, "io error occurred: {}", e),
+            OutputError::Json(e) => write!(f, "json format error: {}", e),
+            OutputError::Toml(e) => write!(f, "toml format error: {}", e),
+        }
+    }
+}
+
+/// Converts to a JSON value, which is also used to serialize TOML.
+pub(crate) fn to_json(kserd: &Kserd) -> Json {
+    match &kserd.val {
+        Value::Unit => Json::Null,
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Num(n) => {
+            let int = match *n {
+                kserd::Number::Uint(x) => u64::try_from(x).ok().map(Json::from),
+                kserd::Number::Int(x) => i64::try_from(x).ok().map(Json::from),
+                kserd::Number::Float(_) => None,
+            };
+            int.or_else(|| Number::from_f64(n.as_f64()).map(Json::Number))
+                .unwrap_or(Json::Null)
+        }
+        Value::Str(s) => Json::String(s.as_str().to_string()),
+        Value::Barr(b) => Json::Array(b.iter().map(|&x| Json::from(x)).collect()),
+        Value::Tuple(v) | Value::Seq(v) => Json::Array(v.iter().map(to_json).collect()),
+        Value::Cntr(fields) => Json::Object(
+            fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), to_json(v)))
+                .collect(),
+        ),
+        Value::Map(map) => Json::Object(
+            map.iter()
+                .map(|(k, v)| {
+                    let key = match &k.val {
+                        Value::Str(s) => s.as_str().to_string(),
+                        _ => k.as_str(),
+                    };
+                    (key, to_json(v))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn output_history_test() {
+        let mut data: ReplData<()> = ReplData {
+            output_history_size: 2,
+            ..Default::default()
+        };
+        data.push_output(0, Kserd::new_num(0));
+        data.push_output(1, Kserd::new_num(1));
+        data.push_output(1, Kserd::new_num(10)); // replaces
+        assert_eq!(data.output(1), Some(&Kserd::new_num(10)));
+        assert_eq!(data.output(0), Some(&Kserd::new_num(0)));
+
+        data.push_output(2, Kserd::new_num(2)); // drops oldest
+        assert_eq!(data.output(0), None);
+        assert_eq!(data.output(2), Some(&Kserd::new_num(2)));
+
+        data.current_mod = PathBuf::from("lib/a");
+        assert_eq!(data.output(2), None);
+
+        data.current_mod = PathBuf::from("lib");
+        data.clear_outputs(|p| p == Path::new("lib"));
+        assert_eq!(data.output(2), None);
+    }
+
+    #[test]
+    fn output_format_test() {
+        assert_eq!(OutputFormat::from_path("a.json"), OutputFormat::Json);
+        assert_eq!(OutputFormat::from_path("a/b.toml"), OutputFormat::Toml);
+        assert_eq!(OutputFormat::from_path("a.txt"), OutputFormat::Kserd);
+        assert_eq!(OutputFormat::from_path("a"), OutputFormat::Kserd);
+        assert_eq!("json".parse(), Ok(OutputFormat::Json));
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn to_json_test() {
+        let mut map = BTreeMap::new();
+        map.insert(Kserd::new_num(1), Kserd::new_str("one"));
+        let kserd = Kserd::new_cntr(vec![
+            ("a", Kserd::new_num(-1)),
+            ("b", Kserd::new_num(1.5)),
+            ("c", Kserd::new(Value::Seq(vec![Kserd::new_bool(true)]))),
+            ("d", Kserd::new_map(map)),
+            ("e", Kserd::new_unit()),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            to_json(&kserd).to_string(),
+            r#"{"a":-1,"b":1.5,"c":[true],"d":{"1":"one"},"e":null}"#
+        );
+    }
+
+    #[test]
+    fn save_output_test() {
+        let dir = PathBuf::from("./target/save-output-test");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut data: ReplData<()> = ReplData::default();
+        let kserd = Kserd::new_cntr(vec![("a", Kserd::new_num(1))]).unwrap();
+        data.push_output(3, kserd);
+
+        data.save_output(3, dir.join("out.json"), OutputFormat::Json)
+            .unwrap();
+        let s = fs::read_to_string(dir.join("out.json")).unwrap();
+        assert_eq!(s, "{\n  \"a\": 1\n}");
+
+        data.save_output(3, dir.join("out.toml"), OutputFormat::Toml)
+            .unwrap();
+        let s = fs::read_to_string(dir.join("out.toml")).unwrap();
+        assert_eq!(s, "a = 1\n");
+
+        data.push_output(4, Kserd::new_num(4));
+        match data.save_output(4, dir.join("out.toml"), OutputFormat::Toml) {
+            Err(OutputError::Toml(_)) => (),
+            _ => panic!("expecting toml error"),
+        }
+
+        match data.save_output(0, dir.join("out.kserd"), OutputFormat::Kserd) {
+            Err(OutputError::Missing(0)) => (),
+            _ => panic!("expecting missing error"),
+        }
+    }
+}
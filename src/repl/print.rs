@@ -39,7 +39,7 @@ impl<D> Repl<Print, D> {
                     "{} {}: {}",
                     repl_data.cmdtree.path().color(repl_data.prompt_colour),
                     out_stmt.color(repl_data.out_colour),
-                    k.as_str_with_config(config)
+                    fmt_output(&k, config)
                 );
 
                 output.write_line(&line);
@@ -67,6 +67,18 @@ impl<D> Repl<Print, D> {
 
         (r, kserd)
     }
+
+    /// Begin listening to line change events on the output.
+    pub fn output_listen(&mut self) -> output::Receiver {
+        self.state.output.listen()
+    }
+
+    /// Close the sender side of the output channel.
+    ///
+    /// Closing the channel before printing stops the printed result being sent.
+    pub fn close_channel(&mut self) {
+        self.state.output.close()
+    }
 }
 
 fn prepare_read<D>(repl: &mut Repl<Read, D>) {
@@ -0,0 +1,209 @@
+use super::*;
+use crate::code::{validate_static_file_path, AddingStaticFileError, SourceCode};
+use serde::{Deserialize, Serialize};
+use std::{error, io::Write};
+
+/// A portable snapshot of the REPL session.
+///
+/// A session captures the source code of every module, the current module, the paths of the
+/// imported static files, and the persistent module code. It is stored on disk as JSON, which
+/// allows long running sessions to be resumed later or shared.
+///
+/// Static files are stored as paths, _relative to the working directory_. When a session is
+/// loaded, each static file is read from disk and imported again.
+///
+/// # Example
+/// ```rust,no_run
+/// # use papyrus::repl::ReplData;
+/// let data: ReplData<()> = ReplData::default();
+/// data.save_session("session.json").unwrap();
+///
+/// let mut data: ReplData<()> = ReplData::default();
+/// data.load_session("session.json").unwrap();
+/// ```
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// The modules map of relative paths.
+    pub mods_map: ModsMap,
+    /// The current editing and executing mod.
+    pub current_mod: PathBuf,
+    /// The static file paths.
+    pub static_files: Vec<PathBuf>,
+    /// The persistent module code.
+    pub persistent_module_code: String,
+}
+
+impl Session {
+    /// Write the session as JSON.
+    pub fn write<W: Write>(&self, wtr: W) -> Result<(), SessionError> {
+        serde_json::to_writer_pretty(wtr, self).map_err(SessionError::Format)
+    }
+
+    /// Read a session from JSON.
+    pub fn read<R: io::Read>(rdr: R) -> Result<Self, SessionError> {
+        serde_json::from_reader(rdr).map_err(SessionError::Format)
+    }
+}
+
+impl<Data> ReplData<Data> {
+    /// Take a snapshot of the current session.
+    pub fn session(&self) -> Session {
+        Session {
+            mods_map: self.mods_map.clone(),
+            current_mod: self.current_mod.clone(),
+            static_files: self.static_files.iter().map(|x| x.path.clone()).collect(),
+            persistent_module_code: self.linking.persistent_module_code.clone(),
+        }
+    }
+
+    /// Replace the current session with `session`.
+    ///
+    /// The static files are read from disk _before_ any change is made, if any fail to be read
+    /// the error is returned and the current session is left untouched.
+    pub fn restore_session(&mut self, session: Session) -> Result<(), SessionError> {
+        let Session {
+            mut mods_map,
+            current_mod,
+            static_files,
+            persistent_module_code,
+        } = session;
+
+        let static_files = static_files
+            .into_iter()
+            .map(|path| {
+                validate_static_file_path(&path)
+                    .map_err(AddingStaticFileError::InvalidPath)
+                    .and_then(|_| fs::read_to_string(&path).map_err(AddingStaticFileError::Io))
+                    .map(|code| (path.clone(), code))
+                    .map_err(|e| SessionError::StaticFile(path, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        mods_map
+            .entry(PathBuf::from("lib"))
+            .or_insert_with(SourceCode::default);
+        mods_map
+            .entry(current_mod.clone())
+            .or_insert_with(SourceCode::default);
+
+        self.mods_map = mods_map;
+        self.current_mod = current_mod;
+        self.linking.persistent_module_code = persistent_module_code;
+        self.editing = None;
+        self.editing_src = None;
+        self.clear_outputs(|_| true);
+
+        let existing = self
+            .static_files
+            .iter()
+            .map(|x| x.path.clone())
+            .collect::<Vec<_>>();
+        for path in existing {
+            self.remove_static_file(path);
+        }
+
+        for (path, code) in static_files {
+            self.add_static_file(path.clone(), &code)
+                .map_err(|e| SessionError::StaticFile(path, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Save the current session to the file at `path`.
+    ///
+    /// See [`Session`](Session).
+    pub fn save_session<P: AsRef<Path>>(&self, path: P) -> Result<(), SessionError> {
+        let file = fs::File::create(path).map_err(SessionError::Io)?;
+        self.session().write(io::BufWriter::new(file))
+    }
+
+    /// Load the session saved in the file at `path`, replacing the current session.
+    ///
+    /// See [`Session`](Session).
+    pub fn load_session<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SessionError> {
+        let file = fs::File::open(path).map_err(SessionError::Io)?;
+        let session = Session::read(io::BufReader::new(file))?;
+        self.restore_session(session)
+    }
+}
+
+/// Error type for saving and loading sessions.
+#[derive(Debug)]
+pub enum SessionError {
+    /// IO error.
+    Io(io::Error),
+    /// The session file is not valid.
+    Format(serde_json::Error),
+    /// A static file failed to import.
+    StaticFile(PathBuf, AddingStaticFileError),
+}
+
+impl error::Error for SessionError {}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "io error occurred: {}", e),
+            SessionError::Format(e) => write!(f, "session format error: {}", e),
+            SessionError::StaticFile(p, e) => {
+                write!(f, "failed to import static file `{}`: {}", p.display(), e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{Statement, StmtGrp};
+
+    #[test]
+    fn session_roundtrip_test() {
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir("./target/session-roundtrip-test")
+            .unwrap();
+        data.mods_map
+            .get_mut(Path::new("lib"))
+            .unwrap()
+            .stmts
+            .push(StmtGrp(vec![Statement {
+                expr: "2 + 2".to_string(),
+                semi: false,
+            }]));
+        data.mods_map
+            .insert(PathBuf::from("lib/a"), SourceCode::default());
+        data.current_mod = PathBuf::from("lib/a");
+        data.persistent_module_code()
+            .push_str("use std::collections::*;");
+
+        let mut buf = Vec::new();
+        data.session().write(&mut buf).unwrap();
+        let session = Session::read(buf.as_slice()).unwrap();
+
+        let mut data: ReplData<()> = ReplData::default();
+        data.restore_session(session).unwrap();
+        assert_eq!(data.current_mod(), Path::new("lib/a"));
+        assert_eq!(data.mods_map().len(), 2);
+        assert_eq!(
+            data.mods_map()[Path::new("lib")].stmts[0].src_line(),
+            "2 + 2"
+        );
+        assert_eq!(data.persistent_module_code(), "use std::collections::*;");
+    }
+
+    #[test]
+    fn session_missing_static_file_test() {
+        let mut session = ReplData::<()>::default().session();
+        session.static_files.push(PathBuf::from("not_a_file.rs"));
+        session.current_mod = PathBuf::from("lib/b");
+
+        let mut data: ReplData<()> = ReplData::default();
+        match data.restore_session(session) {
+            Err(SessionError::StaticFile(p, _)) => assert_eq!(p, Path::new("not_a_file.rs")),
+            _ => panic!("expecting static file error"),
+        }
+        // left untouched
+        assert_eq!(data.current_mod(), Path::new("lib"));
+    }
+}
@@ -0,0 +1,151 @@
+//! Inferring the types of expressions using the compiler.
+//!
+//! Each expression is probed by compiling it after the current module's statements as
+//! `let _: () = &(expr);`. Unless the expression is `()` this fails to compile, and the mismatched
+//! types error names the expression's type.
+use super::*;
+use crate::{
+    code::{self, SpanKind, Statement, StmtGrp},
+    compile::{self, Diagnostic},
+};
+
+impl<D> ReplData<D> {
+    /// The names bound by the current module's statements, including the `out#` bindings, along
+    /// with their types. The types are inferred by compiling the code, see
+    /// [`SourceCode::bindings`](crate::code::SourceCode::bindings).
+    ///
+    /// Numeric literals which are not constrained have their type written as `{integer}` or
+    /// `{float}`.
+    pub fn variables(&self) -> Result<Vec<(String, String)>, CompilationError> {
+        let names = self.current_src().bindings();
+        let types = self.probe_types(&names)?;
+        Ok(names.into_iter().zip(types).collect())
+    }
+
+    /// The type of `expr`, inferred by compiling it in the context of the current module. The
+    /// expression is not evaluated, and is not added to the REPL's source code.
+    pub fn type_of(&self, expr: &str) -> Result<String, CompilationError> {
+        self.probe_types(&[expr])
+            .map(|mut x| x.pop().expect("one expression probed"))
+    }
+
+    /// Compiles the `exprs` in the context of the current module, returning their types.
+    ///
+    /// The REPL's source code is not altered. Errors which are not from the probes are returned as
+    /// `CompilationError::Diagnostics`.
+    fn probe_types<S: AsRef<str>>(&self, exprs: &[S]) -> Result<Vec<String>, CompilationError> {
+        if exprs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut mods_map = self.mods_map.clone();
+        let src = mods_map
+            .get_mut(self.current_mod())
+            .expect("current module should exist");
+        let grp = src.stmts.len();
+        let mut stmts = exprs
+            .iter()
+            .map(|x| Statement {
+                expr: format!("let _: () = &({})", x.as_ref()),
+                semi: true,
+            })
+            .collect::<Vec<_>>();
+        stmts.push(Statement {
+            expr: "()".to_string(),
+            semi: false,
+        });
+        src.stmts.push(StmtGrp(stmts));
+
+        compile::build_compile_dir_with_options(
+            &self.compilation_dir,
+            &mods_map,
+            &self.linking,
+            &self.static_files,
+            &self.compile_options,
+        )
+        .map_err(CompilationError::IOError)?;
+
+        let diagnostics = match compile::compile_cancellable(
+            &self.compilation_dir,
+            &self.linking,
+            &self.compile_options,
+            &self.cancel,
+            |_| (),
+        ) {
+            // all the expressions are ()
+            Ok(_) => Vec::new(),
+            Err(CompilationError::Diagnostics(diagnostics)) => diagnostics,
+            Err(e) => return Err(e),
+        };
+
+        let source_map = code::construct_source_map(&mods_map, &self.linking, &self.static_files);
+        let mut types = vec![String::from("()"); exprs.len()];
+        let mut errors = Vec::new();
+        for mut d in diagnostics {
+            d.locate(&source_map);
+            match probed(&d, self.current_mod(), grp) {
+                Some((idx, ty)) if idx < types.len() => types[idx] = ty,
+                _ => errors.push(d),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(types)
+        } else {
+            Err(CompilationError::Diagnostics(errors))
+        }
+    }
+}
+
+/// If the diagnostic is the mismatched types error of a probe, returns the probe index and type.
+fn probed(d: &Diagnostic, module: &Path, grp: usize) -> Option<(usize, String)> {
+    if d.code.as_deref() != Some("E0308") {
+        return None;
+    }
+
+    let span = d.primary_span()?;
+    let idx = span
+        .origin
+        .as_ref()
+        .filter(|x| x.path == module)
+        .and_then(|x| match x.kind {
+            SpanKind::Stmt { grp: g, stmt } if g == grp => Some(stmt),
+            _ => None,
+        })?;
+
+    span.label
+        .as_deref()
+        .and_then(found_type)
+        .map(|ty| (idx, ty.to_string()))
+}
+
+/// Reads the type from a label such as ``expected `()`, found `&Vec<u8>` ``. The reference added
+/// by the probe is removed.
+fn found_type(label: &str) -> Option<&str> {
+    let i = label.find(", found ")?;
+    let ty = label[i + 8..].trim_matches('`');
+    let mut chars = ty.chars();
+    Some(match chars.next() {
+        Some('&') => chars.as_str(),
+        _ => ty,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn found_type_test() {
+        assert_eq!(
+            found_type("expected `()`, found `&Vec<u8>`"),
+            Some("Vec<u8>")
+        );
+        assert_eq!(found_type("expected `()`, found `&&str`"), Some("&str"));
+        assert_eq!(
+            found_type("expected `()`, found `&({float}, String)`"),
+            Some("({float}, String)")
+        );
+        assert_eq!(found_type("expected due to this"), None);
+    }
+}
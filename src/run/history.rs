@@ -0,0 +1,164 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Input history, deduplicated and limited in size.
+///
+/// History is persisted as one entry per line, with newlines and backslashes escaped.
+pub struct History {
+    entries: VecDeque<String>,
+    limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            limit,
+        }
+    }
+
+    /// Loads history from `path`, if the file does not exist the history is empty.
+    pub fn load<P: AsRef<Path>>(path: P, limit: usize) -> Self {
+        let mut history = Self::new(limit);
+        if let Ok(s) = fs::read_to_string(path) {
+            for line in s.lines() {
+                history.push(unescape(line));
+            }
+        }
+        history
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for entry in &self.entries {
+            writeln!(file, "{}", escape(entry))?;
+        }
+        file.flush()
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Index zero is the oldest entry.
+    pub fn get(&self, idx: usize) -> Option<&str> {
+        self.entries.get(idx).map(|x| x.as_str())
+    }
+
+    /// Push an entry as the newest item. Blank entries are ignored, and an existing identical entry
+    /// is removed. The oldest entries are dropped to keep within the size limit.
+    pub fn push(&mut self, entry: String) {
+        if entry.trim().is_empty() {
+            return;
+        }
+
+        if let Some(idx) = self.entries.iter().position(|x| x == &entry) {
+            self.entries.remove(idx);
+        }
+        self.entries.push_back(entry);
+
+        while self.entries.len() > self.limit {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Search backwards for an entry containing `query`, beginning at the entry _before_ `before`.
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        let before = std::cmp::min(before, self.entries.len());
+        (0..before)
+            .rev()
+            .find(|&idx| self.entries[idx].contains(query))
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut buf = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => buf.push('\n'),
+                Some(c) => buf.push(c),
+                None => buf.push('\\'),
+            }
+        } else {
+            buf.push(c);
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_push_test() {
+        let mut h = History::new(3);
+        h.push("a".to_string());
+        h.push("  ".to_string());
+        h.push("b".to_string());
+        h.push("a".to_string());
+        assert_eq!(h.len(), 2);
+        assert_eq!(h.get(0), Some("b"));
+        assert_eq!(h.get(1), Some("a"));
+
+        h.push("c".to_string());
+        h.push("d".to_string());
+        assert_eq!(h.len(), 3);
+        assert_eq!(h.get(0), Some("a"));
+        assert_eq!(h.get(2), Some("d"));
+
+        let mut h = History::new(0);
+        h.push("a".to_string());
+        assert_eq!(h.len(), 0);
+    }
+
+    #[test]
+    fn history_search_test() {
+        let mut h = History::new(10);
+        h.push("let apple = 1;".to_string());
+        h.push("let banana = 2;".to_string());
+        h.push("apple + banana".to_string());
+
+        assert_eq!(h.search("apple", h.len()), Some(2));
+        assert_eq!(h.search("apple", 2), Some(0));
+        assert_eq!(h.search("apple", 0), None);
+        assert_eq!(h.search("cherry", h.len()), None);
+        assert_eq!(h.search("", 100), Some(2));
+    }
+
+    #[test]
+    fn history_persistence_test() {
+        let path = "target/testing/history_persistence_test";
+        let mut h = History::new(10);
+        h.push("fn a() {\n    \"\\n\"\n}".to_string());
+        h.push("2 + 2".to_string());
+        h.save(path).unwrap();
+
+        let h = History::load(path, 10);
+        assert_eq!(h.len(), 2);
+        assert_eq!(h.get(0), Some("fn a() {\n    \"\\n\"\n}"));
+        assert_eq!(h.get(1), Some("2 + 2"));
+
+        let h = History::load(path, 1);
+        assert_eq!(h.len(), 1);
+        assert_eq!(h.get(0), Some("2 + 2"));
+
+        let h = History::load("target/testing/no-history-here", 10);
+        assert_eq!(h.len(), 0);
+    }
+}
@@ -1,11 +1,13 @@
+use super::history::History;
 use super::map_xterm_err;
+use crate::compile::CancelToken;
 use crate::output::OutputChange;
 use crossbeam_channel::{unbounded, Receiver};
 use crossterm as xterm;
 use std::{
-    collections::VecDeque,
     fmt,
     io::{self, stdout, Stdout, Write},
+    sync::{Arc, Mutex},
 };
 use xterm::{
     cursor::*,
@@ -21,35 +23,48 @@ use xterm::{
 
 const TAB_WIDTH: usize = 8;
 
-pub struct Screen(pub(super) Receiver<Event>);
+/// What `Ctrl+C` acts on, shared with the terminal event thread.
+pub type Interrupt = Arc<Mutex<Interruption>>;
+
+#[derive(Default)]
+pub struct Interruption {
+    /// The evaluation which `Ctrl+C` cancels, if evaluating.
+    cancel: Option<CancelToken>,
+    /// `Ctrl+C` was pressed again after cancelling the evaluation.
+    exit: bool,
+}
+
+pub struct Screen(pub(super) Receiver<Event>, pub(super) Interrupt);
 
 impl Screen {
     pub fn new() -> io::Result<Self> {
         let (tx, rx) = unbounded();
+        let interrupt = Interrupt::default();
+        let int = Arc::clone(&interrupt);
         std::thread::Builder::new()
             .name("terminal-event-buffer".into())
             .spawn(move || loop {
                 match xterm::event::poll(std::time::Duration::from_millis(5)) {
-                    Ok(true) => {
-                        if xterm::event::read()
-                            .ok()
-                            .and_then(|ev| tx.send(ev).ok())
-                            .is_none()
-                        {
-                            break;
+                    Ok(true) => match xterm::event::read() {
+                        Ok(ev) if interrupted(&ev, &int) => (),
+                        Ok(ev) => {
+                            if tx.send(ev).is_err() {
+                                break;
+                            }
                         }
-                    }
+                        Err(_) => break,
+                    },
                     Ok(false) => {}
                     Err(_) => break,
                 }
             })?;
-        Ok(Screen(rx))
+        Ok(Screen(rx, interrupt))
     }
 
     pub fn begin_interface_input<'a>(
         &'a mut self,
         preallocated_buf: &'a mut InputBuffer,
-        history: &'a mut VecDeque<String>,
+        history: &'a mut History,
     ) -> XResult<Interface<'a>> {
         enable_raw_mode()?;
         preallocated_buf.clear();
@@ -62,20 +77,64 @@ impl Screen {
             prompt_len: 0,
             history,
             history_pos,
+            search: None,
         })
     }
 }
 
+/// Cancels the evaluation if `ev` is `Ctrl+C`, returning if the event was consumed.
+///
+/// Code evaluated in-process cannot be cancelled once running, so a second `Ctrl+C` requests to
+/// exit once the evaluation returns.
+fn interrupted(ev: &Event, interrupt: &Interrupt) -> bool {
+    const BREAK: Event = Key(KeyEvent {
+        modifiers: KeyModifiers::CONTROL,
+        code: Char('c'),
+    });
+
+    if ev != &BREAK {
+        return false;
+    }
+
+    let mut interrupt = match interrupt.lock() {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    match &interrupt.cancel {
+        Some(cancel) if cancel.is_cancelled() => {
+            interrupt.exit = true;
+            true
+        }
+        Some(cancel) => {
+            cancel.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
 pub struct Interface<'a> {
     screen: &'a mut Screen,
     stdout: Stdout,
     buf: &'a mut InputBuffer,
     prompt_len: usize,
     prev_lines_covered: u16,
-    history: &'a mut VecDeque<String>,
+    history: &'a mut History,
     /// history.len() is starting position. Counting backwards for so history.len() - 1 is 1st
     /// entry. Once hits zero, loop back to history.len().
     history_pos: usize,
+    /// Reverse incremental search state, if searching.
+    search: Option<Search>,
+}
+
+/// Reverse incremental search (Ctrl+R) state.
+struct Search {
+    query: String,
+    /// Index of the matched history entry.
+    matched: Option<usize>,
+    /// The prompt and input before searching began, restored if the search is cancelled.
+    prompt: String,
+    input: String,
 }
 
 impl<'a> Interface<'a> {
@@ -87,6 +146,21 @@ impl<'a> Interface<'a> {
         self.buf.len().saturating_sub(self.prompt_len)
     }
 
+    /// Set the evaluation which `Ctrl+C` cancels.
+    pub fn set_interrupt(&self, cancel: Option<CancelToken>) {
+        if let Ok(mut x) = self.screen.1.lock() {
+            if cancel.is_some() {
+                x.exit = false;
+            }
+            x.cancel = cancel;
+        }
+    }
+
+    /// `Ctrl+C` was pressed twice during the last evaluation, asking to exit.
+    pub fn exit_requested(&self) -> bool {
+        self.screen.1.lock().map(|x| x.exit).unwrap_or(false)
+    }
+
     /// This _does not_ include prompt length.
     pub fn buf_pos(&self) -> usize {
         self.buf.pos.saturating_sub(self.prompt_len)
@@ -147,6 +221,19 @@ impl<'a> Interface<'a> {
         });
 
         while let Ok(ev) = self.screen.0.recv() {
+            // searching consumes events until it is accepted or cancelled
+            let (ev, search_ended) = if self.search.is_some() {
+                match self.search_event(ev) {
+                    Some(ev) => (ev, true),
+                    None => {
+                        self.redraw()?;
+                        continue;
+                    }
+                }
+            } else {
+                (ev, false)
+            };
+
             last = ev;
             if events.contains(&ev) {
                 break;
@@ -199,6 +286,13 @@ impl<'a> Interface<'a> {
                     self.apply_history_line();
                     true
                 }
+                Key(KeyEvent {
+                    modifiers: KeyModifiers::CONTROL,
+                    code: Char('r'),
+                }) => {
+                    self.begin_search();
+                    true
+                }
                 Key(KeyEvent {
                     modifiers: NOMOD,
                     code: Char(c),
@@ -213,42 +307,147 @@ impl<'a> Interface<'a> {
                 _ => false,
             };
 
-            if modified {
-                // flushing will update prev lines changed and terminal cursor to end of buffer
-                // we get the cursor delta with the current buffer position to find out what needs
-                // to be moved!
-                self.flush_buffer()?;
-                let (col, rows) = self.buf.cursor_delta(self.buf.pos, term_width_nofail());
-                let uprows = self.prev_lines_covered;
-                queue!(self.stdout, MoveToColumn(col as u16 + 1))?;
-                if uprows > 0 {
-                    queue!(self.stdout, MoveUp(uprows))?;
-                }
-                if rows > 0 {
-                    queue!(self.stdout, MoveDown(rows as u16))?;
-                }
-                self.stdout.flush()?;
+            if modified || search_ended {
+                self.redraw()?;
             }
         }
 
         Ok(last)
     }
 
+    /// Flush the buffer and place the terminal cursor at the buffer position.
+    fn redraw(&mut self) -> XResult<()> {
+        // flushing will update prev lines changed and terminal cursor to end of buffer
+        // we get the cursor delta with the current buffer position to find out what needs
+        // to be moved!
+        self.flush_buffer()?;
+        let (col, rows) = self.buf.cursor_delta(self.buf.pos, term_width_nofail());
+        let uprows = self.prev_lines_covered;
+        queue!(self.stdout, MoveToColumn(col as u16 + 1))?;
+        if uprows > 0 {
+            queue!(self.stdout, MoveUp(uprows))?;
+        }
+        if rows > 0 {
+            queue!(self.stdout, MoveDown(rows as u16))?;
+        }
+        self.stdout.flush()?;
+        Ok(())
+    }
+
     /// Push the line onto the history stack.
-    /// Pops off oldest history to keep history len constant.
     pub fn add_history(&mut self, line: String) {
-        // must keep self.history.len() constant.
-        self.history.pop_front();
-        self.history.push_back(line);
+        self.history.push(line);
+    }
+
+    fn begin_search(&mut self) {
+        self.search = Some(Search {
+            query: String::new(),
+            matched: None,
+            prompt: self.buf.buffer(..self.prompt_len),
+            input: self.buffer(),
+        });
+        self.apply_search();
+    }
+
+    /// Handle an event while searching. Returns the event if the search has finished and the
+    /// event should be processed as usual.
+    fn search_event(&mut self, ev: Event) -> Option<Event> {
+        let search = self.search.as_mut()?;
+        let len = self.history.len();
+
+        match ev {
+            Key(KeyEvent {
+                modifiers: KeyModifiers::NONE,
+                code: Char(c),
+            })
+            | Key(KeyEvent {
+                modifiers: KeyModifiers::SHIFT,
+                code: Char(c),
+            }) => {
+                search.query.push(c);
+                // the current match might still match
+                let from = search.matched.map(|x| x + 1).unwrap_or(len);
+                search.matched = self.history.search(&search.query, from);
+            }
+            Key(KeyEvent {
+                modifiers: KeyModifiers::NONE,
+                code: Backspace,
+            }) => {
+                search.query.pop();
+                search.matched = self.history.search(&search.query, len);
+            }
+            Key(KeyEvent {
+                modifiers: KeyModifiers::CONTROL,
+                code: Char('r'),
+            }) => {
+                // search for an older match, staying on the current if there is none
+                let from = search.matched.unwrap_or(len);
+                if let Some(idx) = self.history.search(&search.query, from) {
+                    search.matched = Some(idx);
+                }
+            }
+            Key(KeyEvent {
+                modifiers: KeyModifiers::NONE,
+                code: Esc,
+            })
+            | Key(KeyEvent {
+                modifiers: KeyModifiers::CONTROL,
+                code: Char('c'),
+            })
+            | Key(KeyEvent {
+                modifiers: KeyModifiers::CONTROL,
+                code: Char('g'),
+            }) => {
+                let Search { prompt, input, .. } = self.search.take()?;
+                self.set_prompt(&prompt);
+                self.buf.truncate(self.prompt_len);
+                self.buf.insert_str(&input);
+                return None;
+            }
+            ev => {
+                // accept the match and process the event
+                let Search {
+                    prompt,
+                    input,
+                    matched,
+                    ..
+                } = self.search.take()?;
+                self.set_prompt(&prompt);
+                self.buf.truncate(self.prompt_len);
+                let history = &*self.history;
+                match matched.and_then(|idx| history.get(idx).map(|x| (idx, x))) {
+                    Some((idx, line)) => {
+                        self.buf.insert_str(line);
+                        self.history_pos = idx;
+                    }
+                    None => self.buf.insert_str(&input),
+                }
+                return Some(ev);
+            }
+        }
+
+        self.apply_search();
+        None
+    }
+
+    /// Writes the search prompt and matched line.
+    fn apply_search(&mut self) {
+        if let Some(search) = &self.search {
+            let prompt = format!("(reverse-i-search)`{}': ", search.query);
+            let line = search
+                .matched
+                .and_then(|idx| self.history.get(idx))
+                .unwrap_or("")
+                .to_string();
+            self.set_prompt(&prompt);
+            self.buf.truncate(self.prompt_len);
+            self.buf.insert_str(&line);
+        }
     }
 
     fn apply_history_line(&mut self) {
         // get line
-        let line = self
-            .history
-            .get(self.history_pos)
-            .map(|x| x.as_str())
-            .unwrap_or("");
+        let line = self.history.get(self.history_pos).unwrap_or("");
         self.buf.truncate(self.prompt_len);
         self.buf.insert_str(line);
     }
@@ -622,7 +821,9 @@ mod tests {
         let (origcols, _origrows) = size()?;
         let mut screen = Screen::new()?;
         let mut inputbuf = InputBuffer::new();
-        let mut history = VecDeque::from(vec!["Hello".to_string(), "World".to_string()]);
+        let mut history = History::new(2);
+        history.push("Hello".to_string());
+        history.push("World".to_string());
         let mut input = screen.begin_interface_input(&mut inputbuf, &mut history)?;
 
         let repl: Repl<_, ()> = Repl::default();
@@ -676,10 +877,8 @@ mod tests {
         // test adding history items
         input.add_history("Item 1".to_string());
         drop(input);
-        assert_eq!(
-            history.into_iter().collect::<Vec<_>>().as_slice(),
-            &["World".to_string(), "Item 1".to_string()]
-        );
+        assert_eq!(history.get(0), Some("World"));
+        assert_eq!(history.get(1), Some("Item 1"));
 
         // Ensure to reset terminal state
         disable_raw_mode()?;
@@ -9,10 +9,12 @@ use repl::{EvalResult, Evaluate, Print, Read, ReadResult};
 use std::io::{self, prelude::*};
 use std::sync::{Arc, Mutex};
 
+mod history;
 mod interface;
 #[cfg(test)]
 mod tests;
 
+use history::History;
 use interface::{CItem, Interface, Screen};
 
 #[cfg(feature = "racer-completion")]
@@ -180,6 +182,15 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
 /// Available with the `runnable` feature and when the REPL is in the `Read` state.
 impl<D> Repl<Read, D> {
     /// Run the repl inside the terminal, consuming the repl. Returns the output of the REPL.
+    ///
+    /// Input history is navigated with the Up and Down arrows, and `Ctrl+R` begins a reverse
+    /// incremental search. History is persisted to the compilation directory, see
+    /// [`ReplData::history_size`](crate::repl::ReplData::history_size).
+    ///
+    /// `Ctrl+C` during an evaluation cancels it, discarding the input. Code evaluated in-process
+    /// cannot be interrupted once running, a second `Ctrl+C` exits the REPL once the evaluation
+    /// returns. Evaluate out-of-process to be able to cancel long running code, see
+    /// [`ExecOptions`](crate::compile::ExecOptions).
     pub fn run<T, U, V>(self, run_callbacks: RunCallbacks<D, T, U, V>) -> io::Result<String>
     where
         T: FnMut(&Repl<Print, D>) -> kserd::fmt::FormattingConfig,
@@ -238,10 +249,16 @@ where
     let cache = CacheWrapper;
     let mut reevaluate: Option<String> = None;
 
-    // must seed the history size, this is maintained as constant.
-    let mut history = std::collections::VecDeque::from(vec![String::default(); 100]); // 100 history
+    let history_file = read.data.history_file();
+    let history_size = read.data.history_size;
+    let mut history = History::load(&history_file, history_size);
+    let mut save_history = false;
 
     let output = loop {
+        if save_history && history_size > 0 {
+            history.save(&history_file).ok(); // history is a nicety, failing to save is fine
+        }
+
         let mut interface = screen.begin_interface_input(&mut inputbuf, &mut history)?;
         interface.set_prompt(&read.prompt(true));
 
@@ -257,12 +274,25 @@ where
             read.line_input(&val);
         } else if do_read(&mut read, &mut interface, &cache)? {
             break read.output().to_owned();
+        } else {
+            save_history = true;
         }
 
         match read.read() {
             ReadResult::Read(repl) => read = repl,
             ReadResult::Eval(repl) => {
-                match do_eval(repl, &mut runcb) {
+                interface.set_interrupt(Some(repl.data.cancel.clone()));
+                let (repl, signal) = do_eval(repl, &mut runcb);
+                interface.set_interrupt(None);
+
+                // a second `Ctrl+C` exits once the evaluation returns
+                let signal = if interface.exit_requested() {
+                    Signal::Exit
+                } else {
+                    signal
+                };
+
+                match (repl, signal) {
                     (mut repl, Signal::Exit) => {
                         // run exit function
                         if let Some(exitfn) = runcb.exitfn {
@@ -289,6 +319,10 @@ where
         }
     };
 
+    if history_size > 0 {
+        history.save(&history_file).ok();
+    }
+
     let _ = std::panic::take_hook(); // remove the previous set_hook
     Ok(output)
 }
@@ -198,10 +198,10 @@ fn interface_integration() {
     let (tx, rx) = unbounded();
     let tx = Tx(tx);
     let mut inputbuf = InputBuffer::new();
-    let mut screen = Screen(rx);
+    let mut screen = Screen(rx, Default::default());
     writeln!(io::stdout()).unwrap();
     slp();
-    let mut history = std::collections::VecDeque::from(vec![String::default(); 2]);
+    let mut history = super::History::new(2);
     let mut interface = screen
         .begin_interface_input(&mut inputbuf, &mut history)
         .unwrap();
@@ -362,7 +362,7 @@ fn colour_off() {
 
 fn fire_off_run(rx: Receiver<Event>) -> JoinHandle<Result<String>> {
     std::thread::spawn(|| {
-        let screen = Screen(rx);
+        let screen = Screen(rx, Default::default());
         let repl = crate::repl::Repl::<_, ()>::default();
         run(repl, RunCallbacks::new(&mut ()), || Ok(screen))
     })
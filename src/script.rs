@@ -0,0 +1,273 @@
+//! Running a rust source file as a script.
+//!
+//! A script is parsed in the same manner as REPL input, flattened into a single `lib` module, then
+//! compiled and evaluated once. The result of the trailing expression is returned as a [`Kserd`].
+//! If the script contains only items and one of them is `fn main()`, then `main()` is called.
+//!
+//! A leading shebang line (`#!/usr/bin/env papyrus`) is ignored, such that scripts can be made
+//! executable.
+//!
+//! # Example
+//! ```rust,no_run
+//! let r = papyrus::script::run("script.rs", "target/script-compile-dir").unwrap();
+//! if let Some(kserd) = r {
+//!     println!("{}", kserd.as_str());
+//! }
+//! ```
+//!
+//! [`Kserd`]: ::kserd::Kserd
+use crate::code::{construct_source_map, ModsMap, SourceCode, Statement, StaticFiles, StmtGrp};
+use crate::compile::{self, CancelToken, CompilationError, CompileOptions, ExecError, ExecOptions};
+use crate::input::{self, InputResult};
+use crate::linking::LinkingConfiguration;
+use ::kserd::Kserd;
+use std::path::{Path, PathBuf};
+use std::{error, fmt, fs, io};
+
+/// Compile and evaluate the script at `script`, using `compile_dir` as the compilation directory.
+///
+/// Returns the evaluated result if the script ends in an expression (no trailing semi-colon).
+/// Compilation progress is not reported, the compiler output is returned in the error if
+/// compilation fails.
+pub fn run<P, Q>(script: P, compile_dir: Q) -> Result<Option<Kserd<'static>>, ScriptError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let code = fs::read_to_string(script).map_err(ScriptError::Io)?;
+    run_str(&code, compile_dir)
+}
+
+/// Compile and evaluate `code` as a script, using `compile_dir` as the compilation directory.
+///
+/// See [`run`](run).
+pub fn run_str<Q: AsRef<Path>>(
+    code: &str,
+    compile_dir: Q,
+) -> Result<Option<Kserd<'static>>, ScriptError> {
+    let compile_dir = compile_dir.as_ref();
+
+    let src = parse_script(code)?;
+    let returns_value = src
+        .stmts
+        .last()
+        .and_then(|grp| grp.0.last())
+        .map(|stmt| !stmt.semi)
+        .unwrap_or(false);
+    let has_stmts = !src.stmts.is_empty();
+
+    let mut mods_map = ModsMap::new();
+    mods_map.insert(PathBuf::from("lib"), src);
+    let linking = LinkingConfiguration::default();
+
+    let options = CompileOptions::default();
+
+    compile::build_compile_dir_with_options(
+        compile_dir,
+        &mods_map,
+        &linking,
+        &StaticFiles::new(),
+        &options,
+    )
+    .map_err(ScriptError::Io)?;
+
+    let lib_file = compile::compile_with_options(compile_dir, &linking, &options, |_| ()).map_err(
+        |mut e| {
+            e.locate(&construct_source_map(
+                &mods_map,
+                &linking,
+                &StaticFiles::new(),
+            ));
+            ScriptError::Compile(e)
+        },
+    )?;
+
+    if !has_stmts {
+        return Ok(None);
+    }
+
+    let lib_file = compile::unshackle_library_file(lib_file);
+    // scripts print straight to the terminal
+    let opts = ExecOptions {
+        capture_output: false,
+        ..Default::default()
+    };
+    let (kserd, _lib) = compile::exec(
+        &lib_file,
+        "_lib_intern_eval",
+        &(),
+        None,
+        &opts,
+        &CancelToken::default(),
+        |_| (),
+    )
+    .map_err(ScriptError::Eval)?;
+
+    Ok(if returns_value { Some(kserd) } else { None })
+}
+
+/// The compilation directory used for a script when run through the `papyrus` binary.
+///
+/// Each script gets its own directory under `$HOME/.papyrus/scripts/`, keyed on the absolute path
+/// of the script, so subsequent runs can reuse the build artifacts.
+pub fn default_compile_dir<P: AsRef<Path>>(script: P) -> PathBuf {
+    let script = script.as_ref();
+    let path = script
+        .canonicalize()
+        .unwrap_or_else(|_| script.to_path_buf());
+    let hash = blake3::hash(path.to_string_lossy().as_bytes());
+
+    crate::repl::default_compile_dir()
+        .join("scripts")
+        .join(&hash.to_hex()[..16])
+}
+
+/// Parse the script into a single `SourceCode`.
+fn parse_script(code: &str) -> Result<SourceCode, ScriptError> {
+    let code = strip_shebang(code);
+
+    let input = match input::parse_program(code) {
+        InputResult::Program(input) => input,
+        InputResult::InputError(e) => return Err(ScriptError::Parse(e)),
+        InputResult::More => {
+            return Err(ScriptError::Parse(
+                "unexpected end of file, is there an unclosed delimiter?".to_string(),
+            ))
+        }
+        _ => return Ok(SourceCode::default()),
+    };
+
+    let mut stmts = input.stmts;
+
+    if stmts.is_empty() && input.items.iter().any(|x| is_main(&x.0)) {
+        stmts.push(Statement {
+            expr: "main()".to_string(),
+            semi: false,
+        });
+    }
+
+    let stmts = if stmts.is_empty() {
+        Vec::new()
+    } else {
+        vec![StmtGrp(stmts)]
+    };
+
+    Ok(SourceCode {
+        items: input.items,
+        stmts,
+        crates: input.crates,
+    })
+}
+
+/// Whether the item is `fn main()`, with any visibility, attributes, or return type.
+fn is_main(item: &str) -> bool {
+    match syn::parse_str::<syn::Item>(item) {
+        Ok(syn::Item::Fn(f)) => f.sig.ident == "main" && f.sig.inputs.is_empty(),
+        _ => false,
+    }
+}
+
+/// Removes a leading `#!` line, but not an inner attribute (`#![...]`).
+fn strip_shebang(code: &str) -> &str {
+    if code.starts_with("#!") && !code.starts_with("#![") {
+        code.find('\n').map(|idx| &code[idx + 1..]).unwrap_or("")
+    } else {
+        code
+    }
+}
+
+/// Error type for running a script.
+#[derive(Debug)]
+pub enum ScriptError {
+    /// Reading the script or writing the compilation directory failed.
+    Io(io::Error),
+    /// The script could not be parsed.
+    Parse(String),
+    /// The script failed to compile.
+    Compile(CompilationError),
+    /// Evaluating the script failed, usually due to a panic.
+    Eval(ExecError),
+}
+
+impl error::Error for ScriptError {}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Io(e) => write!(f, "io error occurred: {}", e),
+            ScriptError::Parse(e) => write!(f, "failed to parse script: {}", e),
+            ScriptError::Compile(e) => write!(f, "{}", e),
+            ScriptError::Eval(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_shebang_test() {
+        assert_eq!(strip_shebang("#!/usr/bin/env papyrus\n2+2"), "2+2");
+        assert_eq!(strip_shebang("#!/usr/bin/env papyrus"), "");
+        assert_eq!(strip_shebang("#![feature(test)]"), "#![feature(test)]");
+        assert_eq!(strip_shebang("2+2"), "2+2");
+    }
+
+    #[test]
+    fn is_main_test() {
+        assert!(is_main("fn main() {}"));
+        assert!(is_main("pub fn main() {}"));
+        assert!(is_main("#[allow(unused)]\nfn main() {}"));
+        assert!(is_main("fn main() -> Result<(), String> { Ok(()) }"));
+        assert!(!is_main("fn main_loop() {}"));
+        assert!(!is_main("fn foo() { main() }"));
+        assert!(!is_main("struct main;"));
+    }
+
+    #[test]
+    fn parse_script_test() {
+        let src = parse_script("#!/usr/bin/env papyrus\nlet a = 1;\na + 1").unwrap();
+        assert_eq!(src.stmts.len(), 1);
+        assert_eq!(src.stmts[0].src_line(), "let a = 1; a + 1");
+
+        let src = parse_script("fn main() {\nprintln!(\"Hello\");\n}").unwrap();
+        assert_eq!(src.items.len(), 1);
+        assert_eq!(src.stmts[0].src_line(), "main()");
+
+        let src = parse_script("fn foo() {}").unwrap();
+        assert!(src.stmts.is_empty());
+
+        let src =
+            parse_script("#[allow(unused)]\npub fn main() -> Result<(), ()> {\nOk(())\n}").unwrap();
+        assert_eq!(src.stmts[0].src_line(), "main()");
+
+        let src = parse_script("fn main_loop() {}").unwrap();
+        assert!(src.stmts.is_empty());
+
+        match parse_script("fn foo() {") {
+            Err(ScriptError::Parse(_)) => (),
+            _ => panic!("expecting parse error"),
+        }
+    }
+
+    #[test]
+    fn run_script_test() {
+        let compile_dir = "target/testing/run_script_test";
+        let r = run_str("let a = 2;\na * 3", compile_dir).unwrap();
+        assert_eq!(r, Some(Kserd::new_num(6)));
+
+        let r = run_str("let a = 2;\na * 3;", compile_dir).unwrap();
+        assert_eq!(r, None);
+
+        match run_str("let a = 2;\na * ;", compile_dir) {
+            Err(ScriptError::Parse(_)) => (),
+            _ => panic!("expecting parse error"),
+        }
+
+        match run_str("let a: String = 2;\na", compile_dir) {
+            Err(ScriptError::Compile(_)) => (),
+            _ => panic!("expecting compile error"),
+        }
+    }
+}
@@ -0,0 +1,522 @@
+//! Drive a REPL over a line-delimited JSON protocol, such as from an editor extension.
+//!
+//! `papyrus --server` serves a REPL on stdin and stdout. Each line of input is a request object, with
+//! an `id` (any JSON value, which is echoed back) and a `method`. Each request is answered with a
+//! single line, either `{"id": .., "result": ..}` or `{"id": .., "error": ".."}`. Requests are
+//! handled in order, except `cancel`, which is handled as soon as it is read. A `cancel` cancels the
+//! evaluation in progress and any requests read before it which are yet to be handled.
+//!
+//! While a request is evaluated, each change to the REPL [`Output`](crate::output::Output) is
+//! written as an event, `{"event": "output", "id": .., "change": ..}`, where the change is a
+//! serialized [`OutputChange`](crate::output::OutputChange). This includes the output printed by
+//! the evaluated code (if captured, see [`ExecOptions`](crate::compile::ExecOptions), which
+//! `papyrus --server` does), the printed result, and the prompt drawn afterwards. Lines can contain
+//! ANSI colour codes.
+//!
+//! # Methods
+//! | method | parameters | result |
+//! | ------ | ---------- | ------ |
+//! | `eval` | `code` | an [evaluation](#evaluation) |
+//! | `command` | `command`, the command without the `:` prefix | an [evaluation](#evaluation) |
+//! | `complete` | `line` | `start`, the byte position the `matches` replace from |
+//! | `modules` | | `current`, the current module, and `modules`, all module paths |
+//! | `output` | `n` | `data`, the formatted result of `[out<n>]` in the current module, and `json` |
+//! | `cancel` | | `null`, the current and pending evaluations are cancelled |
+//! | `exit` | | `null`, the server stops |
+//!
+//! # Evaluation
+//! The result of `eval` and `command` has a `status` and the `prompt` of the REPL after evaluating.
+//!
+//! - `ok`: data was evaluated, the result has the statement number `out`, the formatted `data`,
+//!   and its `renderings` (see [`render`](crate::repl::render)),
+//! - `more`: more input is required, such as an unclosed delimiter or a trailing statement with a
+//!   semi-colon. The next `eval` appends to the input,
+//! - `empty`: nothing was evaluated, such as only defining items,
+//! - `command`: a command was run, its output is in the output events,
+//! - `error`: the input failed, `kind` and `message` describe why.
+//!
+//! The server stops when input ends, on `exit`, or on the `:exit` command.
+//!
+//! Each `eval` evaluates the previous statements of the module again, unless bindings are persisted
+//! with `papyrus --server --persist` or the `persist on` command, see
+//! [`ReplData::persist_bindings`](crate::repl::ReplData::persist_bindings).
+//!
+//! # Example
+//! ```text
+//! -> {"id": 1, "method": "eval", "code": "2+2"}
+//! <- {"event": "output", "id": 1, "change": {"current_line": "papyrus [out0]: 4"}}
+//! <- {"event": "output", "id": 1, "change": "new_line"}
+//! <- {"event": "output", "id": 1, "change": {"current_line": "[lib] papyrus=> "}}
+//! <- {"id": 1, "result": {"status": "ok", "out": 0, "data": "4", "renderings": [..], "prompt": ".."}}
+//! -> {"id": 2, "method": "complete", "line": ":mo"}
+//! <- {"id": 2, "result": {"start": 0, "matches": [":mod", ":mod clear", ":mod switch"]}}
+//! ```
+use crate::complete::{cmdr::TreeCompleter, modules::ModulesCompleter};
+use crate::repl::{self, EvalOutcome, EvalResult, Read, ReadResult, Repl, ReplData, Signal};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A request, tagged by the `method`.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Method {
+    Eval { code: String },
+    Command { command: String },
+    Complete { line: String },
+    Modules,
+    Output { n: usize },
+    Cancel,
+    Exit,
+}
+
+/// Serve the REPL, reading requests from `input` and writing responses and events to `output`.
+///
+/// Returns when `input` ends or the REPL exits.
+pub fn serve<D, R, W>(
+    mut repl: Repl<Read, D>,
+    app_data: &mut D,
+    input: R,
+    output: W,
+) -> io::Result<()>
+where
+    R: BufRead + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let writer = Writer(Arc::new(Mutex::new(output)));
+    let (tx, rx) = crossbeam_channel::unbounded();
+
+    // requests are numbered in the order they are read, those numbered below this are cancelled
+    let cancelled = Arc::new(Mutex::new(0u64));
+
+    // requests are read on another thread such that evaluation can be cancelled
+    let w = writer.clone();
+    let cancel = repl.data.cancel.clone();
+    let cancelled_ = Arc::clone(&cancelled);
+    let reader = thread::spawn(move || -> io::Result<()> {
+        let mut seq = 0;
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match parse_request(&line) {
+                Ok((id, Method::Cancel)) => {
+                    *cancelled_.lock().expect("cancel lock poisoned") = seq;
+                    cancel.cancel();
+                    w.write(&json!({ "id": id, "result": null }))?;
+                }
+                Ok((id, method)) => {
+                    if tx.send((seq, id, method)).is_err() {
+                        break; // the server stopped
+                    }
+                    seq += 1;
+                }
+                Err((id, e)) => w.write(&json!({ "id": id, "error": e }))?,
+            }
+        }
+
+        Ok(())
+    });
+
+    #[cfg(feature = "racer-completion")]
+    let cache = crate::complete::code::CodeCache::new().unwrap_or_else(|e| e.0);
+
+    for (seq, id, method) in rx.iter() {
+        let mut exit = false;
+
+        // a cancel read after this request was queued still applies to it
+        {
+            let cancelled = cancelled.lock().expect("cancel lock poisoned");
+            if seq < *cancelled {
+                repl.data.cancel.cancel();
+            } else {
+                repl.data.cancel.reset();
+            }
+        }
+
+        let response = match method {
+            Method::Eval { code } => {
+                let (r, result, signal) = eval(repl, &code, &id, app_data, &writer);
+                repl = r;
+                exit = signal;
+                Ok(result)
+            }
+            Method::Command { command } => {
+                let code = format!("{}{}", crate::CMD_PREFIX, command);
+                let (r, result, signal) = eval(repl, &code, &id, app_data, &writer);
+                repl = r;
+                exit = signal;
+                Ok(result)
+            }
+            Method::Complete { line } => {
+                #[cfg(feature = "racer-completion")]
+                let (start, matches) = complete(&repl, &line, &cache);
+                #[cfg(not(feature = "racer-completion"))]
+                let (start, matches) = complete(&repl, &line);
+                Ok(json!({ "start": start, "matches": matches }))
+            }
+            Method::Modules => Ok(modules(&repl.data)),
+            Method::Output { n } => repl
+                .data
+                .output(n)
+                .map(|kserd| {
+                    json!({
+                        "data": repl::fmt_output(kserd, Default::default()),
+                        "json": repl::to_json(kserd),
+                    })
+                })
+                .ok_or_else(|| repl::OutputError::Missing(n).to_string()),
+            Method::Exit => {
+                exit = true;
+                Ok(Value::Null)
+            }
+            Method::Cancel => Ok(Value::Null), // handled by the reader
+        };
+
+        let response = match response {
+            Ok(result) => json!({ "id": id, "result": result }),
+            Err(e) => json!({ "id": id, "error": e }),
+        };
+        writer.write(&response)?;
+
+        if exit {
+            return Ok(());
+        }
+    }
+
+    // input ended, the reader might have failed
+    reader.join().unwrap_or(Ok(()))
+}
+
+/// Parses a request, on failure returning the id, if any, with the error message.
+fn parse_request(line: &str) -> Result<(Value, Method), (Value, String)> {
+    let value: Value =
+        serde_json::from_str(line).map_err(|e| (Value::Null, format!("invalid json: {}", e)))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    match serde_json::from_value(value) {
+        Ok(method) => Ok((id, method)),
+        Err(e) => Err((id, format!("invalid request: {}", e))),
+    }
+}
+
+/// Evaluates the input, writing the output changes as events.
+///
+/// Returns the evaluation result, and whether the REPL exited.
+fn eval<D, W>(
+    mut repl: Repl<Read, D>,
+    code: &str,
+    id: &Value,
+    app_data: &mut D,
+    writer: &Writer<W>,
+) -> (Repl<Read, D>, Value, bool)
+where
+    W: Write + Send + 'static,
+{
+    let mut input = code.to_string();
+
+    loop {
+        repl.line_input(&input);
+        let mut eval = match repl.read() {
+            ReadResult::Eval(eval) => eval,
+            ReadResult::Read(read) => {
+                let result = json!({ "status": "more", "prompt": read.prompt(false) });
+                return (read, result, false);
+            }
+        };
+
+        let rx = eval.output_listen();
+        let (w, id_) = (writer.clone(), id.clone());
+        let jh = thread::spawn(move || {
+            for change in rx.iter() {
+                let event = json!({ "event": "output", "id": id_, "change": change });
+                if w.write(&event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let EvalResult {
+            repl: print,
+            signal,
+            outcome,
+            renderings,
+        } = eval.eval_keep_cancel(app_data);
+        let (mut read, out) = print.print();
+        read.close_channel();
+        jh.join().ok();
+
+        let exit = match signal {
+            Signal::ReEvaluate(s) => {
+                input = s;
+                repl = read;
+                continue;
+            }
+            Signal::Exit => true,
+            Signal::None => false,
+        };
+
+        let mut result = match outcome {
+            EvalOutcome::Ok(kserd) => json!({
+                "status": "ok",
+                "out": out.map(|x| x.0),
+                "data": repl::fmt_output(&kserd, Default::default()),
+                "renderings": renderings,
+            }),
+            EvalOutcome::Empty => json!({ "status": "empty" }),
+            EvalOutcome::Command => json!({ "status": "command" }),
+            EvalOutcome::ParseFailed(e) => error("ParseError", e),
+            EvalOutcome::BuildFailed(e) => error("BuildError", e.to_string()),
+            EvalOutcome::CompileFailed(e) => error("CompileError", e.to_string()),
+            EvalOutcome::LoadFailed(e) | EvalOutcome::ExecFailed(e) => {
+                error("ExecError", e.to_string())
+            }
+            EvalOutcome::Panicked(e) => error("Panic", e.to_string()),
+            EvalOutcome::Cancelled => error("Cancelled", "evaluation cancelled".to_string()),
+        };
+        result["prompt"] = read.prompt(false).into();
+
+        break (read, result, exit);
+    }
+}
+
+fn error(kind: &str, message: String) -> Value {
+    json!({ "status": "error", "kind": kind, "message": message })
+}
+
+/// Completes the end of the line, returning the byte position the matches start at.
+fn complete<D>(
+    repl: &Repl<Read, D>,
+    line: &str,
+    #[cfg(feature = "racer-completion")] cache: &crate::complete::code::CodeCache,
+) -> (usize, Vec<String>) {
+    let data = &repl.data;
+
+    if line.starts_with(crate::CMD_PREFIX) {
+        let tree = TreeCompleter::build(&data.cmdtree)
+            .complete(line)
+            .map(|x| x.0.to_owned())
+            .collect::<Vec<_>>();
+        if !tree.is_empty() {
+            return (TreeCompleter::word_break(line), tree);
+        }
+
+        let mods = ModulesCompleter::build(&data.cmdtree, data.mods_map())
+            .complete(line)
+            .collect();
+        return (ModulesCompleter::word_break(line), mods);
+    }
+
+    #[cfg(feature = "racer-completion")]
+    {
+        use crate::complete::code::CodeCompleter;
+
+        let injection = format!("{}\n{}", repl.input_buffer(), line);
+        let matches = CodeCompleter::build(data)
+            .complete(&injection, Some(CODE_COMPLETIONS), cache)
+            .into_iter()
+            .map(|x| x.matchstr)
+            .collect();
+        (CodeCompleter::word_break(line), matches)
+    }
+
+    #[cfg(not(feature = "racer-completion"))]
+    (line.len(), Vec::new())
+}
+
+#[cfg(feature = "racer-completion")]
+const CODE_COMPLETIONS: usize = 50;
+
+fn modules<D>(data: &ReplData<D>) -> Value {
+    json!({
+        "current": data.current_mod(),
+        "modules": data.mods_map().keys().collect::<Vec<_>>(),
+    })
+}
+
+/// Writes JSON lines, shared between threads.
+struct Writer<W>(Arc<Mutex<W>>);
+
+impl<W> Clone for Writer<W> {
+    fn clone(&self) -> Self {
+        Writer(Arc::clone(&self.0))
+    }
+}
+
+impl<W: Write> Writer<W> {
+    fn write(&self, value: &Value) -> io::Result<()> {
+        let mut w = self.0.lock().unwrap();
+        serde_json::to_writer(&mut *w, value)?;
+        w.write_all(b"\n")?;
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parse_request_test() {
+        assert_eq!(
+            parse_request(r#"{"id": 1, "method": "eval", "code": "2+2"}"#),
+            Ok((
+                json!(1),
+                Method::Eval {
+                    code: "2+2".to_string()
+                }
+            ))
+        );
+        assert_eq!(
+            parse_request(r#"{"id": "a", "method": "modules"}"#),
+            Ok((json!("a"), Method::Modules))
+        );
+        assert_eq!(
+            parse_request(r#"{"method": "exit"}"#),
+            Ok((Value::Null, Method::Exit))
+        );
+
+        let e = parse_request(r#"{"id": 2, "method": "fly"}"#).unwrap_err();
+        assert_eq!(e.0, json!(2));
+        assert!(e.1.starts_with("invalid request"), "{}", e.1);
+
+        let e = parse_request(r#"{"id": 3, "method": "output"}"#).unwrap_err();
+        assert_eq!(e.0, json!(3));
+
+        let e = parse_request("{").unwrap_err();
+        assert_eq!(e.0, Value::Null);
+        assert!(e.1.starts_with("invalid json"), "{}", e.1);
+    }
+
+    #[test]
+    fn serve_test() {
+        let input = [
+            r#"{"id": 1, "method": "complete", "line": ":mo"}"#,
+            r#"{"id": 2, "method": "command", "command": "mod switch a"}"#,
+            r#"{"id": 3, "method": "modules"}"#,
+            r#"{"id": 4, "method": "output", "n": 0}"#,
+            r#"{"id": 8, "method": "eval", "code": "2+2"}"#,
+            r#"{"id": 10, "method": "eval", "code": "3+3"}"#,
+            r#"{"id": 9, "method": "cancel"}"#,
+            r#"{"id": 5, "method": "eval", "code": "let a = 1;"}"#,
+            r#"not json"#,
+            r#"{"id": 6, "method": "exit"}"#,
+            r#"{"id": 7, "method": "modules"}"#,
+        ]
+        .join("\n");
+
+        let lines = serve_lines(input);
+        let response = |id: Value| {
+            lines
+                .iter()
+                .find(|x| x.get("event").is_none() && x["id"] == id)
+                .unwrap()
+        };
+
+        let r = response(json!(1));
+        assert_eq!(r["result"]["start"], 0);
+        assert_eq!(r["result"]["matches"][0], ":mod");
+
+        let r = response(json!(2));
+        assert_eq!(r["result"]["status"], "command");
+        assert_eq!(r["result"]["prompt"], "[a] papyrus=> ");
+        // output events are tagged with the request
+        assert!(lines.iter().any(|x| x["event"] == "output" && x["id"] == 2));
+
+        assert_eq!(
+            response(json!(3))["result"],
+            json!({ "current": "a", "modules": ["a", "lib"] })
+        );
+        assert_eq!(
+            response(json!(4))["error"],
+            "no result for out0 in the history"
+        );
+        // the cancel is read before the queued eval is handled, the first might have finished
+        assert!(response(json!(8)).get("result").is_some());
+        assert_eq!(response(json!(10))["result"]["kind"], "Cancelled");
+        assert_eq!(response(json!(9))["result"], Value::Null);
+        assert_eq!(response(json!(5))["result"]["status"], "more");
+
+        let r = response(Value::Null);
+        assert!(r["error"].as_str().unwrap().starts_with("invalid json"));
+
+        assert_eq!(response(json!(6))["result"], Value::Null);
+        assert!(lines.iter().all(|x| x["id"] != 7));
+    }
+
+    #[test]
+    #[cfg(feature = "test-runnable")]
+    fn serve_eval_test() {
+        let input = [
+            r#"{"id": 1, "method": "eval", "code": "2+2"}"#,
+            r#"{"id": 2, "method": "output", "n": 0}"#,
+            r#"{"id": 3, "method": "eval", "code": "let a: u8 = \"a\"; a"}"#,
+            r#"{"id": 4, "method": "command", "command": "exit"}"#,
+            r#"{"id": 5, "method": "modules"}"#,
+        ]
+        .join("\n");
+
+        let lines = serve_lines(input);
+        let response = |id| {
+            lines
+                .iter()
+                .find(|x| x.get("event").is_none() && x["id"] == id)
+                .unwrap()
+        };
+
+        let r = response(1);
+        assert_eq!(r["result"]["status"], "ok");
+        assert_eq!(r["result"]["out"], 0);
+        assert_eq!(r["result"]["data"], "4");
+        assert_eq!(r["result"]["renderings"][0]["mime"], "text/plain");
+        assert!(lines.iter().any(|x| x["id"] == 1
+            && x["change"]["current_line"]
+                .as_str()
+                .map(|x| x.ends_with("[out0]: 4"))
+                .unwrap_or(false)));
+
+        assert_eq!(response(2)["result"], json!({ "data": "4", "json": 4 }));
+
+        let r = response(3);
+        assert_eq!(r["result"]["status"], "error");
+        assert_eq!(r["result"]["kind"], "CompileError");
+
+        assert_eq!(response(4)["result"]["status"], "empty");
+        assert!(lines.iter().all(|x| x["id"] != 5));
+    }
+
+    fn serve_lines(input: String) -> Vec<Value> {
+        let output = Shared::default();
+        serve(
+            Repl::default(),
+            &mut (),
+            Cursor::new(input.into_bytes()),
+            output.clone(),
+        )
+        .unwrap();
+
+        let output = output.0.lock().unwrap();
+        std::str::from_utf8(&output)
+            .unwrap()
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect()
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -23,6 +23,21 @@ fn chg_compile_dir<T, U>(mut repl: Repl<T, U>) -> Repl<T, U> {
     repl
 }
 
+#[cfg(feature = "test-runnable")]
+fn eval_input(
+    mut repl: Repl<repl::Read, ()>,
+    input: &str,
+) -> (Repl<repl::Read, ()>, repl::EvalOutcome) {
+    repl.line_input(input);
+    match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => {
+            let repl::EvalResult { repl, outcome, .. } = repl.eval(&mut ());
+            (repl.print().0, outcome)
+        }
+    }
+}
+
 #[test]
 #[cfg(feature = "test-runnable")]
 fn multiline_literal_inputs() {
@@ -44,7 +59,7 @@ a
     repl = match repl.read() {
         ReadResult::Read(_) => panic!("should be at Eval state!"),
         ReadResult::Eval(repl) => {
-            let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+            let repl::EvalResult { repl, signal, .. } = repl.eval(&mut ());
             assert_eq!(signal, Signal::None);
             let (repl, result_kserd) = repl.print();
             let expected_kserd = Kserd::new_str("Hello\nMultiline\nInput\n");
@@ -66,7 +81,7 @@ a
     match repl.read() {
         ReadResult::Read(_) => panic!("should be at Eval state!"),
         ReadResult::Eval(repl) => {
-            let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+            let repl::EvalResult { repl, signal, .. } = repl.eval(&mut ());
             assert_eq!(signal, Signal::None);
             let (repl, result_kserd) = repl.print();
             let expected_kserd = Kserd::new_str("Hello\nWorld!");
@@ -75,3 +90,233 @@ a
         }
     };
 }
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn eval_outcomes() {
+    use papyrus::compile::CompilationError;
+
+    let mut repl = chg_compile_dir(repl!());
+    let mut eval = |input: &str| {
+        let (r, outcome) = eval_input(std::mem::replace(&mut repl, repl!()), input);
+        repl = r;
+        outcome
+    };
+
+    match eval("2+3") {
+        repl::EvalOutcome::Ok(kserd) => assert_eq!(kserd, Kserd::new_num(5)),
+        x => panic!("expecting Ok, found {:?}", x),
+    }
+    match eval(":help") {
+        repl::EvalOutcome::Command => (),
+        x => panic!("expecting Command, found {:?}", x),
+    }
+    match eval("fn boom() -> u32 { panic!(\"boom\") }") {
+        repl::EvalOutcome::Empty => (),
+        x => panic!("expecting Empty, found {:?}", x),
+    }
+    match eval("let a: u32 = \"a\"; a") {
+        repl::EvalOutcome::CompileFailed(CompilationError::Diagnostics(d)) => {
+            assert_eq!(d[0].code.as_deref(), Some("E0308"))
+        }
+        x => panic!("expecting CompileFailed, found {:?}", x),
+    }
+    match eval("boom()") {
+        repl::EvalOutcome::Panicked(panic) => assert_eq!(panic.message, "boom"),
+        x => panic!("expecting Panicked, found {:?}", x),
+    }
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn variables() {
+    use papyrus::compile::CompilationError;
+
+    let mut repl = chg_compile_dir(repl!());
+    assert_eq!(repl.data.variables().unwrap(), vec![]);
+
+    for input in &[
+        "let (a, b) = (1u8, vec![String::new()]); a",
+        "let a = \"shadowed\"; b.len()",
+        "let c = String::new(); c",
+    ] {
+        repl = eval_input(repl, input).0;
+    }
+
+    let vars = repl.data.variables().unwrap();
+    let expected = [
+        ("b", "Vec<String>"),
+        ("out0", "u8"),
+        ("a", "&str"),
+        ("out1", "usize"),
+        ("out2", "String"),
+    ];
+    assert_eq!(vars.len(), expected.len());
+    for ((name, ty), (ename, ety)) in vars.iter().zip(&expected) {
+        assert_eq!(name, ename);
+        assert_eq!(ty, ety);
+    }
+
+    // no statements are added
+    assert_eq!(repl.data.current_src().stmts.len(), 3);
+
+    assert_eq!(
+        repl.data
+            .type_of("b.iter().map(|x| x.len()).sum::<usize>()")
+            .unwrap(),
+        "usize"
+    );
+    assert_eq!(repl.data.type_of("()").unwrap(), "()");
+    match repl.data.type_of("not_defined") {
+        Err(CompilationError::Diagnostics(d)) => assert_eq!(d[0].code.as_deref(), Some("E0425")),
+        x => panic!("expecting Diagnostics, found {:?}", x),
+    }
+    assert_eq!(repl.data.current_src().stmts.len(), 3);
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn persisted_bindings() {
+    persisted_bindings_test(false);
+    #[cfg(unix)]
+    persisted_bindings_test(true);
+}
+
+#[cfg(feature = "test-runnable")]
+fn persisted_bindings_test(out_of_process: bool) {
+    let mut repl = repl!();
+    let dir = unqiue_build_dir();
+    repl.data.with_compilation_dir(&dir).unwrap();
+    repl.data.exec_options.out_of_process = out_of_process;
+    repl.data.exec_options.worker = Some(env!("CARGO_BIN_EXE_papyrus").into());
+    let file = dir.canonicalize().unwrap().join("side-effects");
+    std::fs::remove_file(&file).ok();
+    let side_effect = format!(
+        "std::io::Write::write_all(&mut std::fs::OpenOptions::new().create(true).append(true).open({:?}).unwrap(), b\"x\").unwrap()",
+        file
+    );
+
+    assert!(!repl.data.persist_bindings);
+    repl = eval_input(repl, ":persist on").0;
+    assert!(repl.data.persist_bindings);
+
+    let mut eval = |input: &str| {
+        let (r, outcome) = eval_input(std::mem::replace(&mut repl, repl!()), input);
+        repl = r;
+        match outcome {
+            repl::EvalOutcome::Ok(kserd) => kserd,
+            x => panic!("expecting Ok, found {:?}", x),
+        }
+    };
+
+    let input = format!("let v = {{ {}; vec![1, 2] }}; v.len()", side_effect);
+    assert_eq!(eval(&input), Kserd::new_num(2));
+    assert_eq!(eval("v[1] + out0"), Kserd::new_num(4));
+    assert_eq!(
+        eval("let s = \"abc\"; s.len() + v.len()"),
+        Kserd::new_num(5)
+    );
+    // a borrowed binding cannot be restored, the previous statements are evaluated again
+    assert_eq!(eval("s.len()"), Kserd::new_num(3));
+
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "xx");
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn output_fallbacks() {
+    use papyrus::code::{DEBUG_ID, TYPE_NAME_ID};
+
+    let mut repl = chg_compile_dir(repl!());
+    repl = eval_input(repl, "#[derive(Debug)] struct A(u8);").0;
+    repl = eval_input(repl, "struct B;").0;
+
+    let mut eval = |input: &str| {
+        let (r, outcome) = eval_input(std::mem::replace(&mut repl, repl!()), input);
+        repl = r;
+        match outcome {
+            repl::EvalOutcome::Ok(kserd) => kserd,
+            x => panic!("expecting Ok, found {:?}", x),
+        }
+    };
+
+    let kserd = eval("vec![A(1), A(2)]");
+    assert_eq!(kserd.id(), Some(DEBUG_ID));
+    assert_eq!(repl::fmt_output(&kserd, Default::default()), "[A(1), A(2)]");
+
+    let kserd = eval("B");
+    assert_eq!(kserd.id(), Some(TYPE_NAME_ID));
+    assert!(repl::fmt_output(&kserd, Default::default()).ends_with("::B>"));
+
+    assert_eq!(eval("vec![1, 2]").id(), Some("Vec"));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn output_history() {
+    let mut repl = chg_compile_dir(repl!());
+    repl = eval_input(repl, "1 + 1").0;
+    repl = eval_input(repl, "String::from(\"two\")").0;
+
+    assert_eq!(repl.data.output(0), Some(&Kserd::new_num(2)));
+    assert_eq!(repl.data.output(1).and_then(|k| k.str()), Some("two"));
+    assert_eq!(repl.data.output(2), None);
+
+    let path = unqiue_build_dir().join("out0.json");
+    repl.data
+        .save_output(0, &path, repl::OutputFormat::Json)
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "2");
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn renderings() {
+    let mut repl = chg_compile_dir(repl!());
+    let mut eval = |input: &str| {
+        repl.line_input(input);
+        match std::mem::replace(&mut repl, repl!()).read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(r) => {
+                let repl::EvalResult {
+                    repl: r,
+                    renderings,
+                    ..
+                } = r.eval(&mut ());
+                repl = r.print().0;
+                renderings
+                    .into_iter()
+                    .map(|x| (x.mime, x.data))
+                    .collect::<Vec<_>>()
+            }
+        }
+    };
+
+    let r = eval("vec![(1, \"a\"), (2, \"b\")]");
+    assert_eq!(
+        r[0],
+        ("text/plain".into(), "0 | 1\n--+--\n1 | a\n2 | b".into())
+    );
+    assert_eq!(r[1], ("text/csv".into(), "0,1\n1,a\n2,b".into()));
+    assert_eq!(r[2].0, "text/html");
+
+    eval(
+        "struct Bold(&'static str);
+impl __papyrus_display::Mime for Bold {
+    fn mime(&self) -> Vec<(String, String)> {
+        vec![(\"text/html\".into(), format!(\"<b>{}</b>\", self.0))]
+    }
+}",
+    );
+    let r = eval("Bold(\"Hello\")");
+    assert_eq!(
+        r,
+        vec![
+            ("text/plain".into(), "<text/html>".into()),
+            ("text/html".into(), "<b>Hello</b>".into())
+        ]
+    );
+
+    assert_eq!(eval("()"), vec![("text/plain".into(), "()".into())]);
+    assert_eq!(eval(":help"), vec![]);
+}
@@ -0,0 +1,145 @@
+//! Out-of-process evaluation, the `papyrus` binary built for the integration tests is the worker.
+#![cfg(feature = "test-runnable")]
+
+#[macro_use]
+extern crate papyrus;
+
+use kserd::*;
+use papyrus::compile::{ExecError, ExecOptions};
+use papyrus::prelude::*;
+use papyrus::repl::EvalOutcome;
+use std::time::Duration;
+
+fn worker_options() -> ExecOptions {
+    ExecOptions {
+        out_of_process: true,
+        worker: Some(env!("CARGO_BIN_EXE_papyrus").into()),
+        ..Default::default()
+    }
+}
+
+/// A REPL compiling in a directory named after the test, evaluating with `opts`.
+fn worker_repl(name: &str, opts: ExecOptions) -> Repl<repl::Read, ()> {
+    let mut repl = repl!();
+    repl.data
+        .with_compilation_dir(format!("target/testing/worker-{}", name))
+        .unwrap();
+    repl.data.exec_options = opts;
+    repl
+}
+
+fn read(mut repl: Repl<repl::Read, ()>, input: &str) -> Repl<repl::Evaluate, ()> {
+    repl.line_input(input);
+    match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl,
+    }
+}
+
+/// Evaluates `input` in a new REPL, returning the output and the outcome.
+fn eval(name: &str, opts: ExecOptions, input: &str) -> (String, EvalOutcome) {
+    let repl = read(worker_repl(name, opts), input);
+    let repl::EvalResult { repl, outcome, .. } = repl.eval(&mut ());
+    let (repl, _) = repl.print();
+    (repl.output().to_string(), outcome)
+}
+
+#[test]
+fn exec_out_of_process() {
+    let eval = |input| eval("out-of-process", worker_options(), input).1;
+
+    match eval("2+2") {
+        EvalOutcome::Ok(kserd) => assert_eq!(kserd, Kserd::new_num(4)),
+        x => panic!("expecting Ok, found {:?}", x),
+    }
+
+    match eval("fn exit() -> i32 { std::process::exit(3) } exit()") {
+        EvalOutcome::ExecFailed(ExecError::Exited(3)) => (),
+        x => panic!("expecting Exited(3), found {:?}", x),
+    }
+
+    // SIGABRT
+    match eval("fn abort() -> i32 { std::process::abort() } abort()") {
+        EvalOutcome::ExecFailed(ExecError::Crashed(6)) => (),
+        x => panic!("expecting Crashed, found {:?}", x),
+    }
+
+    match eval("fn boom() -> i32 { panic!(\"eval panic {}\", 1) } boom()") {
+        EvalOutcome::Panicked(panic) => {
+            assert_eq!(panic.message, "eval panic 1");
+            assert!(panic.location.unwrap().starts_with("src/lib.rs:"));
+        }
+        x => panic!("expecting Panicked, found {:?}", x),
+    }
+}
+
+#[test]
+fn exec_limits() {
+    let memory_limit = 64 << 30;
+    let opts = ExecOptions {
+        memory_limit: Some(memory_limit),
+        ..worker_options()
+    };
+    match eval("limits", opts.clone(), "vec![1u8; 1 << 20].len()").1 {
+        EvalOutcome::Ok(kserd) => assert_eq!(kserd, Kserd::new_num(1 << 20)),
+        x => panic!("expecting Ok, found {:?}", x),
+    }
+    match eval(
+        "limits",
+        opts,
+        "fn alloc() -> usize { vec![1u8; 128 << 30].len() } alloc()",
+    )
+    .1
+    {
+        EvalOutcome::ExecFailed(ExecError::MemoryLimit(x)) => assert_eq!(x, memory_limit),
+        x => panic!("expecting MemoryLimit, found {:?}", x),
+    }
+
+    let cpu_limit = Duration::from_millis(500);
+    let opts = ExecOptions {
+        cpu_limit: Some(cpu_limit),
+        ..worker_options()
+    };
+    let input =
+        "fn spin() -> u64 { let mut i = 0u64; while i != u64::max_value() { i += 1; } i } spin()";
+    match eval("limits", opts, input).1 {
+        EvalOutcome::ExecFailed(ExecError::CpuLimit(x)) => assert_eq!(x, cpu_limit),
+        x => panic!("expecting CpuLimit, found {:?}", x),
+    }
+}
+
+#[test]
+fn exec_capture_output() {
+    let input = "fn print() -> i32 { println!(\"Hello\\nworld\"); eprintln!(\"err\"); 1 } print()";
+    let (output, outcome) = eval("capture-output", worker_options(), input);
+    match outcome {
+        EvalOutcome::Ok(kserd) => assert_eq!(kserd, Kserd::new_num(1)),
+        x => panic!("expecting Ok, found {:?}", x),
+    }
+    assert!(output.contains("Hello\nworld\nerr\n"), "{:?}", output);
+}
+
+#[test]
+fn exec_timeout_and_cancel() {
+    let input = "fn spin() -> i32 { loop {} } spin()";
+
+    let timeout = Duration::from_millis(200);
+    let opts = ExecOptions {
+        timeout: Some(timeout),
+        ..worker_options()
+    };
+    match eval("timeout", opts, input).1 {
+        EvalOutcome::ExecFailed(ExecError::TimedOut(t)) => assert_eq!(t, timeout),
+        x => panic!("expecting TimedOut, found {:?}", x),
+    }
+
+    // the library is cached, so the worker is running by the time it is cancelled
+    let repl = read(worker_repl("timeout", worker_options()), input);
+    let evaluating = repl.eval_async(&std::sync::Arc::new(std::sync::Mutex::new(())));
+    std::thread::sleep(Duration::from_millis(500));
+    evaluating.cancel();
+    match evaluating.wait().outcome {
+        EvalOutcome::Cancelled => (),
+        x => panic!("expecting Cancelled, found {:?}", x),
+    }
+}